@@ -0,0 +1,304 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::{fmt, Segments};
+use rocket::{Request, Response};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use serde::{Serialize, Deserialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::{Config, FileInfo, now_seconds, optional_query_value};
+use crate::index::FileIndex;
+
+#[cfg(test)]
+mod tests {
+	use super::{AuditConfig, AuditLog, AuditQuery, AuditRecord};
+	use crate::test_dir::TestDir;
+
+	fn test_record(timestamp: u64, username: &str, operation: &str) -> AuditRecord {
+		AuditRecord {
+			timestamp,
+			username: Some(username.to_string()),
+			token: None,
+			ip: None,
+			operation: operation.to_string(),
+			path: Some("shares/team/notes.txt".to_string()),
+			digest: None,
+			status: 200
+		}
+	}
+
+	#[test]
+	fn rotate_and_query() {
+		let data_root = TestDir::new("audit");
+
+		// Small enough that every record goes in its own file
+		let audit_log = AuditLog::new(&data_root, &AuditConfig { max_bytes: 1, max_files: 2 });
+		for timestamp in 1..=4 {
+			audit_log.append(&test_record(timestamp, if timestamp % 2 == 0 { "bob" } else { "alice" }, "put_file"));
+		}
+
+		// Only the newest files are kept
+		let records = audit_log.query(&AuditQuery::default());
+		assert_eq!(records.iter().map(|record| record.timestamp).collect::<Vec<u64>>(), vec![2, 3, 4]);
+
+		let query = AuditQuery { user: Some("bob".to_string()), ..AuditQuery::default() };
+		assert_eq!(audit_log.query(&query).iter().map(|record| record.timestamp).collect::<Vec<u64>>(), vec![2, 4]);
+
+		let query = AuditQuery { since: Some(3), operation: Some("get_file".to_string()), ..AuditQuery::default() };
+		assert!(audit_log.query(&query).is_empty());
+	}
+}
+
+// The name of the audit log in the data root, rotated logs get a number after it
+pub const AUDIT_LOG_FILE: &str = "audit.log";
+
+// How many records a query returns when it doesn't say
+pub const DEFAULT_QUERY_LIMIT: usize = 100;
+
+// A structure for representing how the audit log gets rotated
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct AuditConfig {
+	max_bytes: u64, // How big the log can get before it is rotated
+	max_files: u32 // How many rotated logs are kept
+}
+
+impl Default for AuditConfig {
+	fn default() -> Self {
+		Self {
+			max_bytes: 10 * 1024 * 1024,
+			max_files: 5
+		}
+	}
+}
+
+// A structure for representing a single operation on the server
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AuditRecord {
+	timestamp: u64,
+	username: Option<String>, // The user that made the request, None for the server password or if nobody was authenticated
+	token: Option<String>, // The digest of the API token the request was made with, if it was
+	ip: Option<String>,
+	operation: String, // The name of the route
+	path: Option<String>, // The virtual path that was operated on
+	digest: Option<String>, // The digest of the file that was uploaded
+	status: u16
+}
+
+impl AuditRecord {
+
+	// Getters
+
+	pub fn get_timestamp(&self) -> u64 {
+		self.timestamp
+	}
+
+	pub fn get_username(&self) -> Option<&str> {
+		self.username.as_deref()
+	}
+
+	pub fn get_token(&self) -> Option<&str> {
+		self.token.as_deref()
+	}
+
+	pub fn get_ip(&self) -> Option<&str> {
+		self.ip.as_deref()
+	}
+
+	pub fn get_operation(&self) -> &str {
+		&self.operation
+	}
+
+	pub fn get_path(&self) -> Option<&str> {
+		self.path.as_deref()
+	}
+
+	pub fn get_digest(&self) -> Option<&str> {
+		self.digest.as_deref()
+	}
+
+	pub fn get_status(&self) -> u16 {
+		self.status
+	}
+}
+
+// A structure for filtering the audit log, read from the query parameters of the same names
+#[derive(Default)]
+pub struct AuditQuery {
+	since: Option<u64>,
+	until: Option<u64>,
+	user: Option<String>,
+	operation: Option<String>,
+	path: Option<String>, // Only records for paths under this one
+	limit: Option<usize> // How many of the newest matching records to return
+}
+
+impl AuditQuery {
+	// Constructor
+
+	fn from_query(req: &Request<'_>) -> Result<Self, Status> {
+		Ok(Self {
+			since: optional_query_value(req, "since")?,
+			until: optional_query_value(req, "until")?,
+			user: optional_query_value(req, "user")?,
+			operation: optional_query_value(req, "operation")?,
+			path: optional_query_value(req, "path")?,
+			limit: optional_query_value(req, "limit")?
+		})
+	}
+
+	// If a record is one the query is asking for
+	fn matches(&self, record: &AuditRecord) -> bool {
+		self.since.is_none_or(|since| record.timestamp >= since)
+			&& self.until.is_none_or(|until| record.timestamp <= until)
+			&& self.user.as_ref().is_none_or(|user| record.username.as_ref() == Some(user))
+			&& self.operation.as_ref().is_none_or(|operation| record.operation == *operation)
+			&& self.path.as_ref().is_none_or(|path| record.path.as_ref().is_some_and(|record_path| Path::new(record_path).starts_with(path)))
+	}
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuditQuery {
+	type Error = ();
+
+	async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+		match Self::from_query(req) {
+			Ok(query) => Outcome::Success(query),
+			Err(status) => Outcome::Failure((status, ()))
+		}
+	}
+}
+
+// Kept in the request's local cache by whatever authenticated it, so the record says who made the request
+pub struct AuditIdentity {
+	pub username: Option<String>,
+	pub token: Option<String>
+}
+
+// A structure for appending to the audit log, saved as JSON lines in the data root
+pub struct AuditLog {
+	path: PathBuf,
+	config: AuditConfig,
+	lock: Mutex<()>
+}
+
+impl AuditLog {
+
+	// Constructor
+
+	pub fn new(data_root: &Path, config: &AuditConfig) -> Self {
+		Self {
+			path: data_root.join(AUDIT_LOG_FILE),
+			config: config.clone(),
+			lock: Mutex::new(())
+		}
+	}
+
+	// Get the path of a rotated log, 1 being the newest
+	fn rotated_path(&self, number: u32) -> PathBuf {
+		self.path.with_extension(format!("log.{}", number))
+	}
+
+	// Add a record to the end of the log, rotating it first if it has gotten too big
+	pub fn append(&self, record: &AuditRecord) {
+		let _lock = self.lock.lock().unwrap();
+
+		if fs::metadata(&self.path).is_ok_and(|metadata| metadata.len() >= self.config.max_bytes) {
+			self.rotate();
+		}
+
+		let line = serde_json::to_string(record).expect("Could not serialize audit record");
+		let appended = OpenOptions::new().create(true).append(true).open(&self.path)
+			.and_then(|mut file| writeln!(file, "{}", line));
+		if let Err(e) = appended {
+			println!("Could not append to audit log {:?}: {}", self.path, e);
+		}
+	}
+
+	// Shift every rotated log back by one, dropping the oldest, and make the current log the newest rotated one
+	fn rotate(&self) {
+		if self.config.max_files == 0 {
+			let _ = fs::remove_file(&self.path);
+			return;
+		}
+
+		let _ = fs::remove_file(self.rotated_path(self.config.max_files));
+		for number in (1..self.config.max_files).rev() {
+			let _ = fs::rename(self.rotated_path(number), self.rotated_path(number + 1));
+		}
+		if let Err(e) = fs::rename(&self.path, self.rotated_path(1)) {
+			println!("Could not rotate audit log {:?}: {}", self.path, e);
+		}
+	}
+
+	// Get the newest records matching a query, oldest first
+	pub fn query(&self, query: &AuditQuery) -> Vec<AuditRecord> {
+		let _lock = self.lock.lock().unwrap();
+
+		// Read from the oldest rotated log to the current one
+		let paths = (1..=self.config.max_files).rev().map(|number| self.rotated_path(number)).chain(std::iter::once(self.path.clone()));
+		let mut records: Vec<AuditRecord> = paths
+			.filter_map(|path| File::open(path).ok())
+			.flat_map(|file| BufReader::new(file).lines().map_while(Result::ok))
+			.filter_map(|line| serde_json::from_str(&line).ok())
+			.filter(|record| query.matches(record))
+			.collect();
+
+		let limit = query.limit.unwrap_or(DEFAULT_QUERY_LIMIT);
+		records.split_off(records.len().saturating_sub(limit))
+	}
+}
+
+// Utility function to get the virtual path a routed request operated on, which routes take as their trailing segments
+fn virtual_path(req: &Request<'_>) -> Option<String> {
+	let route = req.route()?;
+	let index = route.uri.path().split('/').filter(|segment| !segment.is_empty())
+		.position(|segment| segment.starts_with("<virtual_path_segments"))?;
+	let segments: Segments<'_, fmt::Path> = req.routed_segments(index..);
+	segments.to_path_buf(true).ok().map(|path| path.to_string_lossy().into_owned())
+}
+
+// A fairing for writing an audit record for every routed request once it has been answered
+pub struct Auditor;
+
+#[rocket::async_trait]
+impl Fairing for Auditor {
+	fn info(&self) -> Info {
+		Info {
+			name: "Audit log",
+			kind: Kind::Response
+		}
+	}
+
+	async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+		let (Some(route), Some(audit_log)) = (req.route(), req.rocket().state::<AuditLog>()) else {
+			return;
+		};
+		let operation = route.name.as_deref().unwrap_or("unnamed").to_string();
+		let path = virtual_path(req);
+		let identity = req.local_cache(|| AuditIdentity { username: None, token: None });
+
+		// Record what an upload left on disk
+		let digest = match (&path, req.rocket().state::<Config>(), req.rocket().state::<FileIndex>()) {
+			(Some(path), Some(config), Some(index)) if operation == "put_file" && res.status().class().is_success() => {
+				let full_path = Path::new(config.get_server_config().get_files_root()).join(path);
+				FileInfo::from_indexed_file_path(full_path, index).ok().map(|file_info| file_info.get_digest().to_string())
+			},
+			_ => None
+		};
+
+		audit_log.append(&AuditRecord {
+			timestamp: now_seconds(),
+			username: identity.username.clone(),
+			token: identity.token.clone(),
+			ip: req.client_ip().map(|ip| ip.to_string()),
+			operation,
+			path,
+			digest,
+			status: res.status().code
+		});
+	}
+}
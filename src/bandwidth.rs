@@ -0,0 +1,218 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[cfg(test)]
+mod tests {
+	use super::{BandwidthConfig, TimeWindow};
+
+	#[test]
+	fn defer_during_windows() {
+		let window = TimeWindow::try_from("09:00-17:30".to_string()).unwrap();
+		assert!(window.contains(9 * 60) && window.contains(17 * 60 + 29));
+		assert!(!window.contains(17 * 60 + 30) && !window.contains(8 * 60));
+
+		// Windows can go past midnight
+		let night = TimeWindow::try_from("22:00-06:00".to_string()).unwrap();
+		assert!(night.contains(23 * 60) && night.contains(60) && !night.contains(12 * 60));
+		assert!(TimeWindow::try_from("25:00-06:00".to_string()).is_err());
+		assert!(TimeWindow::try_from("09:00".to_string()).is_err());
+
+		// Only large files are deferred, at the time in the configured time zone
+		let config: BandwidthConfig = toml::from_str("large_file_bytes=1000\ndefer_large_during=[\"09:00-17:00\"]\nutc_offset_minutes=-300").unwrap();
+		let ten_utc = 10 * 60 * 60;
+		let fifteen_utc = 15 * 60 * 60;
+		assert!(!config.is_deferred(5000, ten_utc));
+		assert!(config.is_deferred(5000, fifteen_utc));
+		assert!(!config.is_deferred(500, fifteen_utc));
+	}
+}
+
+// How much of a limit can be used up at once after a transfer has been idle
+const BURST: Duration = Duration::from_secs(1);
+
+// A structure for representing how fast the client transfers files and when it holds off on large ones
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct BandwidthConfig {
+	upload_bytes_per_second: u64, // Across every upload at once, 0 for no limit
+	download_bytes_per_second: u64, // Across every download at once, 0 for no limit
+	large_file_bytes: u64, // How big a file has to be to be deferred
+	defer_large_during: Vec<TimeWindow>, // When large files are left for a later sync
+	utc_offset_minutes: i32, // The time zone the windows are in, as minutes ahead of UTC
+	mappings: HashMap<String, MappingBandwidth> // Limits for just the files of a mapping, keyed by its client path
+}
+
+impl Default for BandwidthConfig {
+	fn default() -> Self {
+		Self {
+			upload_bytes_per_second: 0,
+			download_bytes_per_second: 0,
+			large_file_bytes: 10_000_000,
+			defer_large_during: Vec::new(),
+			utc_offset_minutes: 0,
+			mappings: HashMap::new()
+		}
+	}
+}
+
+impl BandwidthConfig {
+
+	// Getters
+
+	pub fn get_upload_bytes_per_second(&self) -> u64 {
+		self.upload_bytes_per_second
+	}
+
+	pub fn get_download_bytes_per_second(&self) -> u64 {
+		self.download_bytes_per_second
+	}
+
+	pub fn get_large_file_bytes(&self) -> u64 {
+		self.large_file_bytes
+	}
+
+	pub fn get_mappings(&self) -> &HashMap<String, MappingBandwidth> {
+		&self.mappings
+	}
+
+	// If a file of the given size should be left for later at the given time, in seconds since the Unix epoch
+	pub fn is_deferred(&self, size: u64, now_seconds: u64) -> bool {
+		if size < self.large_file_bytes {
+			return false;
+		}
+		let local_minutes = now_seconds as i64 / 60 + self.utc_offset_minutes as i64;
+		let minute_of_day = local_minutes.rem_euclid(24 * 60) as u32;
+		self.defer_large_during.iter().any(|window| window.contains(minute_of_day))
+	}
+}
+
+// A structure for representing the limits on a single mapping
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct MappingBandwidth {
+	upload_bytes_per_second: u64, // 0 for no limit
+	download_bytes_per_second: u64 // 0 for no limit
+}
+
+impl MappingBandwidth {
+
+	// Getters
+
+	pub fn get_upload_bytes_per_second(&self) -> u64 {
+		self.upload_bytes_per_second
+	}
+
+	pub fn get_download_bytes_per_second(&self) -> u64 {
+		self.download_bytes_per_second
+	}
+}
+
+// A time of day range written like "09:00-17:00", which goes past midnight if it ends before it starts
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(try_from = "String")]
+pub struct TimeWindow {
+	start_minute: u32, // Minutes after midnight
+	end_minute: u32
+}
+
+impl TimeWindow {
+	// If a minute of the day is in the window
+	pub fn contains(&self, minute_of_day: u32) -> bool {
+		if self.start_minute <= self.end_minute {
+			self.start_minute <= minute_of_day && minute_of_day < self.end_minute
+		} else {
+			self.start_minute <= minute_of_day || minute_of_day < self.end_minute
+		}
+	}
+}
+
+impl TryFrom<String> for TimeWindow {
+	type Error = String;
+
+	fn try_from(window: String) -> Result<Self, Self::Error> {
+		let minute = |time: &str| -> Option<u32> {
+			let (hours, minutes) = time.trim().split_once(':')?;
+			let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+			(hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+		};
+		match window.split_once('-').and_then(|(start, end)| Some((minute(start)?, minute(end)?))) {
+			Some((start_minute, end_minute)) => Ok(Self { start_minute, end_minute }),
+			None => Err(format!("{:?} is not a time window like \"09:00-17:00\"", window))
+		}
+	}
+}
+
+// A structure for holding transfers to a number of bytes per second, shared by every transfer it limits
+pub struct Throttle {
+	bytes_per_second: u64,
+	next_free: Mutex<Instant> // When everything let through so far will have been sent at the limit
+}
+
+impl Throttle {
+
+	// Constructor
+
+	pub fn new(bytes_per_second: u64) -> Self {
+		Self {
+			bytes_per_second,
+			next_free: Mutex::new(Instant::now())
+		}
+	}
+
+	// Wait until sending some bytes would keep under the limit
+	pub async fn consume(&self, bytes: usize) {
+		if self.bytes_per_second == 0 {
+			return;
+		}
+
+		let wait_until = {
+			let mut next_free = self.next_free.lock().unwrap();
+			let now = Instant::now();
+			let start = (*next_free).max(now.checked_sub(BURST).unwrap_or(now));
+			*next_free = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64);
+			*next_free
+		};
+		tokio::time::sleep_until(tokio::time::Instant::from_std(wait_until)).await;
+	}
+}
+
+// The throttles for every transfer, and the ones for each mapping that has its own limits
+pub struct BandwidthLimits {
+	upload: Arc<Throttle>,
+	download: Arc<Throttle>,
+	mappings: Vec<(PathBuf, Arc<Throttle>, Arc<Throttle>)> // The client path of the mapping, then its upload and download throttles
+}
+
+impl BandwidthLimits {
+
+	// Constructor
+
+	pub fn from_config(config: &BandwidthConfig) -> Self {
+		Self {
+			upload: Arc::new(Throttle::new(config.get_upload_bytes_per_second())),
+			download: Arc::new(Throttle::new(config.get_download_bytes_per_second())),
+			mappings: config.get_mappings().iter()
+				.map(|(client_path, limits)| (
+					PathBuf::from(client_path),
+					Arc::new(Throttle::new(limits.get_upload_bytes_per_second())),
+					Arc::new(Throttle::new(limits.get_download_bytes_per_second()))
+				))
+				.collect()
+		}
+	}
+
+	// Get every throttle an upload of a client file has to keep under
+	pub fn upload_throttles(&self, client_path: &Path) -> Vec<Arc<Throttle>> {
+		let mappings = self.mappings.iter().filter(|(mapping_path, ..)| client_path.starts_with(mapping_path));
+		std::iter::once(self.upload.clone()).chain(mappings.map(|(_, upload, _)| upload.clone())).collect()
+	}
+
+	// Get every throttle a download to a client file has to keep under
+	pub fn download_throttles(&self, client_path: &Path) -> Vec<Arc<Throttle>> {
+		let mappings = self.mappings.iter().filter(|(mapping_path, ..)| client_path.starts_with(mapping_path));
+		std::iter::once(self.download.clone()).chain(mappings.map(|(_, _, download)| download.clone())).collect()
+	}
+}
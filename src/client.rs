@@ -1,288 +1,1926 @@
-use std::io;
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
 use std::fs;
-use std::path::Path;
-use reqwest::StatusCode;
-use reqwest::{multipart, Body};
-use skywriter::{FileInfo, Config, ClientConfig, ServerConfig, Mappings, Mapping};
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+use clap::{Parser, Subcommand};
+use futures::stream::{self, StreamExt};
+use notify::{RecursiveMode, Watcher};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{StatusCode, Response};
+use reqwest::{multipart, Body, Method, RequestBuilder};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use skywriter::{FileInfo, Config, ClientConfig, ServerConfig, Mappings, Mapping, mapping_server_str, now_seconds, random_token, sha256_digest_str};
+use skywriter::bandwidth::BandwidthLimits;
+use skywriter::events::{ChangeEvent, ChangeFeed, CLIENT_ID_HEADER};
+use skywriter::filter::PathFilter;
+use skywriter::index::FileIndex;
+use skywriter::placeholder::{placeholder_name_target, placeholder_path, placeholder_target, read_placeholder, write_placeholder};
+use skywriter::sync_state::{Direction, PendingOperation, SyncState};
+use skywriter::tree::TreeInfo;
+use skywriter::signing::{self, signing_message, SIGNATURE_HEADER, TIMESTAMP_HEADER, NONCE_HEADER, CONTENT_SHA256_HEADER};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::time;
 use tokio_util::codec::{BytesCodec, FramedRead};
 use toml::value::Table;
 
+#[cfg(test)]
+mod tests {
+	use super::{download_path, ClientError, ErrorClass};
+	use reqwest::StatusCode;
+	use std::io;
+	use std::path::Path;
+
+	#[test]
+	fn error_classes() {
+		// Trouble on the server's side might go away, so it is tried again
+		assert_eq!(ClientError::Status(StatusCode::INTERNAL_SERVER_ERROR).class(), ErrorClass::Server);
+		assert_eq!(ClientError::Status(StatusCode::SERVICE_UNAVAILABLE).class(), ErrorClass::Server);
+		assert_eq!(ClientError::Status(StatusCode::REQUEST_TIMEOUT).class(), ErrorClass::Server);
+		assert_eq!(ClientError::Busy(30).class(), ErrorClass::Server);
+		assert!(ClientError::Busy(30).class().is_transient());
+
+		// Being refused or asking for something that can't be done won't change by trying again
+		assert_eq!(ClientError::Unauthorized(StatusCode::FORBIDDEN).class(), ErrorClass::Auth);
+		assert!(!ErrorClass::Auth.is_transient());
+		assert_eq!(ClientError::Status(StatusCode::NOT_FOUND).class(), ErrorClass::Permanent);
+		assert_eq!(ClientError::Status(StatusCode::UNPROCESSABLE_ENTITY).class(), ErrorClass::Permanent);
+		assert_eq!(ClientError::Io(io::Error::from(io::ErrorKind::PermissionDenied)).class(), ErrorClass::Permanent);
+		assert!(!ErrorClass::Permanent.is_transient());
+	}
+
+	#[test]
+	fn downloads_land_next_to_the_file() {
+		assert_eq!(download_path(Path::new("local/docs/report.pdf")), Path::new("local/docs/.report.pdf.download"));
+	}
+}
+
+// Everything but the characters that never need encoding in a URL
+const URL_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+// Exit codes, so scripts can tell what went wrong
+const EXIT_FAILED: u8 = 1; // Some of the work couldn't be done
+const EXIT_CONFIG: u8 = 2; // The config or command line is unusable, the same code clap uses for bad arguments
+const EXIT_AUTH: u8 = 3; // The server refused the credentials or access
+const EXIT_UNREACHABLE: u8 = 4; // The server couldn't be reached
+
+// The command line arguments
+#[derive(Parser)]
+#[command(name = "client", about = "Sync files with a SkyWriter server")]
+struct Args {
+	/// The config file to use
+	#[arg(long, default_value = "Config.toml")]
+	config: PathBuf,
+
+	/// Print JSON instead of text, the plan as an array for status and --dry-run, otherwise a line for each event and a summary at the end
+	#[arg(long, global = true)]
+	json: bool,
+
+	/// Hash every file again instead of trusting the digests of files that look unchanged
+	#[arg(long, global = true)]
+	rehash: bool,
+
+	/// What to do, syncing every mapping if not given
+	#[command(subcommand)]
+	command: Option<Command>
+}
+
+#[derive(Subcommand)]
+enum Command {
+	/// Sync mappings both ways
+	Sync(SyncArgs),
+	/// Make the server match the client, without downloading anything
+	Push(SyncArgs),
+	/// Make the client match the server, without uploading anything
+	Pull(SyncArgs),
+	/// Show what a sync would do without doing it
+	Status {
+		/// Which mappings to look at, every mapping if none are named
+		mappings: Vec<String>
+	},
+	/// Keep mappings in sync as they change until stopped
+	Watch {
+		/// Which mappings to watch, every mapping if none are named
+		mappings: Vec<String>
+	},
+	/// List the files under a path on the server
+	Ls { remote_path: String },
+	/// Download a single file, to its file name in the current directory if no local path is given
+	Get { remote_path: String, local_path: Option<PathBuf> },
+	/// Upload a single file
+	Put { local_path: PathBuf, remote_path: String },
+	/// Delete a file on the server
+	Rm { remote_path: String },
+	/// Move a file on the server
+	Mv { from: String, to: String },
+	/// Download the files that placeholders stand in for, under each path given
+	Hydrate {
+		#[arg(required = true)]
+		paths: Vec<PathBuf>
+	},
+	/// Swap files that are in sync for placeholders, under each path given
+	Dehydrate {
+		#[arg(required = true)]
+		paths: Vec<PathBuf>
+	},
+	/// Work with the config file
+	Config {
+		#[command(subcommand)]
+		command: ConfigCommand
+	}
+}
+
+#[derive(clap::Args)]
+struct SyncArgs {
+	/// Which mappings to sync, every mapping if none are named
+	mappings: Vec<String>,
+	/// Only show what would be done
+	#[arg(long)]
+	dry_run: bool
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+	/// Check the config file and that the server accepts its credentials
+	Check
+}
+
+// Things that can go wrong for the client
+#[derive(Debug)]
+enum ClientError {
+	Config(String), // The config is unusable
+	UnknownMapping(String), // A mapping was named that isn't in the config
+	Connection(reqwest::Error), // The server couldn't be reached or the response couldn't be read
+	Unauthorized(StatusCode), // The server refused the credentials or access
+	Status(StatusCode), // The server couldn't do what was asked
+	Busy(u64), // The server is too busy, along with how many seconds it said to wait
+	Io(io::Error), // Something went wrong with a file on the client
+	NotFile(PathBuf), // A path that should be a file is something else
+	NotSynced(PathBuf), // A file has changes the server doesn't have, so it can't be swapped for a placeholder
+	Watch(notify::Error) // The client paths couldn't be watched for changes
+}
+
+impl ClientError {
+	// The exit code to leave with because of this error
+	fn exit_code(&self) -> u8 {
+		match self {
+			ClientError::Config(_) | ClientError::UnknownMapping(_) => EXIT_CONFIG,
+			ClientError::Unauthorized(_) => EXIT_AUTH,
+			ClientError::Connection(_) => EXIT_UNREACHABLE,
+			ClientError::Status(_) | ClientError::Busy(_) | ClientError::Io(_) | ClientError::NotFile(_) | ClientError::NotSynced(_) | ClientError::Watch(_) => EXIT_FAILED
+		}
+	}
+
+	// How the error should be handled
+	fn class(&self) -> ErrorClass {
+		match self {
+			ClientError::Connection(e) if e.is_decode() => ErrorClass::Permanent,
+			ClientError::Connection(_) => ErrorClass::Network,
+			ClientError::Status(status) if status.is_server_error() || *status == StatusCode::REQUEST_TIMEOUT => ErrorClass::Server,
+			ClientError::Busy(_) => ErrorClass::Server,
+			ClientError::Unauthorized(_) => ErrorClass::Auth,
+			_ => ErrorClass::Permanent
+		}
+	}
+
+	// A short name for the kind of error, for anything reading the JSON output
+	fn kind(&self) -> &'static str {
+		match self {
+			ClientError::Config(_) => "config",
+			ClientError::UnknownMapping(_) => "unknown-mapping",
+			ClientError::Connection(_) => "connection",
+			ClientError::Unauthorized(_) => "unauthorized",
+			ClientError::Status(_) => "status",
+			ClientError::Busy(_) => "busy",
+			ClientError::Io(_) => "io",
+			ClientError::NotFile(_) => "not-file",
+			ClientError::NotSynced(_) => "not-synced",
+			ClientError::Watch(_) => "watch"
+		}
+	}
+}
+
+// How a failure should be handled
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+enum ErrorClass {
+	Network, // The server couldn't be reached
+	Server, // The server had trouble or was too busy
+	Auth, // The server refused the credentials or access
+	Permanent // Nothing will change by trying again
+}
+
+impl ErrorClass {
+	// If trying again later might work
+	fn is_transient(self) -> bool {
+		matches!(self, ErrorClass::Network | ErrorClass::Server)
+	}
+}
+
+impl fmt::Display for ClientError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			ClientError::Config(message) => write!(f, "{}", message),
+			ClientError::UnknownMapping(name) => write!(f, "no mapping for {}", name),
+			ClientError::Connection(e) => write!(f, "could not reach server: {}", e),
+			ClientError::Unauthorized(status) => write!(f, "server refused access, status {}", status),
+			ClientError::Status(status) => write!(f, "server returned status {}", status),
+			ClientError::Busy(seconds) => write!(f, "server is too busy, try again in {} seconds", seconds),
+			ClientError::Io(e) => write!(f, "{}", e),
+			ClientError::NotFile(path) => write!(f, "{:?} is not a file", path),
+			ClientError::NotSynced(path) => write!(f, "{:?} is not the same as the server's copy, sync it first", path),
+			ClientError::Watch(e) => write!(f, "could not watch for changes: {}", e)
+		}
+	}
+}
+
+// How a sync should go
+#[derive(Clone, Copy)]
+struct SyncOptions {
+	direction: Direction,
+	dry_run: bool // Only work out what would be done
+}
+
+// What a sync does with a file
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+enum SyncAction {
+	Upload,
+	Download,
+	DeleteLocal,
+	DeleteRemote,
+	Conflict, // Both sides changed since the last sync, so neither is touched
+	Placeholder, // The file is on the server, so the client gets a placeholder for it instead of a download
+	Hydrate, // A placeholder is swapped for the file it stands in for
+	Dehydrate, // A file is swapped for a placeholder
+	Skip
+}
+
+impl SyncAction {
+	// Work out what to do with a file from what the client and server have and the digest it had when it was last in sync
+	fn plan(client_file_info: &FileInfo, server_file_info: &FileInfo, synced_digest: Option<&str>, direction: Direction) -> Self {
+		let client_digest = client_file_info.exists().then(|| client_file_info.get_digest());
+		let server_digest = server_file_info.exists().then(|| server_file_info.get_digest());
+		if client_digest == server_digest {
+			return SyncAction::Skip;
+		}
+
+		match direction {
+			// Whichever side changed since the last sync wins, or the newest if the file has never been in sync
+			Direction::Both => match (client_digest != synced_digest, server_digest != synced_digest) {
+				(true, false) if client_file_info.exists() => SyncAction::Upload,
+				(true, false) => SyncAction::DeleteRemote,
+				(false, true) if server_file_info.exists() => SyncAction::Download,
+				(false, true) => SyncAction::DeleteLocal,
+				_ if synced_digest.is_some() => SyncAction::Conflict,
+				_ if client_file_info.get_seconds() < server_file_info.get_seconds() => SyncAction::Download,
+				_ => SyncAction::Upload
+			},
+			// The client wins, though only files that were in sync before get deleted
+			Direction::Push if client_file_info.exists() => SyncAction::Upload,
+			Direction::Push if synced_digest.is_some() => SyncAction::DeleteRemote,
+			// The server wins, the same way
+			Direction::Pull if server_file_info.exists() => SyncAction::Download,
+			Direction::Pull if synced_digest.is_some() => SyncAction::DeleteLocal,
+			_ => SyncAction::Skip
+		}
+	}
+}
+
+// What a sync is going to do with a file, along with what it found on each side
+struct FilePlan {
+	entry: PlanEntry,
+	client_file_info: FileInfo,
+	server_file_info: FileInfo,
+	placeholder: bool // The client only has a placeholder for the file
+}
+
+// A single step of a sync
+#[derive(Serialize)]
+struct PlanEntry {
+	action: SyncAction,
+	client_path: PathBuf,
+	server_path: PathBuf,
+	size: u64, // The size of the file being copied or deleted
+	deferred: bool, // The file can't be copied right now, so it is left for a later sync
+	refused: bool, // The mapping's rules keep the file from being copied at all
+	#[serde(skip_serializing_if = "Option::is_none")]
+	reason: Option<String> // Why the file was deferred or refused
+}
+
+impl PlanEntry {
+	// Describe the step in a line
+	fn describe(&self) -> String {
+		let (client_path, server_path) = (self.client_path.display(), self.server_path.display());
+		let description = match self.action {
+			SyncAction::Upload => format!("upload {} -> {}", client_path, server_path),
+			SyncAction::Download => format!("download {} -> {}", server_path, client_path),
+			SyncAction::DeleteLocal => format!("delete {}", client_path),
+			SyncAction::DeleteRemote => format!("delete {}", server_path),
+			SyncAction::Conflict => format!("conflict {} <-> {}, changed on both sides", client_path, server_path),
+			SyncAction::Placeholder => format!("placeholder {} -> {}", server_path, placeholder_path(&self.client_path).display()),
+			SyncAction::Hydrate => format!("hydrate {} -> {}", server_path, client_path),
+			SyncAction::Dehydrate => format!("dehydrate {}", client_path),
+			SyncAction::Skip => format!("skip {}", client_path)
+		};
+		let reason = self.reason.as_deref().unwrap_or_default();
+		if self.deferred {
+			format!("defer {}, {}", description, reason)
+		} else if self.refused {
+			format!("refuse {}, {}", description, reason)
+		} else {
+			description
+		}
+	}
+}
+
+// Something that went wrong with a path during a run
+#[derive(Serialize)]
+struct Failure {
+	path: PathBuf,
+	kind: &'static str,
+	class: ErrorClass,
+	error: String,
+	#[serde(skip)]
+	exit_code: u8
+}
+
+// What a run did, the last line of the JSON output
+#[derive(Serialize)]
+struct Summary<'a> {
+	scanned: usize, // How many files were looked at
+	uploaded: usize,
+	downloaded: usize,
+	placeholders: usize, // How many files got a placeholder instead of a download, or were swapped for one
+	deleted: usize,
+	conflicts: usize,
+	deferred: usize, // How many files were left for a later sync
+	refused: usize, // How many files the mapping's rules kept from being copied
+	skipped: usize, // How many files were already in sync
+	failed: usize,
+	bytes: u64, // How much was copied, or would be for a dry run
+	duration_ms: u64,
+	dry_run: bool,
+	errors: &'a [Failure]
+}
+
+// A line of the JSON output
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+enum Event<'a> {
+	File {
+		#[serde(flatten)]
+		entry: &'a PlanEntry,
+		dry_run: bool
+	},
+	Listing(&'a FileInfo),
+	Error(&'a Failure),
+	Summary(Summary<'a>)
+}
+
+// What happened during a run, printed as it happens and summed up at the end
+struct Report {
+	json: bool, // Print JSON instead of text
+	dry_run: bool, // The steps are only planned, so they get printed as a plan at the end, a JSON array of them for JSON
+	started: Instant,
+	scanned: usize,
+	skipped: usize,
+	entries: Vec<PlanEntry>,
+	failures: Vec<Failure>
+}
+
+impl Report {
+
+	// Constructor
+
+	fn new(json: bool, dry_run: bool) -> Self {
+		Self {
+			json,
+			dry_run,
+			started: Instant::now(),
+			scanned: 0,
+			skipped: 0,
+			entries: Vec::new(),
+			failures: Vec::new()
+		}
+	}
+
+	// Print a line of the JSON output
+	fn emit(&self, event: Event<'_>) {
+		println!("{}", serde_json::to_string(&event).expect("Could not serialize event"));
+	}
+
+	// If JSON lines are printed as things happen, which a plan isn't since it is printed whole at the end
+	fn streams_json(&self) -> bool {
+		self.json && !self.dry_run
+	}
+
+	// Keep track of the outcome of syncing a file
+	fn record(&mut self, client_path: &Path, result: Result<PlanEntry, ClientError>) {
+		self.scanned += 1;
+		match result {
+			Ok(entry) if entry.action == SyncAction::Skip => {
+				self.skipped += 1;
+			},
+			Ok(entry) => {
+				if self.streams_json() {
+					self.emit(Event::File { entry: &entry, dry_run: self.dry_run });
+				} else if !self.dry_run {
+					println!("{}", entry.describe());
+				}
+				self.entries.push(entry);
+			},
+			Err(e) => self.fail(client_path, e)
+		}
+	}
+
+	// Print and keep track of a failure
+	fn fail(&mut self, path: &Path, e: ClientError) {
+		let failure = Failure {
+			path: path.to_path_buf(),
+			kind: e.kind(),
+			class: e.class(),
+			error: e.to_string(),
+			exit_code: e.exit_code()
+		};
+		if self.streams_json() {
+			self.emit(Event::Error(&failure));
+		} else {
+			eprintln!("{}: {}", path.display(), e);
+		}
+		self.failures.push(failure);
+	}
+
+	// Print a file on the server
+	fn list(&self, file_info: &FileInfo) {
+		if self.json {
+			self.emit(Event::Listing(file_info));
+		} else {
+			println!("{}\t{}", file_info.get_seconds(), file_info.get_path().display());
+		}
+	}
+
+	// Count up what was done
+	fn summary(&self) -> Summary<'_> {
+		let count = |action: SyncAction| self.entries.iter().filter(|entry| entry.action == action && !entry.deferred && !entry.refused).count();
+		Summary {
+			scanned: self.scanned,
+			uploaded: count(SyncAction::Upload),
+			downloaded: count(SyncAction::Download) + count(SyncAction::Hydrate),
+			placeholders: count(SyncAction::Placeholder) + count(SyncAction::Dehydrate),
+			deleted: count(SyncAction::DeleteLocal) + count(SyncAction::DeleteRemote),
+			conflicts: count(SyncAction::Conflict),
+			deferred: self.entries.iter().filter(|entry| entry.deferred).count(),
+			refused: self.entries.iter().filter(|entry| entry.refused).count(),
+			skipped: self.skipped,
+			failed: self.failures.len(),
+			bytes: self.entries.iter()
+				.filter(|entry| matches!(entry.action, SyncAction::Upload | SyncAction::Download | SyncAction::Hydrate) && !entry.deferred && !entry.refused)
+				.map(|entry| entry.size)
+				.sum(),
+			duration_ms: self.started.elapsed().as_millis() as u64,
+			dry_run: self.dry_run,
+			errors: &self.failures
+		}
+	}
+
+	// Print the end of the run, the plan of a dry run, otherwise the summary for JSON or the totals of a sync
+	fn finish(&self) {
+		let summary = self.summary();
+		if self.dry_run {
+			self.print_plan(&summary);
+		} else if self.json {
+			self.emit(Event::Summary(summary));
+		} else if summary.scanned > 0 {
+			println!("{} scanned, {} uploaded, {} downloaded, {} placeholders, {} deleted, {} conflicts, {} deferred, {} refused, {} skipped, {} failed, {} moved in {:.1}s",
+				summary.scanned, summary.uploaded, summary.downloaded, summary.placeholders, summary.deleted, summary.conflicts, summary.deferred, summary.refused, summary.skipped, summary.failed,
+				format_size(summary.bytes), self.started.elapsed().as_secs_f64());
+		}
+	}
+
+	// Print every step as a table with totals, or as JSON
+	fn print_plan(&self, summary: &Summary<'_>) {
+		if self.json {
+			println!("{}", serde_json::to_string_pretty(&self.entries).expect("Could not serialize plan"));
+			return;
+		}
+
+		if self.entries.is_empty() {
+			println!("Everything is up to date");
+			return;
+		}
+
+		let width = self.entries.iter().map(|entry| entry.client_path.display().to_string().len()).max().unwrap_or(0).max("CLIENT PATH".len());
+		println!("{:<16}{:>10}  {:<width$}  SERVER PATH", "ACTION", "SIZE", "CLIENT PATH", width = width);
+		for entry in self.entries.iter() {
+			let action = serde_json::to_value(entry.action).ok().and_then(|action| action.as_str().map(str::to_string)).unwrap_or_default();
+			let action = if entry.deferred {
+				format!("defer-{}", action)
+			} else if entry.refused {
+				format!("refuse-{}", action)
+			} else {
+				action
+			};
+			println!("{:<16}{:>10}  {:<width$}  {}", action, format_size(entry.size), entry.client_path.display(), entry.server_path.display(), width = width);
+		}
+
+		println!();
+		println!("{} to upload, {} to download, {} placeholders, {} to delete, {} conflicts, {} deferred, {} refused, {} to transfer",
+			summary.uploaded, summary.downloaded, summary.placeholders, summary.deleted, summary.conflicts, summary.deferred, summary.refused, format_size(summary.bytes));
+	}
+
+	// How many files still have to be looked at again, because they failed or were deferred
+	fn unfinished(&self) -> usize {
+		self.failures.len() + self.entries.iter().filter(|entry| entry.deferred).count()
+	}
+
+	// The exit code for the run, which is for the most serious failure if there were any, conflicts counting as failures
+	fn exit_code(&self) -> u8 {
+		let conflicts = self.entries.iter().any(|entry| entry.action == SyncAction::Conflict);
+		let failures = self.failures.iter().map(|failure| failure.exit_code).max().unwrap_or(0);
+		if conflicts { failures.max(EXIT_FAILED) } else { failures }
+	}
+}
+
+// A mapping being watched for changes
+struct WatchedMapping {
+	client_path: PathBuf,
+	server_path: PathBuf,
+	real_path: PathBuf, // The canonical client path, which is what changed paths are reported under
+	is_dir: bool
+}
+
+impl WatchedMapping {
+	// Get the client and server paths to sync for a changed path, if it is in this mapping
+	fn resolve(&self, changed_path: &Path) -> Option<(PathBuf, PathBuf)> {
+		let relative_path = changed_path.strip_prefix(&self.real_path).ok()?;
+		if relative_path.as_os_str().is_empty() {
+			return Some((self.client_path.clone(), self.server_path.clone()));
+		}
+		if !self.is_dir {
+			return None;
+		}
+		Some((self.client_path.join(relative_path), self.server_path.join(relative_path)))
+	}
+
+	// Get the client and server paths to sync for a virtual path that changed on the server, if it is in this mapping
+	fn resolve_server(&self, virtual_path: &Path) -> Option<(PathBuf, PathBuf)> {
+		let server_path = Path::new("/").join(virtual_path);
+		let relative_path = server_path.strip_prefix(&self.server_path).ok()?;
+		if relative_path.as_os_str().is_empty() {
+			return Some((self.client_path.clone(), self.server_path.clone()));
+		}
+		if !self.is_dir {
+			return None;
+		}
+		Some((self.client_path.join(relative_path), server_path.clone()))
+	}
+}
+
+// A structure for reading server-sent events from a response as it arrives
+struct EventConnection {
+	res: Response,
+	buffer: Vec<u8> // What has arrived of the next event
+}
+
+impl EventConnection {
+	// Wait for the next chunk of the response, returning the name and data of every event it finished, or None once the connection is lost
+	async fn next_events(&mut self) -> Option<Vec<(String, String)>> {
+		let chunk = self.res.chunk().await.ok()??;
+		self.buffer.extend_from_slice(&chunk);
+
+		// Events end with a blank line, anything without a name or data is only there to keep the connection open
+		let mut events = Vec::new();
+		while let Some(end) = self.buffer.windows(2).position(|window| window == b"\n\n") {
+			let block: Vec<u8> = self.buffer.drain(..end + 2).collect();
+			let (mut name, mut data) = (None, Vec::new());
+			for line in String::from_utf8_lossy(&block).lines() {
+				if let Some(value) = line.strip_prefix("event:") {
+					name = Some(value.trim().to_string());
+				} else if let Some(value) = line.strip_prefix("data:") {
+					data.push(value.trim_start().to_string());
+				}
+			}
+			if name.is_some() || !data.is_empty() {
+				events.push((name.unwrap_or_else(|| "message".to_string()), data.join("\n")));
+			}
+		}
+		Some(events)
+	}
+}
+
+// Utility function to wait for events if there is a connection, and forever otherwise
+async fn next_events(connection: &mut Option<EventConnection>) -> Option<Vec<(String, String)>> {
+	match connection {
+		Some(connection) => connection.next_events().await,
+		None => std::future::pending().await
+	}
+}
+
+// Utility function to find every file under a path, which is just the path if it isn't a directory
+fn walk_files(path: &Path, file_paths: &mut Vec<PathBuf>) {
+	match fs::read_dir(path) {
+		Ok(iter) => {
+			for entry in iter.flatten() {
+				walk_files(&entry.path(), file_paths);
+			}
+		},
+		Err(_) => file_paths.push(path.to_path_buf())
+	}
+}
+
+// Utility function to show a number of bytes in the largest unit that keeps it above 1
+fn format_size(bytes: u64) -> String {
+	const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+	let mut size = bytes as f64;
+	let mut unit = 0;
+	while size >= 1024.0 && unit < UNITS.len() - 1 {
+		size /= 1024.0;
+		unit += 1;
+	}
+	match unit {
+		0 => format!("{} B", bytes),
+		_ => format!("{:.1} {}", size, UNITS[unit])
+	}
+}
+
+// Utility function to get a server path as a &str without its leading slash
+fn server_path_str(server_path: &Path) -> Result<&str, ClientError> {
+	server_path.to_str()
+		.map(|server_path| server_path.trim_start_matches('/'))
+		.ok_or_else(|| ClientError::Config(format!("Server path {:?} could not be interpreted as &str", server_path)))
+}
+
+// Utility function to build the URL path for a route that takes a server path, encoding each segment of it
+fn route_path(route: &str, server_path: &Path) -> Result<String, ClientError> {
+	let segments: Vec<String> = server_path_str(server_path)?.split('/')
+		.map(|segment| utf8_percent_encode(segment, URL_COMPONENT).to_string())
+		.collect();
+	Ok(format!("{}/{}", route, segments.join("/")))
+}
+
+// Utility function to get the path a file is downloaded to before it replaces the client file
+fn download_path(client_path: &Path) -> PathBuf {
+	let mut file_name = std::ffi::OsString::from(".");
+	file_name.push(client_path.file_name().unwrap_or_default());
+	file_name.push(".download");
+	client_path.with_file_name(file_name)
+}
+
+// Utility function to turn a response the server didn't succeed with into an error
+fn check_response(res: Response) -> Result<Response, ClientError> {
+	match res.status() {
+		StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(ClientError::Unauthorized(res.status())),
+		StatusCode::TOO_MANY_REQUESTS => {
+			let retry_after = res.headers().get(reqwest::header::RETRY_AFTER)
+				.and_then(|retry_after| retry_after.to_str().ok())
+				.and_then(|retry_after| retry_after.parse().ok());
+			Err(ClientError::Busy(retry_after.unwrap_or(0)))
+		},
+		status if !status.is_success() => Err(ClientError::Status(status)),
+		_ => Ok(res)
+	}
+}
+
 struct Client {
-	config: Config
+	config: Config,
+	http_client: reqwest::Client,
+	state: SyncState,
+	index: FileIndex, // The digests of client files, so files that haven't changed aren't hashed again
+	bandwidth: BandwidthLimits,
+	client_id: String // Sent with every request so changes this client makes can be told apart
 }
 
 impl Client {
-	pub fn new() -> Self {
-		Self {
-			config: Config::from_file("Config.toml")
+	pub fn new(config_path: &Path, rehash: bool) -> Result<Self, ClientError> {
+		let config = Config::load(config_path).map_err(|e| ClientError::Config(e.to_string()))?;
+		let http_client = build_http_client(config.get_client_config())?;
+		let data_root = Path::new(config.get_client_config().get_data_root());
+		let state = SyncState::load(data_root);
+		let index = FileIndex::load(data_root, config.get_client_config().get_transfers().get_hash_threads());
+		if rehash {
+			index.clear();
 		}
+		let bandwidth = BandwidthLimits::from_config(config.get_client_config().get_bandwidth());
+		Ok(Self {
+			config,
+			http_client,
+			state,
+			index,
+			bandwidth,
+			client_id: random_token(16)
+		})
 	}
 
 	fn get_config(&self) -> &Config {
 		&self.config
 	}
-	
+
 	fn get_client_config(&self) -> &ClientConfig {
-		&self.get_config().get_client_config()
+		self.get_config().get_client_config()
 	}
-	
+
 	fn get_server_config(&self) -> &ServerConfig {
-		&self.get_config().get_server_config()
+		self.get_config().get_server_config()
 	}
-	
+
+	// The client's own password if it has one, otherwise the server password
 	fn get_password(&self) -> &str {
-		self.get_server_config().get_password()
+		self.get_client_config().get_password().unwrap_or_else(|| self.get_server_config().get_password())
+	}
+
+	fn get_username(&self) -> Option<&str> {
+		self.get_client_config().get_username()
 	}
-	
+
 	fn get_server_url(&self) -> &str {
-		&self.get_client_config().get_server_url()
+		self.get_client_config().get_server_url()
 	}
-	
+
 	fn get_mappings(&self) -> &Mappings {
-		&self.get_client_config().get_mappings()
+		self.get_client_config().get_mappings()
 	}
 
 	pub fn get_file_mappings(&self) -> &Table {
-		&self.get_mappings().get_file_mappings()
+		self.get_mappings().get_file_mappings()
 	}
 
 	pub fn get_dir_mappings(&self) -> &Table {
-		&self.get_mappings().get_dir_mappings()
+		self.get_mappings().get_dir_mappings()
 	}
 
-	// Synchronize all mapped files
-	pub async fn sync_files(&self, file_mappings: &Table) -> () {
-		// Go through all of the file mappings and update the files
-		for mapping in file_mappings.iter().map(Mapping::from_table_entry) {
-			self.update_file(mapping.get_client_path(), mapping.get_server_path()).await;
+	// Build a request to a path on the server with the credentials attached
+	// An API token is preferred, then a signature if this client signs its requests, then the password
+	// The body digest is what gets signed for the request's body, the digest of the file for uploads
+	fn request(&self, method: Method, path: &str, body_digest: Option<&str>) -> RequestBuilder {
+		let url = format!("{}{}", self.get_server_url(), path);
+		let request = self.http_client.request(method.clone(), &url).header(CLIENT_ID_HEADER, &self.client_id);
+
+		if let Some(token) = self.get_client_config().get_token() {
+			return request.header("token", token);
 		}
-	}
 
-	// Synchronize all mapped directories
-	pub async fn sync_dirs(&self, dir_mappings: &Table) -> () {
-		// Go through all of the directory mappings and update the directories
-		for mapping in dir_mappings.iter().map(Mapping::from_table_entry) {
-			self.update_dir(mapping.get_client_path(), mapping.get_server_path()).await;
+		let request = match self.get_username() {
+			Some(username) => request.header("username", username),
+			None => request
+		};
+
+		if !self.get_client_config().get_sign_requests() {
+			return request.header("password", self.get_password());
 		}
+
+		// Sign the path as the server will see it, which is after percent-encoding
+		let path_and_query = match reqwest::Url::parse(&url) {
+			Ok(url) => match url.query() {
+				Some(query) => format!("{}?{}", url.path(), query),
+				None => url.path().to_string()
+			},
+			Err(_) => path.to_string()
+		};
+		let timestamp = now_seconds();
+		let nonce = random_token(16);
+		let body_digest = body_digest.map(str::to_string).unwrap_or_else(|| sha256_digest_str(""));
+		let message = signing_message(method.as_str(), &path_and_query, timestamp, &nonce, &body_digest);
+
+		request
+			.header(SIGNATURE_HEADER, signing::sign(self.get_password(), &message))
+			.header(TIMESTAMP_HEADER, timestamp.to_string())
+			.header(NONCE_HEADER, nonce)
+			.header(CONTENT_SHA256_HEADER, body_digest)
 	}
 
-	// Update a file on the client or server based on which is most recent
-	async fn update_file(&self, client_file_path: &Path, server_file_path: &Path) -> () {
-		// Get the file info on the client, panic if unable to build the FileInfo struct
-		let client_file_info = FileInfo::from_file_path(client_file_path.to_path_buf())
-			.expect("Could not build FileInfo for client path");
+	// Send a request, returning the response only if the server succeeded
+	async fn send(&self, request: RequestBuilder) -> Result<Response, ClientError> {
+		let res = request.send().await.map_err(ClientError::Connection)?;
+		check_response(res)
+	}
 
-		// Try to parse the configured server path for the mapping as a &str, panic if unable
-		let server_file_path_str = server_file_path.to_str()
-			.expect("Server file path could not be interpreted as &str");
+	// Get some JSON from the server
+	async fn get_json<T: DeserializeOwned>(&self, path: &str) -> Result<T, ClientError> {
+		self.retrying(|| async {
+			let res = self.send(self.request(Method::GET, path, None)).await?;
+			res.json::<T>().await.map_err(ClientError::Connection)
+		}).await
+	}
 
-		// Create the HTTP client and ask the server for the file information
-		let client = reqwest::Client::new();
-		let res_result = client
-			.get(format!("{}/info/file{}", self.get_server_url(), server_file_path_str))
-			.header("password", self.get_password())
-			.send()
-			.await;
+	// Do something with the server, trying it again after a while if it fails in a way that might not happen next time
+	// A server that is too busy is given at least as long as it asked for, unless that is longer than any retry waits
+	async fn retrying<T, F, Fut>(&self, operation: F) -> Result<T, ClientError>
+	where F: Fn() -> Fut, Fut: Future<Output = Result<T, ClientError>> {
+		let retry_config = self.get_client_config().get_retry();
+		let mut retry = 0;
+		loop {
+			match operation().await {
+				Err(e) if e.class().is_transient() && retry < retry_config.get_attempts() => {
+					let delay = match e {
+						ClientError::Busy(seconds) => match retry_config.wait_for(Duration::from_secs(seconds), retry) {
+							Some(delay) => delay,
+							None => {
+								return Err(e);
+							}
+						},
+						_ => retry_config.backoff(retry)
+					};
+					time::sleep(delay).await;
+					retry += 1;
+				},
+				result => {
+					return result;
+				}
+			}
+		}
+	}
 
-		// If everything went ok, get the response, otherwise return
-		let res = match res_result {
-			Ok(res) => res,
-			Err(e) => {
-				println!("Could not get file info, status {:?}", e.status());
-				return;
+	// Delete or move something on the server, trying it again like retrying does
+	// If a retry finds the file gone, a request that seemed to fail must have reached the server after all, so it counts as done
+	async fn retrying_once_applied<F, Fut>(&self, operation: F) -> Result<(), ClientError>
+	where F: Fn() -> Fut, Fut: Future<Output = Result<(), ClientError>> {
+		let tried = Cell::new(false);
+		self.retrying(|| {
+			let retried = tried.replace(true);
+			let attempt = operation();
+			async move {
+				match attempt.await {
+					Err(ClientError::Status(StatusCode::NOT_FOUND)) if retried => Ok(()),
+					result => result
+				}
 			}
+		}).await
+	}
+
+	// Get the file and directory mappings to work on, every one of them if no names are given
+	// A mapping can be named by either its client path or its server path
+	fn select_mappings(&self, names: &[String]) -> Result<(Vec<Mapping>, Vec<Mapping>), ClientError> {
+		let mut file_mappings: Vec<Mapping> = self.get_file_mappings().iter().map(Mapping::from_table_entry).collect();
+		let mut dir_mappings: Vec<Mapping> = self.get_dir_mappings().iter().map(Mapping::from_table_entry).collect();
+		if names.is_empty() {
+			return Ok((file_mappings, dir_mappings));
+		}
+
+		let is_named = |name: &String, mapping: &Mapping| {
+			Path::new(name) == mapping.get_client_path()
+				|| name.trim_start_matches('/') == mapping.get_server_path_str().trim_start_matches('/')
 		};
+		if let Some(name) = names.iter().find(|name| !file_mappings.iter().chain(dir_mappings.iter()).any(|mapping| is_named(name, mapping))) {
+			return Err(ClientError::UnknownMapping(name.clone()));
+		}
 
-		// If not authorized, report and return
-		if res.status() == StatusCode::UNAUTHORIZED {
-			println!("Could not get file info, status {:?}", res.status());
+		file_mappings.retain(|mapping| names.iter().any(|name| is_named(name, mapping)));
+		dir_mappings.retain(|mapping| names.iter().any(|name| is_named(name, mapping)));
+		Ok((file_mappings, dir_mappings))
+	}
+
+	// Synchronize the named mappings, or every mapping if none are named
+	pub async fn sync(&self, names: &[String], options: SyncOptions, report: &mut Report) -> Result<(), ClientError> {
+		let (file_mappings, dir_mappings) = self.select_mappings(names)?;
+		if !options.dry_run {
+			let covered: Vec<&Path> = file_mappings.iter().chain(dir_mappings.iter()).map(Mapping::get_client_path).collect();
+			self.drain_pending(&covered, report).await;
+		}
+		self.sync_files(&file_mappings, options, report).await;
+		self.sync_dirs(&dir_mappings, options, report).await;
+		if !options.dry_run {
+			self.state.save();
+		}
+		Ok(())
+	}
+
+	// Sync the files that are waiting for the server to be reachable again, leaving them waiting if it still isn't
+	// Files under the covered client paths are about to be synced anyway, so they are only taken off the queue
+	async fn drain_pending(&self, covered: &[&Path], report: &mut Report) -> () {
+		if self.state.pending_len() == 0 {
 			return;
 		}
+		if let Err(e) = self.send(self.request(Method::GET, "/", None)).await {
+			if e.class().is_transient() {
+				return;
+			}
+		}
+
+		let pending: Vec<PendingOperation> = self.state.take_pending().into_iter()
+			.filter(|operation| !covered.iter().any(|client_path| operation.get_client_path().starts_with(client_path)))
+			.collect();
+		for direction in [Direction::Both, Direction::Push, Direction::Pull] {
+			let file_paths: Vec<(PathBuf, PathBuf)> = pending.iter()
+				.filter(|operation| operation.get_direction() == direction)
+				.map(|operation| (operation.get_client_path().to_path_buf(), operation.get_server_path().to_path_buf()))
+				.collect();
+			if !file_paths.is_empty() {
+				self.update_files(file_paths, SyncOptions { direction, dry_run: false }, report).await;
+			}
+		}
+	}
+
+	// Synchronize mapped files
+	pub async fn sync_files(&self, file_mappings: &[Mapping], options: SyncOptions, report: &mut Report) -> () {
+		// Update all of the mapped files together
+		let file_paths = file_mappings.iter().map(|mapping| (mapping.get_client_path().to_path_buf(), mapping.get_server_path().to_path_buf())).collect();
+		self.update_files(file_paths, options, report).await;
+	}
+
+	// Synchronize mapped directories
+	pub async fn sync_dirs(&self, dir_mappings: &[Mapping], options: SyncOptions, report: &mut Report) -> () {
+		// Go through all of the directory mappings and update what changed in them
+		for mapping in dir_mappings {
+			self.update_changed_dir(mapping.get_client_path(), mapping.get_server_path(), options, report).await;
+		}
+	}
+
+	// Update files on the client or server based on which side changed, checking and transferring several at the same time over the one HTTP client
+	// Every file is checked before any are transferred, so the smallest can go first and many small files aren't held up behind a large one
+	async fn update_files(&self, file_paths: Vec<(PathBuf, PathBuf)>, options: SyncOptions, report: &mut Report) -> () {
+		let concurrent = self.get_client_config().get_transfers().get_concurrent();
+
+		// Hash whatever the index doesn't know yet on several threads rather than one file at a time
+		let client_file_paths: Vec<PathBuf> = file_paths.iter().map(|(client_path, _)| client_path.clone()).filter(|client_path| client_path.is_file()).collect();
+		self.index.digest_all(&client_file_paths);
+
+		let mut file_plans = Vec::new();
+		let mut planned = stream::iter(file_paths)
+			.map(|(client_path, server_path)| async move {
+				let result = self.plan_file(&client_path, &server_path, options.direction).await;
+				(client_path, server_path, result)
+			})
+			.buffer_unordered(concurrent);
+		while let Some((client_path, server_path, result)) = planned.next().await {
+			match result {
+				Ok(file_plan) => file_plans.push(file_plan),
+				Err(e) => {
+					self.queue_if_transient(&client_path, &server_path, options, &e);
+					report.record(&client_path, Err(e));
+				}
+			}
+		}
+
+		file_plans.sort_by_key(|file_plan| file_plan.entry.size);
+		let mut applied = stream::iter(file_plans)
+			.map(|file_plan| async move {
+				let (client_path, server_path) = (file_plan.entry.client_path.clone(), file_plan.entry.server_path.clone());
+				(client_path, server_path, self.apply_plan(file_plan, options).await)
+			})
+			.buffer_unordered(concurrent);
+		while let Some((client_path, server_path, result)) = applied.next().await {
+			if let Err(e) = &result {
+				self.queue_if_transient(&client_path, &server_path, options, e);
+			}
+			report.record(&client_path, result);
+		}
+	}
+
+	// Remember a file that failed to sync for a reason that might go away, so it is synced again as soon as the server can be reached
+	fn queue_if_transient(&self, client_path: &Path, server_path: &Path, options: SyncOptions, e: &ClientError) {
+		if !options.dry_run && e.class().is_transient() {
+			self.state.queue(PendingOperation::new(client_path, server_path, options.direction, e.to_string()));
+		}
+	}
+
+	// Work out what to do with a file by comparing the client's copy with the server's
+	async fn plan_file(&self, client_file_path: &Path, server_file_path: &Path, direction: Direction) -> Result<FilePlan, ClientError> {
+		// Get the file info on the client, which is what the server had when the placeholder was made if there is only a placeholder
+		let placeholders = self.uses_placeholders(client_file_path);
+		let placeholder_file_info = if placeholders && !client_file_path.exists() { read_placeholder(client_file_path) } else { None };
+		let placeholder = placeholder_file_info.is_some();
+		let client_file_info = match placeholder_file_info {
+			Some(client_file_info) => client_file_info,
+			None => FileInfo::from_indexed_file_path(client_file_path.to_path_buf(), &self.index)
+				.map_err(|_| ClientError::NotFile(client_file_path.to_path_buf()))?
+		};
+
+		// Ask the server for the file information, which it answers with a 404 for paths it ignores
+		let (server_file_info, ignored) = match self.get_json(&route_path("/info/file", server_file_path)?).await {
+			Ok(server_file_info) => (server_file_info, false),
+			Err(ClientError::Status(StatusCode::NOT_FOUND)) => (FileInfo::missing(server_file_path.to_path_buf()), true),
+			Err(e) => {
+				return Err(e);
+			}
+		};
 
-		// Build the FileInfo struct for the file on the server, panic if unable
-		let server_file_info = res.json::<FileInfo>().await
-			.expect("Could not build FileInfo for server path");
-		
 		// Here is the real logic of syncing the files comes in
-		match (client_file_info.exists(), server_file_info.exists()) {
-			// If the file exists on the client and the server, do some more checks
-			(true, true) => {
-				// If the server and client do not have the same file contents (meaning there has been a change since the last sync)
-				if client_file_info.get_digest() != server_file_info.get_digest() {
-					// Depending on which was more recently changed, upload or download
-					if client_file_info.get_seconds() < server_file_info.get_seconds() {
-						self.download(server_file_info.get_path(), client_file_info.get_path()).await;
+		let synced_digest = self.state.get(client_file_path, server_file_path);
+		let action = match SyncAction::plan(&client_file_info, &server_file_info, synced_digest.as_deref(), direction) {
+			// Placeholders are never uploaded, and files the client doesn't have get one instead of being downloaded
+			SyncAction::Upload if placeholder => SyncAction::Skip,
+			SyncAction::Download if placeholders && !client_file_path.exists() => SyncAction::Placeholder,
+			// With neither the file nor its placeholder left, the server's copy is only deleted if the mappings allow it, otherwise the placeholder is put back
+			SyncAction::DeleteRemote if placeholders && !self.get_mappings().get_delete_placeholders() => SyncAction::Placeholder,
+			// The server never stores a path it ignores, so the client's copy is kept rather than taken for one the server deleted
+			_ if ignored && client_file_info.exists() => SyncAction::Upload,
+			_ if ignored => SyncAction::Skip,
+			action => action
+		};
+
+		let size = match action {
+			SyncAction::Download | SyncAction::DeleteRemote | SyncAction::Placeholder => server_file_info.get_size(),
+			_ => client_file_info.get_size()
+		};
+
+		// The mapping's rules keep some files from being copied at all, and leave files that changed too recently for a later sync
+		// Large files are left alone during the hours they shouldn't be copied as well, to be picked up by a later sync
+		let (mut deferred, mut refused, mut reason) = (false, false, None);
+		if matches!(action, SyncAction::Upload | SyncAction::Download) {
+			let seconds = if action == SyncAction::Download { server_file_info.get_seconds() } else { client_file_info.get_seconds() };
+			let rules = self.get_mappings().get_filter(&self.filter_root(client_file_path)).map_err(ClientError::Config)?;
+			if ignored {
+				(refused, reason) = (true, Some("ignored by the server".to_string()));
+			} else if let Some(refusal) = rules.as_ref().and_then(|rules| rules.refusal(client_file_path, size)) {
+				(refused, reason) = (true, Some(refusal));
+			} else if let Some(rules) = rules.as_ref().filter(|rules| rules.is_too_new(seconds, now_seconds())) {
+				(deferred, reason) = (true, Some(format!("changed less than {} seconds ago", rules.get_min_age_seconds())));
+			} else if self.get_client_config().get_bandwidth().is_deferred(size, now_seconds()) {
+				(deferred, reason) = (true, Some("too large to copy during these hours".to_string()));
+			}
+		}
+		Ok(FilePlan {
+			entry: PlanEntry {
+				action,
+				client_path: client_file_path.to_path_buf(),
+				server_path: server_file_path.to_path_buf(),
+				size,
+				deferred,
+				refused,
+				reason
+			},
+			client_file_info,
+			server_file_info,
+			placeholder
+		})
+	}
+
+	// Do what was planned for a file, unless this is a dry run, returning what was done
+	async fn apply_plan(&self, file_plan: FilePlan, options: SyncOptions) -> Result<PlanEntry, ClientError> {
+		let FilePlan { entry, client_file_info, server_file_info, placeholder } = file_plan;
+		let (client_file_path, server_file_path) = (entry.client_path.as_path(), entry.server_path.as_path());
+		if !options.dry_run && !entry.deferred && !entry.refused {
+			match entry.action {
+				SyncAction::Upload => {
+					self.upload(client_file_path, server_file_path).await?;
+					self.state.set(client_file_path, server_file_path, client_file_info.get_digest());
+				},
+				SyncAction::Download => {
+					self.download(server_file_path, client_file_path).await?;
+					self.state.set(client_file_path, server_file_path, server_file_info.get_digest());
+				},
+				SyncAction::DeleteLocal if placeholder => {
+					fs::remove_file(placeholder_path(client_file_path)).map_err(ClientError::Io)?;
+					self.state.forget(client_file_path, server_file_path);
+				},
+				SyncAction::DeleteLocal => {
+					fs::remove_file(client_file_path).map_err(ClientError::Io)?;
+					self.state.forget(client_file_path, server_file_path);
+					self.index.forget(client_file_path);
+				},
+				SyncAction::DeleteRemote => {
+					self.remove(server_file_path).await?;
+					self.state.forget(client_file_path, server_file_path);
+					self.index.forget(client_file_path);
+				},
+				// Files that are already the same on both sides are in sync
+				SyncAction::Skip if client_file_info.get_digest() == server_file_info.get_digest() => {
+					if client_file_info.exists() {
+						self.state.set(client_file_path, server_file_path, client_file_info.get_digest());
 					} else {
-						self.upload(client_file_info.get_path(), server_file_info.get_path()).await;
+						self.state.forget(client_file_path, server_file_path);
 					}
+				},
+				SyncAction::Placeholder => {
+					write_placeholder(client_file_path, &server_file_info).map_err(ClientError::Io)?;
+					self.state.set(client_file_path, server_file_path, server_file_info.get_digest());
+				},
+				SyncAction::Skip | SyncAction::Conflict | SyncAction::Hydrate | SyncAction::Dehydrate => {}
+			}
+		}
+		Ok(entry)
+	}
+
+	// Ask the server what changed under a directory since the cursor saved for it, returning the new cursor and the changed paths relative to the directory
+	// There are no paths if the server can't say what changed, and no cursor either if it doesn't keep track of changes at all
+	async fn server_changes(&self, client_dir_path: &Path, server_dir_path: &Path) -> (Option<u64>, Option<BTreeSet<PathBuf>>) {
+		let prefix = match server_path_str(server_dir_path) {
+			Ok(prefix) => utf8_percent_encode(prefix, URL_COMPONENT).to_string(),
+			Err(_) => {
+				return (None, None);
+			}
+		};
+
+		let mut since = self.state.get_cursor(client_dir_path, server_dir_path);
+		let mut relative_paths = BTreeSet::new();
+		loop {
+			let path = match since {
+				Some(since) => format!("/changes?prefix={}&since={}", prefix, since),
+				None => format!("/changes?prefix={}", prefix)
+			};
+			let feed: ChangeFeed = match self.get_json(&path).await {
+				Ok(feed) => feed,
+				Err(_) => {
+					return (None, None);
 				}
-			},
-			// If the file exists on the client but not on the server, upload it
-			(true, false) => {
-				self.upload(client_file_info.get_path(), server_file_info.get_path()).await;
-			},
-			// If the file exists on the server but not on the client, download it
-			(false, true) => {
-				self.download(server_file_info.get_path(), client_file_info.get_path()).await;
-			},
-			// If the file does not exist anywhere, do nothing
-			(false, false) => {}
-		}
-	}
-
-	// Update the files in a directory on the client or server based on which are most recent
-	async fn update_dir(&self, client_dir_path: &Path, server_dir_path: &Path) -> () {
-		// Get the file infos on the client, panic if unable to build the FileInfo structs
-		let client_file_infos = FileInfo::from_dir_path(client_dir_path)
-			.expect(format!("Could not build FileInfo for client path {:?}", client_dir_path).as_str());
-		
-		// Try to parse the configured server path for the mapping as a &str, panic if unable
-		let server_dir_path_str = server_dir_path.to_str().expect("Server dir path could not be interpreted as &str");
-
-		// Create the HTTP client and ask the server for the directory information
-		let client = reqwest::Client::new();
-		let res_result = client
-			.get(format!("{}/info/dir{}", self.get_server_url(), server_dir_path_str))
-			.header("password", self.get_password())
-			.send()
-			.await;
-		
-		// If everything went ok, get the response, otherwise return
-		let res = match res_result {
-			Ok(res) => res,
+			};
+			if feed.get_reset() {
+				return (Some(feed.get_cursor()), None);
+			}
+
+			let changed_paths = feed.get_changes().iter().flat_map(ChangeEvent::get_paths);
+			relative_paths.extend(changed_paths.filter_map(|virtual_path| {
+				let relative_path = Path::new("/").join(virtual_path).strip_prefix(server_dir_path).ok()?.to_path_buf();
+				(!relative_path.as_os_str().is_empty()).then_some(relative_path)
+			}));
+
+			since = Some(feed.get_cursor());
+			if feed.get_complete() {
+				return (since, Some(relative_paths));
+			}
+		}
+	}
+
+	// Update only the files in a mapped directory that changed on either side since it was last in sync, or every file if the server can't say
+	async fn update_changed_dir(&self, client_dir_path: &Path, server_dir_path: &Path, options: SyncOptions, report: &mut Report) -> () {
+		let unfinished = report.unfinished();
+		let filter = match self.path_filter(client_dir_path) {
+			Ok(filter) => filter,
 			Err(e) => {
-				println!("Could not get dir info, status {:?}", e.status());
+				report.fail(client_dir_path, e);
 				return;
 			}
 		};
+		let (cursor, server_relative_paths) = self.server_changes(client_dir_path, server_dir_path).await;
+		match server_relative_paths {
+			Some(server_relative_paths) => {
+				// Get the file infos on the client, which reads the rules on the way down
+				let client_file_infos = match self.client_file_infos(client_dir_path, &filter) {
+					Ok(client_file_infos) => client_file_infos,
+					Err(e) => {
+						report.fail(client_dir_path, e);
+						return;
+					}
+				};
 
-		// If not authorized, report and return
-		if res.status() == StatusCode::UNAUTHORIZED {
-			println!("Could not get dir info, status {:?}", res.status());
-			return;
+				// Files the rules let in now might not have changed on either side since the last sync, so different rules mean looking at everything
+				if self.state.get_filter_digest(client_dir_path, server_dir_path) != Some(filter.digest()) {
+					self.update_dir(client_dir_path, server_dir_path, &filter, options, report).await;
+				} else {
+					// Files on the client are new or changed if they don't match the last sync, and gone if they were in sync but aren't there anymore
+					let client_changed_paths = client_file_infos.iter().filter_map(|file_info| {
+						let server_path = server_dir_path.join(file_info.get_path().strip_prefix(client_dir_path).ok()?);
+						let changed = self.state.get(file_info.get_path(), &server_path).as_deref() != Some(file_info.get_digest());
+						changed.then(|| file_info.get_path().to_path_buf())
+					});
+					let placeholders = self.uses_placeholders(client_dir_path);
+					let is_there = |client_path: &Path| client_path.exists() || (placeholders && placeholder_path(client_path).exists());
+					let client_gone_paths = self.state.paths_under(client_dir_path, server_dir_path).into_iter()
+						.map(|(client_path, _)| client_path)
+						.filter(|client_path| !is_there(client_path) && filter.includes(client_path, false));
+					let client_relative_paths: Vec<PathBuf> = client_changed_paths.chain(client_gone_paths)
+						.filter_map(|client_path| client_path.strip_prefix(client_dir_path).ok().map(Path::to_path_buf))
+						.collect();
+
+					let relative_paths: BTreeSet<PathBuf> = server_relative_paths.into_iter()
+						.filter(|relative_path| filter.includes(&client_dir_path.join(relative_path), false))
+						.chain(client_relative_paths)
+						.collect();
+					let file_paths = relative_paths.iter().map(|relative_path| (client_dir_path.join(relative_path), server_dir_path.join(relative_path))).collect();
+					self.update_files(file_paths, options, report).await;
+				}
+			},
+			None => self.update_dir(client_dir_path, server_dir_path, &filter, options, report).await
 		}
 
-		// Build the FileInfo structs for the files in the directory on the server, panic if unable
-		let server_file_infos = res.json::<Vec<FileInfo>>().await
-			.expect(format!("Could not build FileInfos for server path {:?}", server_dir_path).as_str());
-		
-		// Loop through each file on the client
-		for file_info in client_file_infos.iter() {
-			// Get the path to the file relative to the client's directory's path, panic if unable
-			let client_file_path = file_info.get_path();
-			let dir_file_path = client_file_path.strip_prefix(client_dir_path)
-				.expect("Could not strip client dir path prefix");
-			// Build the path of the file on the server
-			let mut server_file_path = server_dir_path.to_path_buf();
-			server_file_path.push(dir_file_path);
-			// Update the file, syncing based on which is most recent
-			self.update_file(client_file_path, &server_file_path).await;
-		}
-		
-		// Loop through each file on the server
-		for file_info in server_file_infos.iter() {
-			// Get the path to the file relative to the server's directory's path
-			let mut server_file_path = server_dir_path.to_path_buf();
-			server_file_path.push(file_info.get_path());
-			// Build the path of the file on the client
-			let mut client_file_path = client_dir_path.to_path_buf();
-			client_file_path.push(file_info.get_path());
-			// Update the file, syncing based on which is most recent
-			self.update_file(&client_file_path, &server_file_path).await;
-		}
-	}
-	
-	// Download a file located at server_path from the server and save it to client_path
-	async fn download(&self, server_path: &Path, client_path: &Path) -> () {
-		// Get the directory that the file will be saved to, create it if it doesn't exist, panic if it has no parent
-		let parent_path = client_path.parent()
-			.expect(format!("Client path {:?} has no parent", client_path).as_str());
-		fs::create_dir_all(parent_path).expect(format!("Could not create dirs needed for {:?}", client_path).as_str());
-
-		// Create the file that will be written to
-		let mut file = fs::File::create(client_path)
-			.expect("File creation failed");
-		
-		// Try to parse the given server path as a &str, panic if unable
-		let server_path = server_path.to_str()
-			.expect("Server path could not be interpreted as &str");
-		
-		// Create the HTTP client and download the file from the server
-		let client = reqwest::Client::new();
-		let res_result = client
-			.get(format!("{}/file/{}", self.get_server_url(), server_path))
-			.header("password", self.get_password())
-			.send()
-			.await;
-
-		// If everything went ok, get the response, otherwise return
-		let res = match res_result {
-			Ok(res) => res,
+		// The rules are only all read once the directory has been walked, so the files they now leave out are forgotten after
+		if !options.dry_run {
+			self.forget_excluded(client_dir_path, server_dir_path, &filter);
+		}
+
+		// Anything that failed or was deferred has to be looked at again next time, so the cursor only moves on if nothing was
+		if report.unfinished() == unfinished {
+			if let Some(cursor) = cursor {
+				self.state.set_cursor(client_dir_path, server_dir_path, cursor);
+			}
+			self.state.set_filter_digest(client_dir_path, server_dir_path, &filter.digest());
+		}
+	}
+
+	// Update the files in a directory on the client or server based on which are most recent, leaving out files the filter refuses
+	async fn update_dir(&self, client_dir_path: &Path, server_dir_path: &Path, filter: &PathFilter, options: SyncOptions, report: &mut Report) -> () {
+		// Only the files that differ need looking at, found by comparing trees or by listing both sides if the server can't compare trees
+		let relative_paths = match self.tree_changes(client_dir_path, server_dir_path, filter).await {
+			Err(ClientError::Status(StatusCode::NOT_FOUND)) => self.listed_paths(client_dir_path, server_dir_path, filter).await,
+			result => result
+		};
+		let relative_paths = match relative_paths {
+			Ok(relative_paths) => relative_paths,
 			Err(e) => {
-				println!("Could not download file, status {:?}", e.status());
+				report.fail(client_dir_path, e);
 				return;
 			}
 		};
 
-		// If not authorized, report and return
-		if res.status() == StatusCode::UNAUTHORIZED {
-			println!("Could not download file, status {:?}", res.status());
+		// Update each file, syncing based on which is most recent
+		let file_paths = relative_paths.iter().map(|relative_path| (client_dir_path.join(relative_path), server_dir_path.join(relative_path))).collect();
+		self.update_files(file_paths, options, report).await;
+	}
+
+	// Get every file on either side of a directory that the filter lets in, relative to it, in order and only once
+	async fn listed_paths(&self, client_dir_path: &Path, server_dir_path: &Path, filter: &PathFilter) -> Result<BTreeSet<PathBuf>, ClientError> {
+		// Get the file infos on the client
+		let client_file_infos = self.client_file_infos(client_dir_path, filter)?;
+
+		// Ask the server for the directory information
+		let server_file_infos: Vec<FileInfo> = self.get_json(&route_path("/info/dir", server_dir_path)?).await?;
+
+		let client_relative_paths = client_file_infos.iter()
+			.filter_map(|file_info| file_info.get_path().strip_prefix(client_dir_path).ok())
+			.map(Path::to_path_buf);
+		let server_relative_paths = server_file_infos.iter()
+			.map(|file_info| file_info.get_path().to_path_buf())
+			.filter(|relative_path| filter.includes(&client_dir_path.join(relative_path), false));
+		Ok(client_relative_paths.chain(server_relative_paths).collect())
+	}
+
+	// Get the files that differ between the two sides of a directory, relative to it
+	// The trees are compared a level at a time, so only directories that differ are looked inside and an unchanged directory takes one request
+	// Files found to be the same on both sides along the way are remembered as in sync
+	// Files the filter refuses are left out, though the server still has them in its digests, so directories with them on the server are always looked inside
+	async fn tree_changes(&self, client_dir_path: &Path, server_dir_path: &Path, filter: &PathFilter) -> Result<BTreeSet<PathBuf>, ClientError> {
+		let include = |relative_path: &Path, is_dir: bool| filter.includes(&client_dir_path.join(relative_path), is_dir);
+		let client_tree = TreeInfo::from_dir_path(client_dir_path, u32::MAX, &include, Some(&self.index), self.uses_placeholders(client_dir_path))
+			.map_err(|_| ClientError::Config(format!("{:?} is not a directory", client_dir_path)))?;
+		filter.check().map_err(ClientError::Config)?;
+		let server_tree_path = |relative_path: &Path, depth: u32| -> Result<String, ClientError> {
+			let server_path = if relative_path.as_os_str().is_empty() { server_dir_path.to_path_buf() } else { server_dir_path.join(relative_path) };
+			Ok(format!("{}?depth={}", route_path("/info/tree", &server_path)?, depth))
+		};
+
+		let mut relative_paths = BTreeSet::new();
+		let mut dirs = vec![(PathBuf::new(), &client_tree)]; // Directories that might differ, relative to the mapped one
+		while let Some((relative_dir_path, client_dir_tree)) = dirs.pop() {
+			let server_dir_tree: TreeInfo = self.get_json(&server_tree_path(&relative_dir_path, 1)?).await?;
+			if server_dir_tree.get_digest() == client_dir_tree.get_digest() {
+				self.remember_tree(&client_dir_path.join(&relative_dir_path), &server_dir_path.join(&relative_dir_path), client_dir_tree);
+				continue;
+			}
+
+			let names: BTreeSet<&str> = client_dir_tree.get_children().into_iter().flatten()
+				.chain(server_dir_tree.get_children().into_iter().flatten())
+				.map(TreeInfo::get_name)
+				.collect();
+			for name in names {
+				let relative_path = relative_dir_path.join(name);
+				match (client_dir_tree.get_child(name), server_dir_tree.get_child(name)) {
+					(Some(client_tree), Some(server_tree)) if client_tree.get_digest() == server_tree.get_digest() => {
+						self.remember_tree(&client_dir_path.join(&relative_path), &server_dir_path.join(&relative_path), client_tree);
+					},
+					(Some(client_tree), Some(server_tree)) if client_tree.get_is_dir() && server_tree.get_is_dir() => {
+						dirs.push((relative_path, client_tree));
+					},
+					// Anything else means every file on either side is different, which takes the whole server tree for a directory
+					(client_tree, server_tree) => {
+						if let Some(client_tree) = client_tree {
+							if client_tree.get_is_dir() {
+								relative_paths.extend(client_tree.file_paths().into_iter().map(|(path, _)| relative_path.join(path)));
+							} else {
+								relative_paths.insert(relative_path.clone());
+							}
+						}
+						if let Some(server_tree) = server_tree {
+							if server_tree.get_is_dir() {
+								if !include(&relative_path, true) {
+									continue;
+								}
+								let server_tree: TreeInfo = self.get_json(&server_tree_path(&relative_path, u32::MAX)?).await?;
+								relative_paths.extend(server_tree.file_paths().into_iter().map(|(path, _)| relative_path.join(path)));
+							} else {
+								relative_paths.insert(relative_path.clone());
+							}
+						}
+					}
+				}
+			}
+		}
+		relative_paths.retain(|relative_path| include(relative_path, false));
+		Ok(relative_paths)
+	}
+
+	// Get the client path of the mapped directory a client path is in, which is where its filter's rules start
+	fn filter_root(&self, client_path: &Path) -> PathBuf {
+		self.get_dir_mappings().keys()
+			.map(Path::new)
+			.filter(|client_dir_path| client_path.starts_with(client_dir_path))
+			.max_by_key(|client_dir_path| client_dir_path.components().count())
+			.unwrap_or(client_path)
+			.to_path_buf()
+	}
+
+	// Get the filter for the mapped directory a client path is in
+	fn path_filter(&self, client_path: &Path) -> Result<PathFilter, ClientError> {
+		let filter_root = self.filter_root(client_path);
+		let rules = self.get_mappings().get_filter(&filter_root).map_err(ClientError::Config)?;
+		PathFilter::load(&filter_root, rules.as_ref()).map_err(ClientError::Config)
+	}
+
+	// If the mapped directory a client path is in gets placeholders instead of downloads
+	fn uses_placeholders(&self, client_path: &Path) -> bool {
+		self.get_mappings().uses_placeholders(&self.filter_root(client_path))
+	}
+
+	// Get the info of every file under a directory on the client that the filter lets in, taking placeholders to be the files they stand in for
+	fn client_file_infos(&self, client_dir_path: &Path, filter: &PathFilter) -> Result<Vec<FileInfo>, ClientError> {
+		let placeholders = self.uses_placeholders(client_dir_path);
+		let include = |path: &Path, is_dir: bool| match (placeholders && !is_dir).then(|| placeholder_target(path)).flatten() {
+			Some(target_path) => filter.includes(&target_path, false),
+			None => filter.includes(path, is_dir)
+		};
+		let client_file_infos = FileInfo::from_indexed_dir_path(client_dir_path, &self.index, &include)
+			.map_err(|_| ClientError::Config(format!("{:?} is not a directory", client_dir_path)))?;
+		filter.check().map_err(ClientError::Config)?;
+
+		// A placeholder only counts if the file it stands in for isn't there as well
+		Ok(client_file_infos.into_iter()
+			.filter_map(|file_info| match placeholders.then(|| placeholder_target(file_info.get_path())).flatten() {
+				Some(target_path) if target_path.exists() => None,
+				Some(target_path) => read_placeholder(&target_path),
+				None => Some(file_info)
+			})
+			.collect())
+	}
+
+	// Forget the last sync of every file the filter now leaves out, so bringing one back later can't look like it was deleted on one side
+	fn forget_excluded(&self, client_dir_path: &Path, server_dir_path: &Path, filter: &PathFilter) {
+		for (client_path, server_path) in self.state.paths_under(client_dir_path, server_dir_path) {
+			if !filter.includes(&client_path, false) {
+				self.state.forget(&client_path, &server_path);
+			}
+		}
+	}
+
+	// Remember every file in a tree that is the same on both sides as in sync
+	fn remember_tree(&self, client_path: &Path, server_path: &Path, tree_info: &TreeInfo) {
+		if !tree_info.get_is_dir() {
+			self.state.set(client_path, server_path, tree_info.get_digest());
 			return;
 		}
+		for (relative_path, digest) in tree_info.file_paths() {
+			self.state.set(&client_path.join(&relative_path), &server_path.join(relative_path), digest);
+		}
+	}
+
+	// Keep the named mappings in sync until interrupted
+	// Paths are synced shortly after they stop changing on the client or the server says they changed there,
+	// and every mapping is synced every so often in case anything was missed
+	pub async fn watch(&self, names: &[String], json: bool) -> Result<(), ClientError> {
+		let (file_mappings, dir_mappings) = self.select_mappings(names)?;
+		let options = SyncOptions { direction: Direction::Both, dry_run: false };
+		let watch_config = self.get_client_config().get_watch();
+
+		// The watcher reports changed paths from its own thread
+		let (sender, mut receiver) = mpsc::unbounded_channel::<PathBuf>();
+		let mut watcher = notify::recommended_watcher(move |result: notify::Result<notify::Event>| {
+			if let Ok(event) = result {
+				for path in event.paths {
+					let _ = sender.send(path);
+				}
+			}
+		}).map_err(ClientError::Watch)?;
+
+		// Files are watched through their directories so they are still seen when an editor replaces them
+		let mut watched_mappings = Vec::new();
+		for (mapping, is_dir) in file_mappings.iter().map(|mapping| (mapping, false)).chain(dir_mappings.iter().map(|mapping| (mapping, true))) {
+			let client_path = mapping.get_client_path();
+			let watch_path = match (is_dir, client_path.parent()) {
+				(true, _) => client_path,
+				(false, Some(parent_path)) if !parent_path.as_os_str().is_empty() => parent_path,
+				(false, _) => Path::new(".")
+			};
+			fs::create_dir_all(watch_path).map_err(ClientError::Io)?;
+			let real_watch_path = fs::canonicalize(watch_path).map_err(ClientError::Io)?;
+			let recursive_mode = if is_dir { RecursiveMode::Recursive } else { RecursiveMode::NonRecursive };
+			watcher.watch(&real_watch_path, recursive_mode).map_err(ClientError::Watch)?;
+
+			let real_path = match (is_dir, client_path.file_name()) {
+				(false, Some(file_name)) => real_watch_path.join(file_name),
+				_ => real_watch_path
+			};
+			watched_mappings.push(WatchedMapping {
+				client_path: client_path.to_path_buf(),
+				server_path: mapping.get_server_path().to_path_buf(),
+				real_path,
+				is_dir
+			});
+		}
+
+		// Text output only mentions runs that did something, JSON gets a summary every time to show the client is alive
+		let finish = |report: Report| {
+			if report.json || !report.entries.is_empty() || !report.failures.is_empty() {
+				report.finish();
+			}
+		};
+
+		let debounce = Duration::from_millis(watch_config.get_debounce_milliseconds());
+		let poll_interval = Duration::from_secs(watch_config.get_poll_seconds().max(1));
+		let server_paths: Vec<&Path> = watched_mappings.iter().map(|watched_mapping| watched_mapping.server_path.as_path()).collect();
+		let mut pending: BTreeMap<PathBuf, PathBuf> = BTreeMap::new(); // Changed client paths and the server paths they sync with
+		let mut deadline = time::Instant::now();
+		let mut next_poll = time::Instant::now();
+		let mut connection: Option<EventConnection> = None;
+		let mut next_connect = time::Instant::now();
+
+		// Listen for Ctrl+C from the start, so one that comes in the middle of a sync stops the watch as soon as it is done
+		let ctrl_c = tokio::signal::ctrl_c();
+		tokio::pin!(ctrl_c);
+		let _ = futures::poll!(&mut ctrl_c);
+		loop {
+			tokio::select! {
+				Some(changed_path) = receiver.recv() => {
+					if let Some((client_path, server_path)) = watched_mappings.iter().find_map(|watched_mapping| watched_mapping.resolve(&changed_path)) {
+						pending.insert(client_path, server_path);
+						deadline = time::Instant::now() + debounce;
+					}
+				},
+				events = next_events(&mut connection) => {
+					let Some(events) = events else {
+						// Anything could have changed while disconnected
+						connection = None;
+						next_connect = time::Instant::now() + poll_interval;
+						next_poll = time::Instant::now();
+						continue;
+					};
+					for (name, data) in events {
+						match name.as_str() {
+							"change" => {
+								// Changes this client made are already in sync
+								let change: ChangeEvent = match serde_json::from_str(&data) {
+									Ok(change) => change,
+									Err(_) => continue
+								};
+								if change.get_client() == Some(self.client_id.as_str()) {
+									continue;
+								}
+								for virtual_path in change.get_paths() {
+									if let Some((client_path, server_path)) = watched_mappings.iter().find_map(|watched_mapping| watched_mapping.resolve_server(virtual_path)) {
+										pending.insert(client_path, server_path);
+										deadline = time::Instant::now() + debounce;
+									}
+								}
+							},
+							// The server dropped some changes, so everything has to be checked
+							"resync" => next_poll = time::Instant::now(),
+							_ => {}
+						}
+					}
+				},
+				_ = time::sleep_until(next_connect), if connection.is_none() => {
+					match self.follow_changes(&server_paths).await {
+						Ok(new_connection) => {
+							connection = Some(new_connection);
+
+							// The server is back, so whatever failed while it was gone can be synced now
+							let mut report = Report::new(json, false);
+							self.drain_pending(&[], &mut report).await;
+							self.state.save();
+							finish(report);
+						},
+						Err(e) => {
+							let mut report = Report::new(json, false);
+							report.fail(Path::new(self.get_server_url()), e);
+							finish(report);
+							next_connect = time::Instant::now() + poll_interval;
+						}
+					}
+				},
+				_ = time::sleep_until(deadline), if !pending.is_empty() => {
+					let mut report = Report::new(json, false);
+					// The rules can change along with the files, so they are read again each time
+					let mut filters: HashMap<PathBuf, PathFilter> = HashMap::new();
+					let mut included_file_paths = Vec::new();
+					for (client_path, server_path) in std::mem::take(&mut pending) {
+						// A placeholder changing is the file it stands in for changing, and one that was deleted can only be told by its name
+						let is_placeholder = |path: &Path| !path.exists() || placeholder_target(path).is_some();
+						let (client_path, server_path) = match placeholder_name_target(&client_path).filter(|_| self.uses_placeholders(&client_path) && is_placeholder(&client_path)) {
+							Some(target_path) => (target_path, server_path.with_extension("")),
+							None => (client_path, server_path)
+						};
+						let filter_root = self.filter_root(&client_path);
+						if !filters.contains_key(&filter_root) {
+							match self.path_filter(&client_path) {
+								Ok(filter) => {
+									filters.insert(filter_root.clone(), filter);
+								},
+								Err(e) => {
+									report.fail(&client_path, e);
+									continue;
+								}
+							}
+						}
+
+						// A directory that was deleted can't be told from a file by looking at it, but the files that were in sync under it can
+						let filter = &filters[&filter_root];
+						let is_dir = client_path.is_dir()
+							|| (!client_path.exists() && self.state.paths_under(&client_path, &server_path).iter().any(|(synced_path, _)| *synced_path != client_path));
+						if !filter.includes(&client_path, is_dir) {
+							continue;
+						}
+						if let Err(e) = filter.check() {
+							report.fail(&client_path, ClientError::Config(e));
+							continue;
+						}
+						if is_dir {
+							self.update_dir(&client_path, &server_path, filter, options, &mut report).await;
+						} else {
+							included_file_paths.push((client_path, server_path));
+						}
+					}
+					self.update_files(included_file_paths, options, &mut report).await;
+					self.state.save();
+					finish(report);
+				},
+				_ = time::sleep_until(next_poll) => {
+					next_poll = time::Instant::now() + poll_interval;
+					let mut report = Report::new(json, false);
+					let covered: Vec<&Path> = file_mappings.iter().chain(dir_mappings.iter()).map(Mapping::get_client_path).collect();
+					self.drain_pending(&covered, &mut report).await;
+					self.sync_files(&file_mappings, options, &mut report).await;
+					self.sync_dirs(&dir_mappings, options, &mut report).await;
+					self.state.save();
+					finish(report);
+				},
+				_ = &mut ctrl_c => {
+					return Ok(());
+				}
+			}
+		}
+	}
+
+	// Start following changes on the server under the given server paths
+	async fn follow_changes(&self, server_paths: &[&Path]) -> Result<EventConnection, ClientError> {
+		let mut query = Vec::new();
+		for server_path in server_paths {
+			query.push(format!("prefix={}", utf8_percent_encode(server_path_str(server_path)?, URL_COMPONENT)));
+		}
+		let res = self.send(self.request(Method::GET, &format!("/events?{}", query.join("&")), None)).await?;
+		Ok(EventConnection {
+			res,
+			buffer: Vec::new()
+		})
+	}
+
+	// Download a file located at server_path from the server and save it to client_path
+	async fn download(&self, server_path: &Path, client_path: &Path) -> Result<(), ClientError> {
+		self.retrying(|| self.download_once(server_path, client_path)).await
+	}
 
-		// Get the response text and copy it as bytes to the created file
-		let res_text = res.text().await
-			.expect("Text extraction failed");
-		io::copy(&mut res_text.as_bytes(), &mut file).expect("Copy failed");
+	// Download a file once, without trying again if it fails
+	async fn download_once(&self, server_path: &Path, client_path: &Path) -> Result<(), ClientError> {
+		// Download the file from the server
+		let mut res = self.send(self.request(Method::GET, &route_path("/file", server_path)?, None)).await?;
 
+		// Get the directory that the file will be saved to and create it if it doesn't exist
+		if let Some(parent_path) = client_path.parent() {
+			fs::create_dir_all(parent_path).map_err(ClientError::Io)?;
+		}
+
+		// Write the response to a file next to the real one as it arrives, no faster than the download limits allow
+		// It only takes the real file's place once all of it is there, so a download that fails part way leaves the old file alone
+		let throttles = self.bandwidth.download_throttles(client_path);
+		let download_path = download_path(client_path);
+		let downloaded = async {
+			let mut file = tokio::fs::File::create(&download_path).await.map_err(ClientError::Io)?;
+			while let Some(chunk) = res.chunk().await.map_err(ClientError::Connection)? {
+				for throttle in throttles.iter() {
+					throttle.consume(chunk.len()).await;
+				}
+				file.write_all(&chunk).await.map_err(ClientError::Io)?;
+			}
+			file.sync_all().await.map_err(ClientError::Io)?;
+			tokio::fs::rename(&download_path, client_path).await.map_err(ClientError::Io)
+		}.await;
+		if downloaded.is_err() {
+			let _ = tokio::fs::remove_file(&download_path).await;
+		}
+		downloaded
 	}
-	
+
 	// Upload a file located at client_path from the client and save it to server_path on the server
-	async fn upload(&self, client_path: &Path, server_path: &Path) -> () {
-		// Try to parse the given server path as a &str, panic if unable
-		let server_path = server_path.to_str().expect("Server path could not be interpreted as &str");
-
-		// Create the HTTP client and the object for the file to be uploaded
-		let client = reqwest::Client::new();
-		let file = tokio::fs::File::open(client_path).await.unwrap();
-		let stream = FramedRead::new(file, BytesCodec::new());
+	async fn upload(&self, client_path: &Path, server_path: &Path) -> Result<(), ClientError> {
+		self.retrying(|| self.upload_once(client_path, server_path)).await
+	}
+
+	// Upload a file once, without trying again if it fails
+	async fn upload_once(&self, client_path: &Path, server_path: &Path) -> Result<(), ClientError> {
+		// Get the digest of the file, which is what gets signed for an upload
+		let file_info = match FileInfo::from_indexed_file_path(client_path.to_path_buf(), &self.index) {
+			Ok(file_info) if file_info.exists() => file_info,
+			_ => {
+				return Err(ClientError::NotFile(client_path.to_path_buf()));
+			}
+		};
+
+		// Create the object for the file to be uploaded, read no faster than the upload limits allow
+		let file = tokio::fs::File::open(client_path).await.map_err(ClientError::Io)?;
+		let throttles = self.bandwidth.upload_throttles(client_path);
+		let stream = FramedRead::new(file, BytesCodec::new()).then(move |chunk| {
+			let throttles = throttles.clone();
+			async move {
+				if let Ok(bytes) = &chunk {
+					for throttle in throttles.iter() {
+						throttle.consume(bytes.len()).await;
+					}
+				}
+				chunk
+			}
+		});
 		let stream_body = Body::wrap_stream(stream);
-		let upload_stream = multipart::Part::stream(stream_body);
+		// Without a content type the server would take the part for a text field and mangle anything that isn't UTF-8
+		let upload_stream = multipart::Part::stream(stream_body)
+			.mime_str("application/octet-stream")
+			.map_err(ClientError::Connection)?;
 		let form = multipart::Form::new().part("file", upload_stream);
 
 		// Upload the file using a put request
-		let res_result = client
-			.put(format!("{}/file/{}", self.get_server_url(), server_path))
-			.multipart(form)
-			.header("password", self.get_password())
-			.send().await;
-		
-		// If everything went ok, get the response, otherwise return
-		let res = match res_result {
-			Ok(res) => res,
+		let request = self.request(Method::PUT, &route_path("/file", server_path)?, Some(file_info.get_digest()))
+			.multipart(form);
+		self.send(request).await?;
+		Ok(())
+	}
+
+	// Print the files under a path on the server, or the file itself if the path is a file
+	async fn list(&self, server_path: &Path, report: &Report) -> Result<(), ClientError> {
+		let file_infos = match self.get_json::<Vec<FileInfo>>(&route_path("/info/dir", server_path)?).await {
+			Ok(file_infos) => file_infos,
+			Err(ClientError::Status(StatusCode::UNPROCESSABLE_ENTITY)) => {
+				vec![self.get_json::<FileInfo>(&route_path("/info/file", server_path)?).await?]
+			},
 			Err(e) => {
-				println!("Could not upload file, status {:?}", e.status());
-				return;
+				return Err(e);
 			}
 		};
 
-		// If not authorized, report and return
-		if res.status() == StatusCode::UNAUTHORIZED {
-			println!("Could not upload file, status {:?}", res.status());
-			return;
+		for file_info in file_infos.iter().filter(|file_info| file_info.exists()) {
+			report.list(file_info);
 		}
+		Ok(())
+	}
+
+	// Delete a file on the server
+	async fn remove(&self, server_path: &Path) -> Result<(), ClientError> {
+		let path = route_path("/file", server_path)?;
+		self.retrying_once_applied(|| async {
+			self.send(self.request(Method::DELETE, &path, None)).await?;
+			Ok(())
+		}).await
+	}
+
+	// Move a file on the server
+	async fn move_file(&self, from_server_path: &Path, to_server_path: &Path) -> Result<(), ClientError> {
+		let to = utf8_percent_encode(server_path_str(to_server_path)?, URL_COMPONENT);
+		let path = format!("{}?to={}", route_path("/move", from_server_path)?, to);
+		self.retrying_once_applied(|| async {
+			self.send(self.request(Method::POST, &path, None)).await?;
+			Ok(())
+		}).await
+	}
+
+	// Get the client and server paths of every file under the given client paths, taking placeholders to be the files they stand in for
+	// The paths have to be in mapped directories that use placeholders, and files the filter refuses are left out
+	fn placeholder_file_paths(&self, client_paths: &[PathBuf]) -> Result<Vec<(PathBuf, PathBuf)>, ClientError> {
+		let mut file_paths = BTreeMap::new();
+		for client_path in client_paths {
+			let client_path = placeholder_target(client_path).unwrap_or_else(|| client_path.clone());
+			let client_dir_path = self.filter_root(&client_path);
+			let server_dir_path = match self.get_dir_mappings().get(&*client_dir_path.to_string_lossy()).and_then(mapping_server_str) {
+				Some(server_dir_path) if self.get_mappings().uses_placeholders(&client_dir_path) => PathBuf::from(server_dir_path),
+				_ => {
+					return Err(ClientError::Config(format!("{:?} is not in a mapped directory that uses placeholders", client_path)));
+				}
+			};
+			let filter = self.path_filter(&client_dir_path)?;
+
+			let mut client_file_paths = Vec::new();
+			walk_files(&client_path, &mut client_file_paths);
+			for client_file_path in client_file_paths {
+				let client_file_path = placeholder_target(&client_file_path).unwrap_or(client_file_path);
+				if !filter.includes(&client_file_path, false) {
+					continue;
+				}
+				if let Ok(relative_path) = client_file_path.strip_prefix(&client_dir_path) {
+					let server_file_path = server_dir_path.join(relative_path);
+					file_paths.insert(client_file_path, server_file_path);
+				}
+			}
+			filter.check().map_err(ClientError::Config)?;
+		}
+		Ok(file_paths.into_iter().collect())
+	}
+
+	// Swap the placeholders under the given client paths for the files they stand in for
+	pub async fn hydrate(&self, client_paths: &[PathBuf], report: &mut Report) -> Result<(), ClientError> {
+		let file_paths = self.placeholder_file_paths(client_paths)?;
+		let mut hydrated = stream::iter(file_paths)
+			.map(|(client_path, server_path)| async move {
+				let result = self.hydrate_file(&client_path, &server_path).await;
+				(client_path, result)
+			})
+			.buffer_unordered(self.get_client_config().get_transfers().get_concurrent());
+		while let Some((client_path, result)) = hydrated.next().await {
+			report.record(&client_path, result);
+		}
+		self.state.save();
+		Ok(())
+	}
+
+	// Download the file a placeholder stands in for and remove the placeholder, leaving files that are already there alone
+	async fn hydrate_file(&self, client_path: &Path, server_path: &Path) -> Result<PlanEntry, ClientError> {
+		let entry = |action: SyncAction, size: u64| PlanEntry { action, client_path: client_path.to_path_buf(), server_path: server_path.to_path_buf(), size, deferred: false, refused: false, reason: None };
+		if client_path.exists() || !placeholder_path(client_path).exists() {
+			return Ok(entry(SyncAction::Skip, 0));
+		}
+
+		let server_file_info: FileInfo = self.get_json(&route_path("/info/file", server_path)?).await?;
+		if !server_file_info.exists() {
+			return Err(ClientError::Status(StatusCode::NOT_FOUND));
+		}
+		self.download(server_path, client_path).await?;
+		fs::remove_file(placeholder_path(client_path)).map_err(ClientError::Io)?;
+		self.state.set(client_path, server_path, server_file_info.get_digest());
+		Ok(entry(SyncAction::Hydrate, server_file_info.get_size()))
+	}
+
+	// Swap the files under the given client paths for placeholders, as long as the server has the same copy
+	pub async fn dehydrate(&self, client_paths: &[PathBuf], report: &mut Report) -> Result<(), ClientError> {
+		let file_paths = self.placeholder_file_paths(client_paths)?;
+		let mut dehydrated = stream::iter(file_paths)
+			.map(|(client_path, server_path)| async move {
+				let result = self.dehydrate_file(&client_path, &server_path).await;
+				(client_path, result)
+			})
+			.buffer_unordered(self.get_client_config().get_transfers().get_concurrent());
+		while let Some((client_path, result)) = dehydrated.next().await {
+			report.record(&client_path, result);
+		}
+		self.state.save();
+		Ok(())
+	}
+
+	// Replace a file with a placeholder, refusing if the server's copy is different since the file's contents would be lost
+	async fn dehydrate_file(&self, client_path: &Path, server_path: &Path) -> Result<PlanEntry, ClientError> {
+		let entry = |action: SyncAction, size: u64| PlanEntry { action, client_path: client_path.to_path_buf(), server_path: server_path.to_path_buf(), size, deferred: false, refused: false, reason: None };
+		if !client_path.is_file() {
+			return Ok(entry(SyncAction::Skip, 0));
+		}
+
+		let client_file_info = FileInfo::from_indexed_file_path(client_path.to_path_buf(), &self.index)
+			.map_err(|_| ClientError::NotFile(client_path.to_path_buf()))?;
+		let server_file_info: FileInfo = self.get_json(&route_path("/info/file", server_path)?).await?;
+		if !server_file_info.exists() || server_file_info.get_digest() != client_file_info.get_digest() {
+			return Err(ClientError::NotSynced(client_path.to_path_buf()));
+		}
+		write_placeholder(client_path, &server_file_info).map_err(ClientError::Io)?;
+		fs::remove_file(client_path).map_err(ClientError::Io)?;
+		self.index.forget(client_path);
+		self.state.set(client_path, server_path, server_file_info.get_digest());
+		Ok(entry(SyncAction::Dehydrate, client_file_info.get_size()))
+	}
+
+	// Check that every mapping makes sense and that the server accepts the credentials
+	async fn check_config(&self, report: &mut Report) -> Result<(), ClientError> {
+		let config_problem = |message: String| ClientError::Config(message);
+
+		// The mappings have to be strings, or tables with one under server for directories, that point the right kinds of things at absolute server paths
+		for (mappings, is_dir) in [(self.get_file_mappings(), false), (self.get_dir_mappings(), true)] {
+			for (client_path, server_path) in mappings.iter() {
+				let client_path = Path::new(client_path);
+				match if is_dir { mapping_server_str(server_path) } else { server_path.as_str() } {
+					None => report.fail(client_path, config_problem(format!("mapping value {} is not a string", server_path))),
+					Some(server_path) if !server_path.starts_with('/') => {
+						report.fail(client_path, config_problem(format!("server path {} is not absolute", server_path)));
+					},
+					Some(_) if client_path.exists() && client_path.is_dir() != is_dir => {
+						let kind = if is_dir { "directory" } else { "file" };
+						report.fail(client_path, config_problem(format!("client path is mapped as a {} but is not one", kind)));
+					},
+					Some(_) => {}
+				}
+			}
+		}
+
+		// Placeholders have to be for mapped directories, and the rules of each mapping and its .skywriterignore files have to make sense
+		for client_path in self.get_mappings().get_placeholders() {
+			if !self.get_dir_mappings().contains_key(&*client_path.to_string_lossy()) {
+				report.fail(client_path, config_problem("placeholders are not for a mapped directory".to_string()));
+			}
+		}
+		for client_path in self.get_dir_mappings().keys() {
+			let checked = self.path_filter(Path::new(client_path)).and_then(|filter| {
+				filter.read_all();
+				filter.check().map_err(ClientError::Config)
+			});
+			if let Err(e) = checked {
+				report.fail(Path::new(client_path), e);
+			}
+		}
+
+		// A 403 for the root is fine, it only means the user can't read everything
+		match self.send(self.request(Method::GET, "/info/dir/", None)).await {
+			Ok(_) | Err(ClientError::Unauthorized(StatusCode::FORBIDDEN)) => {},
+			Err(e) => report.fail(Path::new(self.get_server_url()), e)
+		}
+
+		if report.failures.is_empty() && !report.json {
+			println!("Config OK");
+		}
+		Ok(())
 	}
 }
 
+// Build the HTTP client, trusting any extra certificate authorities and presenting the client certificate if there is one
+fn build_http_client(client_config: &ClientConfig) -> Result<reqwest::Client, ClientError> {
+	let mut builder = reqwest::Client::builder();
+	let config_problem = |message: &str, e: &dyn fmt::Display| ClientError::Config(format!("{}: {}", message, e));
+
+	if let Some(ca_certs_path) = client_config.get_ca_certs() {
+		let ca_certs = fs::read(ca_certs_path).map_err(|e| config_problem("Could not read CA certificates", &e))?;
+		let ca_cert = reqwest::Certificate::from_pem(&ca_certs).map_err(|e| config_problem("Could not parse CA certificates", &e))?;
+		builder = builder.add_root_certificate(ca_cert);
+	}
+
+	if let (Some(client_cert_path), Some(client_key_path)) = (client_config.get_client_cert(), client_config.get_client_key()) {
+		// The identity wants the certificate and key in the same PEM
+		let mut identity_pem = fs::read(client_cert_path).map_err(|e| config_problem("Could not read client certificate", &e))?;
+		identity_pem.push(b'\n');
+		identity_pem.extend(fs::read(client_key_path).map_err(|e| config_problem("Could not read client key", &e))?);
+		let identity = reqwest::Identity::from_pem(&identity_pem).map_err(|e| config_problem("Could not parse client certificate or key", &e))?;
+		builder = builder.identity(identity);
+	}
+
+	builder.build().map_err(|e| config_problem("Could not build HTTP client", &e))
+}
+
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-	let client = Client::new();
-	println!("1");
-	client.sync_files(client.get_file_mappings()).await;
-	println!("2");
-	client.sync_dirs(client.get_dir_mappings()).await;
-	println!("1");
-	println!("1");
-	println!("3");
-	Ok(())
+async fn main() -> ExitCode {
+	let args = Args::parse();
+	let command = args.command.unwrap_or(Command::Sync(SyncArgs { mappings: Vec::new(), dry_run: false }));
+	let dry_run = match &command {
+		Command::Sync(sync_args) | Command::Push(sync_args) | Command::Pull(sync_args) => sync_args.dry_run,
+		Command::Status { .. } => true,
+		_ => false
+	};
+	let mut report = Report::new(args.json, dry_run);
+
+	let client = match Client::new(&args.config, args.rehash) {
+		Ok(client) => client,
+		Err(e) => {
+			report.fail(&args.config, e);
+			report.finish();
+			return ExitCode::from(report.exit_code());
+		}
+	};
+
+	// Failures of the command as a whole are put down to the path it was working on, or the config for syncs
+	let sync_options = |direction: Direction| SyncOptions { direction, dry_run };
+	let (path, result) = match command {
+		Command::Sync(sync_args) => (args.config, client.sync(&sync_args.mappings, sync_options(Direction::Both), &mut report).await),
+		Command::Push(sync_args) => (args.config, client.sync(&sync_args.mappings, sync_options(Direction::Push), &mut report).await),
+		Command::Pull(sync_args) => (args.config, client.sync(&sync_args.mappings, sync_options(Direction::Pull), &mut report).await),
+		Command::Status { mappings } => (args.config, client.sync(&mappings, sync_options(Direction::Both), &mut report).await),
+		Command::Watch { mappings } => (args.config, client.watch(&mappings, args.json).await),
+		Command::Ls { remote_path } => {
+			let remote_path = PathBuf::from(remote_path);
+			let result = client.list(&remote_path, &report).await;
+			(remote_path, result)
+		},
+		Command::Get { remote_path, local_path } => {
+			let remote_path = PathBuf::from(remote_path);
+			let result = match local_path.or_else(|| remote_path.file_name().map(PathBuf::from)) {
+				Some(local_path) => client.download(&remote_path, &local_path).await,
+				None => Err(ClientError::NotFile(remote_path.clone()))
+			};
+			(remote_path, result)
+		},
+		Command::Put { local_path, remote_path } => {
+			let result = client.upload(&local_path, Path::new(&remote_path)).await;
+			(local_path, result)
+		},
+		Command::Rm { remote_path } => {
+			let remote_path = PathBuf::from(remote_path);
+			let result = client.remove(&remote_path).await;
+			(remote_path, result)
+		},
+		Command::Mv { from, to } => {
+			let from = PathBuf::from(from);
+			let result = client.move_file(&from, Path::new(&to)).await;
+			(from, result)
+		},
+		Command::Hydrate { paths } => (args.config, client.hydrate(&paths, &mut report).await),
+		Command::Dehydrate { paths } => (args.config, client.dehydrate(&paths, &mut report).await),
+		Command::Config { command: ConfigCommand::Check } => (args.config, client.check_config(&mut report).await)
+	};
+
+	if let Err(e) = result {
+		report.fail(&path, e);
+	}
+	report.finish();
+	ExitCode::from(report.exit_code())
 }
@@ -0,0 +1,313 @@
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::tokio::sync::broadcast;
+use serde::{Serialize, Deserialize};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::now_seconds;
+
+#[cfg(test)]
+mod tests {
+	use super::{ChangeEvent, ChangeNotifier, ChangeOperation};
+	use crate::test_dir::TestDir;
+	use std::fs;
+	use std::path::Path;
+
+	#[test]
+	fn changes_since_cursor() {
+		let data_root = TestDir::new("events");
+
+		let changes = ChangeNotifier::load(&data_root);
+		let start = changes.changes_since(None, 10, |_| true).cursor;
+		for path in ["docs/a.txt", "photos/b.jpg", "docs/c.txt"] {
+			changes.publish(ChangeEvent::new(ChangeOperation::Put, Path::new(path), None, None, None));
+		}
+
+		// Without a cursor there is nothing to go on
+		let feed = changes.changes_since(None, 10, |_| true);
+		assert!(feed.reset);
+		assert_eq!(feed.cursor, start + 3);
+
+		let feed = changes.changes_since(Some(start + 1), 10, |change| change.get_path().starts_with("docs"));
+		assert!(!feed.reset && feed.complete);
+		assert_eq!(feed.changes.iter().map(|change| change.cursor).collect::<Vec<u64>>(), vec![start + 3]);
+		assert_eq!(feed.cursor, start + 3);
+
+		// Pages stop at the last change they include
+		let feed = changes.changes_since(Some(start), 1, |_| true);
+		assert!(!feed.complete);
+		assert_eq!(feed.cursor, start + 1);
+		let feed = changes.changes_since(Some(start), 0, |_| true);
+		assert_eq!((feed.changes.len(), feed.cursor), (1, start + 1));
+
+		// The journal carries on from where it was after a restart, and cursors from the future are refused
+		let changes = ChangeNotifier::load(&data_root);
+		changes.publish(ChangeEvent::new(ChangeOperation::Delete, Path::new("docs/a.txt"), None, None, None));
+		assert_eq!(changes.changes_since(Some(start + 3), 10, |_| true).changes[0].cursor, start + 4);
+		assert!(changes.changes_since(Some(start + 5), 10, |_| true).reset);
+		assert!(changes.changes_since(Some(u64::MAX), 10, |_| true).reset);
+
+		// A lost journal starts again after every cursor the old one gave out
+		fs::remove_dir_all(&data_root).unwrap();
+		let changes = ChangeNotifier::load(&data_root);
+		assert!(changes.changes_since(Some(start + 4), 10, |_| true).reset);
+	}
+}
+
+// The header clients identify themselves with, so they can tell their own changes apart from everyone else's
+pub const CLIENT_ID_HEADER: &str = "client-id";
+
+// How many changes are kept for subscribers that fall behind before they are told to sync everything instead
+pub const CHANGE_BUFFER: usize = 1024;
+
+// The name of the change journal in the data root
+pub const CHANGE_JOURNAL_FILE: &str = "changes.log";
+
+// How many changes the journal keeps, clients that haven't synced since before the oldest have to sync everything
+pub const MAX_JOURNAL_CHANGES: usize = 100_000;
+
+// How far apart the first cursors of journals started a second apart are
+pub const CURSORS_PER_SECOND: u64 = 1000;
+
+// How many changes a page of the feed has if the request doesn't say
+pub const DEFAULT_FEED_LIMIT: usize = 1000;
+
+// What happened to a file
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChangeOperation {
+	Put,
+	Delete,
+	Move
+}
+
+// A structure for representing a change to a file on the server
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChangeEvent {
+	cursor: u64, // Where the change is in the journal, only ever counting up
+	timestamp: u64,
+	operation: ChangeOperation,
+	path: PathBuf, // The virtual path that changed, the destination for moves
+	from: Option<PathBuf>, // The virtual path a file was moved from
+	digest: Option<String>, // The digest of the file now at the path, None if it was deleted
+	client: Option<String> // The ID of the client that made the change, if it sent one
+}
+
+impl ChangeEvent {
+
+	// Constructor
+
+	pub fn new(operation: ChangeOperation, path: &Path, from: Option<&Path>, digest: Option<String>, client: Option<String>) -> Self {
+		Self {
+			cursor: 0,
+			timestamp: now_seconds(),
+			operation,
+			path: path.to_path_buf(),
+			from: from.map(Path::to_path_buf),
+			digest,
+			client
+		}
+	}
+
+	// Getters
+
+	pub fn get_cursor(&self) -> u64 {
+		self.cursor
+	}
+
+	pub fn get_timestamp(&self) -> u64 {
+		self.timestamp
+	}
+
+	pub fn get_operation(&self) -> ChangeOperation {
+		self.operation
+	}
+
+	pub fn get_path(&self) -> &Path {
+		&self.path
+	}
+
+	pub fn get_from(&self) -> Option<&Path> {
+		self.from.as_deref()
+	}
+
+	pub fn get_digest(&self) -> Option<&str> {
+		self.digest.as_deref()
+	}
+
+	pub fn get_client(&self) -> Option<&str> {
+		self.client.as_deref()
+	}
+
+	// Every virtual path the change touched
+	pub fn get_paths(&self) -> impl Iterator<Item = &Path> {
+		std::iter::once(self.path.as_path()).chain(self.from.as_deref())
+	}
+}
+
+// A page of the change journal
+#[derive(Serialize, Deserialize)]
+pub struct ChangeFeed {
+	cursor: u64, // What to ask for changes since next time
+	reset: bool, // The changes asked for are no longer known, so everything has to be checked
+	complete: bool, // There are no more changes after this page
+	changes: Vec<ChangeEvent>
+}
+
+impl ChangeFeed {
+
+	// Getters
+
+	pub fn get_cursor(&self) -> u64 {
+		self.cursor
+	}
+
+	pub fn get_reset(&self) -> bool {
+		self.reset
+	}
+
+	pub fn get_complete(&self) -> bool {
+		self.complete
+	}
+
+	pub fn get_changes(&self) -> &[ChangeEvent] {
+		&self.changes
+	}
+}
+
+// The newest changes, kept in memory and appended to a file in the data root as JSON lines
+struct Journal {
+	path: PathBuf,
+	changes: VecDeque<ChangeEvent>,
+	last_cursor: u64,
+	file_changes: usize // How many changes are in the file, which is rewritten once it holds too many old ones
+}
+
+impl Journal {
+	// Write out only the changes that are still kept
+	fn compact(&mut self) {
+		let lines: Vec<String> = self.changes.iter()
+			.map(|change| serde_json::to_string(change).expect("Could not serialize change"))
+			.collect();
+		match fs::write(&self.path, lines.iter().map(|line| format!("{}\n", line)).collect::<String>()) {
+			Ok(()) => self.file_changes = lines.len(),
+			Err(e) => println!("Could not compact change journal {:?}: {}", self.path, e)
+		}
+	}
+}
+
+// A structure for numbering changes, keeping them in the journal and handing them out to everyone following them
+pub struct ChangeNotifier {
+	sender: broadcast::Sender<ChangeEvent>,
+	journal: Mutex<Journal>
+}
+
+impl ChangeNotifier {
+
+	// Constructor
+
+	pub fn load(data_root: &Path) -> Self {
+		let path = data_root.join(CHANGE_JOURNAL_FILE);
+
+		// Start with an empty journal if the file doesn't exist yet
+		let mut changes: VecDeque<ChangeEvent> = match File::open(&path) {
+			Ok(file) => BufReader::new(file).lines().map_while(Result::ok)
+				.filter_map(|line| serde_json::from_str(&line).ok())
+				.collect(),
+			Err(_) => VecDeque::new()
+		};
+		let file_changes = changes.len();
+
+		// A new journal starts numbering after any cursor an old one could have handed out, so clients that saw the old one are told to reset
+		let last_cursor = changes.back().map_or(now_seconds() * CURSORS_PER_SECOND, |change: &ChangeEvent| change.cursor);
+		while changes.len() > MAX_JOURNAL_CHANGES {
+			changes.pop_front();
+		}
+
+		Self {
+			sender: broadcast::channel(CHANGE_BUFFER).0,
+			journal: Mutex::new(Journal {
+				path,
+				changes,
+				last_cursor,
+				file_changes
+			})
+		}
+	}
+
+	// Number a change, add it to the journal and tell everyone following changes about it
+	pub fn publish(&self, mut event: ChangeEvent) {
+		let mut journal = self.journal.lock().unwrap();
+		journal.last_cursor += 1;
+		event.cursor = journal.last_cursor;
+
+		let line = serde_json::to_string(&event).expect("Could not serialize change");
+		let appended = OpenOptions::new().create(true).append(true).open(&journal.path)
+			.and_then(|mut file| writeln!(file, "{}", line));
+		if let Err(e) = appended {
+			println!("Could not append to change journal {:?}: {}", journal.path, e);
+		}
+		journal.file_changes += 1;
+
+		journal.changes.push_back(event.clone());
+		if journal.changes.len() > MAX_JOURNAL_CHANGES {
+			journal.changes.pop_front();
+		}
+		if journal.file_changes > 2 * MAX_JOURNAL_CHANGES {
+			journal.compact();
+		}
+
+		// Nobody following isn't a problem
+		let _ = self.sender.send(event);
+	}
+
+	// Start following changes
+	pub fn subscribe(&self) -> broadcast::Receiver<ChangeEvent> {
+		self.sender.subscribe()
+	}
+
+	// Get up to a limit of the wanted changes after a cursor, or say that everything has to be checked if it can't
+	pub fn changes_since(&self, since: Option<u64>, limit: usize, wanted: impl Fn(&ChangeEvent) -> bool) -> ChangeFeed {
+		let journal = self.journal.lock().unwrap();
+
+		// Changes from before the oldest one kept are gone, and a cursor from the future means the journal was lost
+		let oldest_cursor = journal.changes.front().map_or(journal.last_cursor + 1, |change| change.cursor);
+		let since = match since {
+			Some(since) if since >= oldest_cursor.saturating_sub(1) && since <= journal.last_cursor => since,
+			_ => {
+				return ChangeFeed { cursor: journal.last_cursor, reset: true, complete: true, changes: Vec::new() };
+			}
+		};
+
+		// A page always has room for at least one change, otherwise the cursor would never move on
+		let limit = limit.max(1);
+		let mut feed = ChangeFeed { cursor: journal.last_cursor, reset: false, complete: true, changes: Vec::new() };
+		for change in journal.changes.iter().filter(|change| change.cursor > since) {
+			if feed.changes.len() == limit {
+				feed.complete = false;
+				break;
+			}
+			if wanted(change) {
+				feed.changes.push(change.clone());
+			}
+			feed.cursor = change.cursor;
+		}
+		feed
+	}
+}
+
+// A request guard for the ID a client sent, if it sent one
+pub struct ClientId(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientId {
+	type Error = Infallible;
+
+	async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+		Outcome::Success(ClientId(req.headers().get_one(CLIENT_ID_HEADER).map(str::to_string)))
+	}
+}
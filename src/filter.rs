@@ -0,0 +1,370 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::sha256_digest_str;
+
+#[cfg(test)]
+mod tests {
+	use super::{IgnoredPaths, MappingFilter, PathFilter};
+	use crate::test_dir::TestDir;
+	use std::fs;
+	use std::path::Path;
+
+	#[test]
+	fn gitignore_rules() {
+		let root = TestDir::new("filter");
+		fs::create_dir_all(root.join("docs/drafts")).unwrap();
+		fs::create_dir_all(root.join("target")).unwrap();
+		fs::write(root.join(".skywriterignore"), "*.log\n# Comments and blank lines are skipped\n\n/build/\n").unwrap();
+		fs::write(root.join("docs/.skywriterignore"), "!keep.log\ndrafts/\n").unwrap();
+		// Rules under an excluded directory are never read
+		fs::write(root.join("target/.skywriterignore"), "!*.o\n").unwrap();
+
+		let config: MappingFilter = toml::from_str("exclude=[\"target/\", \".DS_Store\"]").unwrap();
+		let filter = PathFilter::load(&root, Some(&config)).unwrap();
+		assert!(filter.includes(&root.join("notes.txt"), false));
+		assert!(!filter.includes(&root.join(".DS_Store"), false));
+		assert!(!filter.includes(&root.join("docs/.DS_Store"), false));
+		assert!(!filter.includes(&root.join("target"), true));
+		assert!(!filter.includes(&root.join("target/main.o"), false));
+		assert!(!filter.includes(&root.join("debug.log"), false));
+		assert!(!filter.includes(&root.join("build/out.bin"), false));
+		assert!(filter.includes(&root.join("docs/build/out.bin"), false));
+
+		// Deeper rules win, and nothing under an excluded directory comes back
+		assert!(filter.includes(&root.join("docs/keep.log"), false));
+		assert!(!filter.includes(&root.join("docs/other.log"), false));
+		assert!(!filter.includes(&root.join("docs/drafts/keep.log"), false));
+
+		// The rules are read on the way down, so one that doesn't make sense turns up once something under it is looked at
+		fs::create_dir_all(root.join("broken")).unwrap();
+		fs::write(root.join("broken/.skywriterignore"), "{a,\n").unwrap();
+		let filter = PathFilter::load(&root, Some(&config)).unwrap();
+		let digest = filter.digest();
+		assert!(filter.includes(&root.join("notes.txt"), false) && filter.check().is_ok());
+		assert!(filter.includes(&root.join("broken/notes.txt"), false) && filter.check().is_err());
+		assert_ne!(filter.digest(), digest);
+		let filter = PathFilter::load(&root, Some(&config)).unwrap();
+		filter.read_all();
+		assert!(filter.check().is_err());
+		fs::remove_dir_all(root.join("broken")).unwrap();
+
+		// With include patterns, only files matching one of them are synced
+		let config: MappingFilter = toml::from_str("include=[\"*.txt\", \"docs/\"]").unwrap();
+		let filter = PathFilter::load(&root, Some(&config)).unwrap();
+		assert!(filter.includes(&root.join("notes.txt"), false));
+		assert!(filter.includes(&root.join("docs/plan.md"), false));
+		assert!(!filter.includes(&root.join("photo.jpg"), false));
+		assert!(filter.includes(&root.join("photos"), true));
+	}
+
+	#[test]
+	fn selected_subfolders() {
+		let root = TestDir::new("filter_select");
+		fs::create_dir_all(root.join("docs/published")).unwrap();
+
+		// Files in the mapped directory itself and under the selected subfolders are synced, and nothing else
+		let config: MappingFilter = toml::from_str("select=[\"docs/published\"]").unwrap();
+		let filter = PathFilter::load(&root, Some(&config)).unwrap();
+		assert!(filter.includes(&root.join("notes.txt"), false));
+		assert!(filter.includes(&root.join("docs"), true));
+		assert!(!filter.includes(&root.join("docs/plan.md"), false));
+		assert!(filter.includes(&root.join("docs/published/plan.md"), false));
+		assert!(!filter.includes(&root.join("target"), true));
+		assert!(PathFilter::load(&root, Some(&toml::from_str("select=[\"../elsewhere\"]").unwrap())).is_err());
+	}
+
+	#[test]
+	fn size_age_and_extension_rules() {
+		// These are for the files being copied, so they only look at the path and what is known about the file
+		let config: MappingFilter = toml::from_str("max_file_bytes=1000\nmin_age_seconds=60\ndeny_extensions=[\".MP4\", \"tmp\"]").unwrap();
+		assert!(config.refusal(Path::new("notes.txt"), 1000).is_none());
+		assert!(config.refusal(Path::new("notes.txt"), 1001).is_some());
+		assert!(config.refusal(Path::new("video.mp4"), 10).is_some());
+		assert!(config.is_too_new(1_000_000 - 59, 1_000_000) && !config.is_too_new(1_000_000 - 60, 1_000_000));
+		let config: MappingFilter = toml::from_str("allow_extensions=[\"md\", \"txt\"]").unwrap();
+		assert!(config.refusal(Path::new("docs/plan.md"), 10).is_none());
+		assert!(config.refusal(Path::new("photo.jpg"), 10).is_some());
+		assert!(config.refusal(Path::new("Makefile"), 10).is_some());
+	}
+
+	#[test]
+	fn ignored_virtual_paths() {
+		let ignored = IgnoredPaths::try_from(vec!["*.bak".to_string(), "/shares/team/private/".to_string(), "/secret.txt".to_string()]).unwrap();
+		assert!(ignored.is_ignored(Path::new("notes.bak"), false));
+		assert!(ignored.is_ignored(Path::new("shares/team/notes.bak"), false));
+		assert!(ignored.is_ignored(Path::new("secret.txt"), false));
+		assert!(!ignored.is_ignored(Path::new("docs/secret.txt"), false));
+
+		// Everything under an ignored directory is ignored too
+		assert!(ignored.is_ignored(Path::new("shares/team/private"), true));
+		assert!(ignored.is_ignored(Path::new("shares/team/private/plans/q3.txt"), false));
+		assert!(!ignored.is_ignored(Path::new("shares/team/notes.txt"), false));
+		assert!(IgnoredPaths::try_from(vec!["{a,".to_string()]).is_err());
+	}
+}
+
+// The name of the files that hold the rules for the directory they are in, with the same syntax as a .gitignore
+pub const IGNORE_FILE: &str = ".skywriterignore";
+
+// A structure for representing the rules a mapped directory is filtered with in the client config
+#[derive(Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct MappingFilter {
+	include: Vec<String>, // If there are any, only files matching one of them are synced
+	exclude: Vec<String>, // Lines like those of a .gitignore, which the directory's .skywriterignore files can override
+	select: Vec<PathBuf>, // If there are any, the only subfolders synced, relative to the mapped directory
+	max_file_bytes: Option<u64>, // Larger files are never copied
+	min_age_seconds: u64, // Files changed more recently are left for a later sync
+	allow_extensions: Vec<String>, // If there are any, only files with one of them are copied
+	deny_extensions: Vec<String> // Files with one of them are never copied
+}
+
+impl MappingFilter {
+
+	// Getters
+
+	pub fn get_include(&self) -> &[String] {
+		&self.include
+	}
+
+	pub fn get_exclude(&self) -> &[String] {
+		&self.exclude
+	}
+
+	pub fn get_select(&self) -> &[PathBuf] {
+		&self.select
+	}
+
+	pub fn get_max_file_bytes(&self) -> Option<u64> {
+		self.max_file_bytes
+	}
+
+	pub fn get_min_age_seconds(&self) -> u64 {
+		self.min_age_seconds
+	}
+
+	// Why a file of a given size can't be copied, if it can't
+	pub fn refusal(&self, path: &Path, size: u64) -> Option<String> {
+		if let Some(max_file_bytes) = self.max_file_bytes.filter(|max_file_bytes| size > *max_file_bytes) {
+			return Some(format!("larger than the limit of {} bytes", max_file_bytes));
+		}
+
+		// Extensions can be given with or without the dot, in any case
+		let extension = path.extension().map(|extension| extension.to_string_lossy().to_lowercase()).unwrap_or_default();
+		let matches = |extensions: &[String]| extensions.iter().any(|listed| listed.trim_start_matches('.').to_lowercase() == extension);
+		if matches(&self.deny_extensions) {
+			return Some(format!("files ending in .{} are denied", extension));
+		}
+		if !self.allow_extensions.is_empty() && !matches(&self.allow_extensions) {
+			return Some("its extension is not allowed".to_string());
+		}
+		None
+	}
+
+	// If a file last modified at a time, in seconds since the Unix epoch, changed too recently to be copied yet
+	pub fn is_too_new(&self, seconds: u64, now_seconds: u64) -> bool {
+		now_seconds.saturating_sub(seconds) < self.min_age_seconds
+	}
+}
+
+// A structure for deciding which files under a mapped directory are synced
+// Rules from deeper .skywriterignore files win over shallower ones, which win over the config, and nothing under an excluded directory can be brought back
+// The .skywriterignore files are read as the sync walks down to them, so the rules and the digest are only complete once the walk is done
+pub struct PathFilter {
+	root: PathBuf, // The client path of the mapped directory
+	exclude: Gitignore, // The exclude patterns from the config, which every .skywriterignore wins over
+	include: Option<Gitignore>, // The patterns files have to match, if there are any
+	selected: Option<Vec<PathBuf>>, // The client paths of the subfolders synced, if only some are
+	config_source: String, // The config the filter was made from, for the digest
+	ignore_files: Mutex<IgnoreFiles>
+}
+
+// The .skywriterignore files read so far
+#[derive(Default)]
+struct IgnoreFiles {
+	layers: HashMap<PathBuf, Option<Gitignore>>, // The rules of each directory looked in, if it has any
+	sources: Vec<String>, // What each file held
+	problem: Option<String> // The first file whose rules couldn't be read
+}
+
+impl IgnoreFiles {
+	// Read the rules of a directory, unless they have been already
+	fn read(&mut self, dir_path: &Path) {
+		if self.layers.contains_key(dir_path) {
+			return;
+		}
+		let ignore_path = dir_path.join(IGNORE_FILE);
+		let layer = match fs::read_to_string(&ignore_path) {
+			Ok(contents) => {
+				let lines: Vec<String> = contents.lines().map(str::to_string).collect();
+				self.sources.push(format!("{:?} {:?}", ignore_path, contents));
+				match build_rules(dir_path, &lines, Some(&ignore_path)) {
+					Ok(layer) => Some(layer),
+					Err(e) => {
+						self.problem.get_or_insert(e);
+						None
+					}
+				}
+			},
+			Err(_) => None
+		};
+		self.layers.insert(dir_path.to_path_buf(), layer);
+	}
+}
+
+// Utility function to turn .gitignore lines into rules for the directory they are relative to
+fn build_rules(dir_path: &Path, lines: &[String], source: Option<&Path>) -> Result<Gitignore, String> {
+	let mut builder = GitignoreBuilder::new(dir_path);
+	for line in lines {
+		builder.add_line(source.map(Path::to_path_buf), line)
+			.map_err(|e| format!("bad pattern {:?}{}: {}", line, source.map(|source| format!(" in {:?}", source)).unwrap_or_default(), e))?;
+	}
+	builder.build().map_err(|e| e.to_string())
+}
+
+impl PathFilter {
+
+	// Constructor
+
+	// Make the filter for a mapped directory from the rules in the config, leaving the .skywriterignore files to be read as they are come to
+	pub fn load(root: &Path, config: Option<&MappingFilter>) -> Result<Self, String> {
+		let (include, exclude) = config.map_or((&[][..], &[][..]), |config| (config.get_include(), config.get_exclude()));
+		let select = config.map_or(&[][..], |config| config.get_select());
+		if let Some(subfolder) = select.iter().find(|subfolder| subfolder.as_os_str().is_empty() || !subfolder.components().all(|component| matches!(component, Component::Normal(_)))) {
+			return Err(format!("selected {:?} is not a subfolder of the mapped directory", subfolder));
+		}
+
+		Ok(Self {
+			root: root.to_path_buf(),
+			exclude: build_rules(root, exclude, None)?,
+			include: if include.is_empty() { None } else { Some(build_rules(root, include, None)?) },
+			selected: if select.is_empty() { None } else { Some(select.iter().map(|subfolder| root.join(subfolder)).collect()) },
+			config_source: format!("{:?}", config),
+			ignore_files: Mutex::new(IgnoreFiles::default())
+		})
+	}
+
+	// Read every .skywriterignore under the mapped directory now, for when nothing is going to walk it
+	pub fn read_all(&self) {
+		let mut dir_paths = vec![self.root.clone()];
+		while let Some(dir_path) = dir_paths.pop() {
+			self.ignore_files.lock().unwrap().read(&dir_path);
+			if let Ok(iter) = fs::read_dir(&dir_path) {
+				for entry in iter.flatten() {
+					let child_path = entry.path();
+					if child_path.is_dir() && self.includes(&child_path, true) {
+						dir_paths.push(child_path);
+					}
+				}
+			}
+		}
+	}
+
+	// The first .skywriterignore read so far that has a pattern that doesn't make sense, if there is one
+	pub fn check(&self) -> Result<(), String> {
+		match &self.ignore_files.lock().unwrap().problem {
+			Some(problem) => Err(problem.clone()),
+			None => Ok(())
+		}
+	}
+
+	// Get the digest of every rule read so far, which changes whenever what is synced might have
+	pub fn digest(&self) -> String {
+		// The directories aren't read in any particular order
+		let mut sources = self.ignore_files.lock().unwrap().sources.clone();
+		sources.sort();
+		sha256_digest_str(&format!("{}{}", self.config_source, sources.concat()))
+	}
+
+	// If a client path under the mapped directory should be synced, or looked inside for a directory
+	pub fn includes(&self, client_path: &Path, is_dir: bool) -> bool {
+		let Ok(relative_path) = client_path.strip_prefix(&self.root) else {
+			return true;
+		};
+
+		// Only files right in the mapped directory or under a selected subfolder are synced, though the directories on the way down to one are looked inside
+		if let Some(selected) = &self.selected {
+			let in_root = !is_dir && relative_path.components().count() == 1;
+			let is_selected = |subfolder: &PathBuf| client_path.starts_with(subfolder) || (is_dir && subfolder.starts_with(client_path));
+			if !in_root && !selected.iter().any(is_selected) {
+				return false;
+			}
+		}
+
+		// Every directory on the way down has to be included as well as the path itself, and its rules are only read once it is
+		let mut ignore_files = self.ignore_files.lock().unwrap();
+		let mut dir_paths = Vec::new();
+		let mut path = self.root.clone();
+		let mut components = relative_path.components().peekable();
+		while let Some(component) = components.next() {
+			ignore_files.read(&path);
+			dir_paths.push(path.clone());
+			path.push(component);
+			let path_is_dir = components.peek().is_some() || is_dir;
+			let excluded = dir_paths.iter().rev()
+				.filter_map(|dir_path| ignore_files.layers.get(dir_path)?.as_ref())
+				.chain([&self.exclude])
+				.map(|layer| layer.matched(&path, path_is_dir))
+				.find(|matched| !matched.is_none())
+				.is_some_and(|matched| matched.is_ignore());
+			if excluded {
+				return false;
+			}
+		}
+
+		// Include patterns only pick out files, since any directory could have a matching file in it
+		match &self.include {
+			Some(include) if !is_dir => include.matched_path_or_any_parents(client_path, false).is_ignore(),
+			_ => true
+		}
+	}
+}
+
+// A structure for representing the paths the server never serves or stores, as .gitignore lines relative to the virtual root
+#[derive(Deserialize, Clone, Debug)]
+#[serde(try_from = "Vec<String>")]
+pub struct IgnoredPaths {
+	patterns: Vec<String>,
+	matcher: Gitignore
+}
+
+impl Default for IgnoredPaths {
+	fn default() -> Self {
+		Self {
+			patterns: Vec::new(),
+			matcher: Gitignore::empty()
+		}
+	}
+}
+
+impl IgnoredPaths {
+
+	// Getters
+
+	pub fn get_patterns(&self) -> &[String] {
+		&self.patterns
+	}
+
+	// If a virtual path, or any directory it is in, matches one of the patterns
+	pub fn is_ignored(&self, virtual_path: &Path, is_dir: bool) -> bool {
+		self.matcher.matched_path_or_any_parents(Path::new("/").join(virtual_path), is_dir).is_ignore()
+	}
+}
+
+impl TryFrom<Vec<String>> for IgnoredPaths {
+	type Error = String;
+
+	fn try_from(patterns: Vec<String>) -> Result<Self, Self::Error> {
+		let mut builder = GitignoreBuilder::new("/");
+		for pattern in &patterns {
+			builder.add_line(None, pattern).map_err(|e| format!("bad ignored path {:?}: {}", pattern, e))?;
+		}
+		let matcher = builder.build().map_err(|e| e.to_string())?;
+		Ok(Self { patterns, matcher })
+	}
+}
@@ -0,0 +1,98 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{ServerConfig, move_path, now_seconds};
+
+#[cfg(test)]
+#[allow(clippy::items_after_test_module)] // Tests go first in every module, even ones without derived items after them
+mod tests {
+	use super::{archive, history_path, parse_version, version_path};
+	use crate::ServerConfig;
+	use crate::test_dir::TestDir;
+	use std::fs;
+	use std::path::Path;
+
+	#[test]
+	fn versions_in_the_same_second() {
+		let root = TestDir::new("history");
+		let server_config: ServerConfig = toml::from_str(&format!(r#"
+			files_root = "{root}/files"
+			data_root = "{root}/data"
+			password = "testpass"
+			ignored_paths = []
+		"#, root = root.display())).expect("Error parsing test server config");
+		let virtual_path = Path::new("notes.txt");
+		let full_path = root.join("files").join(virtual_path);
+		fs::create_dir_all(full_path.parent().unwrap()).unwrap();
+
+		// Each save keeps the one before it, even when they come faster than once a second
+		for contents in ["first", "second", "third"] {
+			fs::write(&full_path, contents).unwrap();
+			archive(&server_config, virtual_path).unwrap();
+		}
+		let mut versions: Vec<String> = fs::read_dir(history_path(&server_config, virtual_path)).unwrap()
+			.map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+			.collect();
+		versions.sort_by_key(|version| parse_version(version));
+		assert_eq!(versions.len(), 3);
+		let contents: Vec<String> = versions.iter()
+			.map(|version| fs::read_to_string(version_path(&server_config, virtual_path, version).unwrap()).unwrap())
+			.collect();
+		assert_eq!(contents, ["first", "second", "third"]);
+
+		// Only names made by archive are versions
+		assert_eq!(parse_version("1700000000"), Some((1700000000, 0)));
+		assert_eq!(parse_version("1700000000-2"), Some((1700000000, 2)));
+		assert_eq!(parse_version("../1700000000"), None);
+		assert_eq!(parse_version("1700000000-"), None);
+		assert!(version_path(&server_config, virtual_path, "..").is_none());
+	}
+}
+
+// The directory in the data root that old versions of files are kept in
+pub const HISTORY_DIR: &str = "history";
+
+// Get the directory that holds the old versions of a virtual path
+pub fn history_path(server_config: &ServerConfig, virtual_path: &Path) -> PathBuf {
+	Path::new(server_config.get_data_root()).join(HISTORY_DIR).join(virtual_path)
+}
+
+// Get the path of a single old version of a virtual path, if the version is one archive could have made
+pub fn version_path(server_config: &ServerConfig, virtual_path: &Path, version: &str) -> Option<PathBuf> {
+	parse_version(version).map(|_| history_path(server_config, virtual_path).join(version))
+}
+
+// Split a version into when it was replaced and its place among the versions replaced in the same second
+// The first is just the seconds, and any after it in the same second get a sequence number, like 1700000000-1
+pub fn parse_version(version: &str) -> Option<(u64, u64)> {
+	let (seconds, sequence) = match version.split_once('-') {
+		Some((seconds, sequence)) => (seconds, sequence.parse().ok()?),
+		None => (version, 0)
+	};
+	Some((seconds.parse().ok()?, sequence))
+}
+
+// Move the current contents of a virtual path into its history before it gets overwritten, deleted or moved away
+pub fn archive(server_config: &ServerConfig, virtual_path: &Path) -> io::Result<()> {
+	let full_path = Path::new(server_config.get_files_root()).join(virtual_path);
+
+	// If there is nothing there, there is nothing to keep
+	if !full_path.is_file() {
+		return Ok(());
+	}
+
+	// Versions are named after when they were replaced, numbering any that were replaced in the same second
+	let history_path = history_path(server_config, virtual_path);
+	fs::create_dir_all(&history_path)?;
+	let seconds = now_seconds();
+	let version_path = (0..)
+		.map(|sequence| match sequence {
+			0 => history_path.join(seconds.to_string()),
+			_ => history_path.join(format!("{}-{}", seconds, sequence))
+		})
+		.find(|version_path| !version_path.exists())
+		.unwrap();
+
+	move_path(&full_path, &version_path)
+}
@@ -0,0 +1,271 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::SystemTime;
+
+use crate::sha256_digest_path;
+
+#[cfg(test)]
+mod tests {
+	use super::{FileIndex, FILE_INDEX_FILE};
+	use crate::sha256_digest_str;
+	use crate::test_dir::TestDir;
+	use std::fs;
+
+	#[test]
+	fn revalidate_by_stat() {
+		let data_root = TestDir::new("index");
+		let file_path = data_root.join("notes.txt");
+		fs::write(&file_path, "first").unwrap();
+
+		let index = FileIndex::load(&data_root, 1);
+		assert_eq!(index.digest(&file_path), sha256_digest_str("first"));
+
+		// Changing the size changes the stamp, so the file is hashed again
+		fs::write(&file_path, "second version").unwrap();
+		assert_eq!(index.digest(&file_path), sha256_digest_str("second version"));
+
+		// The index survives being loaded again, and forgotten files stay forgotten
+		let index = FileIndex::load(&data_root, 1);
+		assert_eq!(index.len(), 1);
+		index.forget(&file_path);
+		assert_eq!(FileIndex::load(&data_root, 1).len(), 0);
+	}
+
+	#[test]
+	fn compact_while_running() {
+		let data_root = TestDir::new("index_compact");
+		let file_path = data_root.join("notes.txt");
+		fs::write(&file_path, "first").unwrap();
+
+		// Hashing and forgetting the same file over and over only adds lines until the file is rewritten
+		let index = FileIndex::load(&data_root, 1);
+		for _ in 0..1000 {
+			index.digest(&file_path);
+			index.forget(&file_path);
+		}
+		let lines = fs::read_to_string(data_root.join(FILE_INDEX_FILE)).unwrap().lines().count();
+		assert!(lines <= 1024, "{} lines were left in the index", lines);
+	}
+}
+
+// The name of the index in the data root
+pub const FILE_INDEX_FILE: &str = "file_index.log";
+
+// How a file looked when it was hashed, if any of it has changed since then the file has to be hashed again
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+struct FileStamp {
+	size: u64,
+	modified_nanos: u128,
+	inode: u64,
+	changed_nanos: i128 // When the inode last changed, which catches writes that put the modified time back
+}
+
+impl FileStamp {
+	// Constructor
+
+	fn from_metadata(metadata: &Metadata) -> Self {
+		let (inode, changed_nanos) = inode_and_changed_nanos(metadata);
+		Self {
+			size: metadata.len(),
+			modified_nanos: metadata.modified().ok()
+				.and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+				.map_or(0, |duration| duration.as_nanos()),
+			inode,
+			changed_nanos
+		}
+	}
+}
+
+// Utility function to get the inode number and change time of a file, which only Unix has
+#[cfg(unix)]
+fn inode_and_changed_nanos(metadata: &Metadata) -> (u64, i128) {
+	use std::os::unix::fs::MetadataExt;
+	(metadata.ino(), metadata.ctime() as i128 * 1_000_000_000 + metadata.ctime_nsec() as i128)
+}
+
+#[cfg(not(unix))]
+fn inode_and_changed_nanos(_metadata: &Metadata) -> (u64, i128) {
+	(0, 0)
+}
+
+// Utility function to get how many files to hash at the same time when not told, one per CPU
+pub fn default_hash_threads() -> usize {
+	thread::available_parallelism().map_or(1, |threads| threads.get())
+}
+
+// A line of the index, a file with no digest being one that was forgotten
+#[derive(Serialize, Deserialize)]
+struct IndexLine {
+	path: PathBuf,
+	stamp: Option<FileStamp>,
+	digest: Option<String>
+}
+
+// What the index knows, and how many lines the file has to tell when it has gotten too big
+struct IndexEntries {
+	digests: HashMap<PathBuf, (FileStamp, String)>,
+	file_lines: usize
+}
+
+impl IndexEntries {
+	// If the file is mostly old lines and should be rewritten
+	fn needs_compacting(&self) -> bool {
+		self.file_lines > 2 * self.digests.len() + 1024
+	}
+}
+
+// A structure for remembering the digest of every file that has been hashed, saved as JSON lines in the data root
+// A digest is trusted for as long as the file's size, modified time, inode and change time stay the same
+pub struct FileIndex {
+	path: PathBuf,
+	entries: Mutex<IndexEntries>,
+	hash_threads: usize // How many files digest_all hashes at the same time
+}
+
+impl FileIndex {
+
+	// Constructor
+
+	pub fn load(data_root: &Path, hash_threads: usize) -> Self {
+		let path = data_root.join(FILE_INDEX_FILE);
+
+		// Start knowing nothing if the file doesn't exist yet, later lines replace earlier ones for the same path
+		let mut digests = HashMap::new();
+		let mut file_lines = 0;
+		if let Ok(file) = File::open(&path) {
+			for line in BufReader::new(file).lines().map_while(Result::ok) {
+				file_lines += 1;
+				match serde_json::from_str::<IndexLine>(&line) {
+					Ok(IndexLine { path, stamp: Some(stamp), digest: Some(digest) }) => {
+						digests.insert(path, (stamp, digest));
+					},
+					Ok(IndexLine { path, .. }) => {
+						digests.remove(&path);
+					},
+					Err(_) => {}
+				}
+			}
+		}
+
+		let mut entries = IndexEntries { digests, file_lines };
+		if entries.needs_compacting() {
+			write_compacted(&path, &mut entries);
+		}
+		Self {
+			path,
+			entries: Mutex::new(entries),
+			hash_threads: hash_threads.max(1)
+		}
+	}
+
+	// How many files the index knows about
+	pub fn len(&self) -> usize {
+		self.entries.lock().unwrap().digests.len()
+	}
+
+	// If the index doesn't know about any files
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	// Get the digest of a file, only hashing it if it changed since it was last hashed
+	pub fn digest(&self, path: &Path) -> String {
+		let stamp = match fs::metadata(path) {
+			Ok(metadata) => FileStamp::from_metadata(&metadata),
+			Err(_) => {
+				return sha256_digest_path(path);
+			}
+		};
+
+		if let Some((known_stamp, digest)) = self.entries.lock().unwrap().digests.get(path) {
+			if *known_stamp == stamp {
+				return digest.clone();
+			}
+		}
+
+		// Hash without holding the lock, since that's the slow part
+		let digest = sha256_digest_path(path);
+		self.update(IndexLine { path: path.to_path_buf(), stamp: Some(stamp), digest: Some(digest.clone()) });
+		digest
+	}
+
+	// Make sure the index knows the digest of every file given, hashing the ones it doesn't on several threads
+	pub fn digest_all(&self, paths: &[PathBuf]) {
+		let next = AtomicUsize::new(0);
+		let hash_next = || {
+			while let Some(path) = paths.get(next.fetch_add(1, Ordering::Relaxed)) {
+				self.digest(path);
+			}
+		};
+
+		let threads = self.hash_threads.min(paths.len());
+		if threads <= 1 {
+			hash_next();
+			return;
+		}
+		thread::scope(|scope| {
+			for _ in 0..threads {
+				scope.spawn(hash_next);
+			}
+		});
+	}
+
+	// Forget every digest, so every file is hashed again
+	pub fn clear(&self) {
+		let mut entries = self.entries.lock().unwrap();
+		entries.digests.clear();
+		write_compacted(&self.path, &mut entries);
+	}
+
+	// Forget about a file that was deleted or moved away
+	pub fn forget(&self, path: &Path) {
+		if self.entries.lock().unwrap().digests.contains_key(path) {
+			self.update(IndexLine { path: path.to_path_buf(), stamp: None, digest: None });
+		}
+	}
+
+	// Change what the index knows and add it to the end of the file
+	fn update(&self, index_line: IndexLine) {
+		let mut entries = self.entries.lock().unwrap();
+		let line = serde_json::to_string(&index_line).expect("Could not serialize index line");
+		match (index_line.stamp, index_line.digest) {
+			(Some(stamp), Some(digest)) => {
+				entries.digests.insert(index_line.path, (stamp, digest));
+			},
+			_ => {
+				entries.digests.remove(&index_line.path);
+			}
+		}
+
+		let appended = self.path.parent().map_or(Ok(()), fs::create_dir_all)
+			.and_then(|()| OpenOptions::new().create(true).append(true).open(&self.path))
+			.and_then(|mut file| writeln!(file, "{}", line));
+		match appended {
+			Ok(()) => entries.file_lines += 1,
+			Err(e) => println!("Could not append to file index {:?}: {}", self.path, e)
+		}
+
+		// A server that runs for a long time would otherwise only get the file rewritten when it restarts
+		if entries.needs_compacting() {
+			write_compacted(&self.path, &mut entries);
+		}
+	}
+}
+
+// Utility function to write out only what the index still knows
+fn write_compacted(path: &Path, entries: &mut IndexEntries) {
+	let lines: String = entries.digests.iter()
+		.map(|(path, (stamp, digest))| IndexLine { path: path.clone(), stamp: Some(*stamp), digest: Some(digest.clone()) })
+		.map(|index_line| format!("{}\n", serde_json::to_string(&index_line).expect("Could not serialize index line")))
+		.collect();
+	match fs::write(path, lines) {
+		Ok(()) => entries.file_lines = entries.digests.len(),
+		Err(e) => println!("Could not compact file index {:?}: {}", path, e)
+	}
+}
@@ -1,21 +1,59 @@
+use std::collections::HashMap;
 use std::ffi::OsStr;
+use std::fmt;
 use ring::digest::{Context, Digest, SHA256};
+use ring::hmac;
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
 use rocket::Request;
 use rocket::request::{FromRequest, Outcome};
+use rocket::mtls::Certificate;
 use rocket::http::Status;
 use serde::{Serialize, Deserialize};
 use std::fs;
 use std::io::{self, Read};
-use std::path::{Path, PathBuf, StripPrefixError};
+use std::num::NonZeroU32;
+use std::path::{Component, Path, PathBuf, StripPrefixError};
 use std::time::SystemTime;
 use std::io::BufReader;
 use data_encoding::HEXUPPER;
 use toml::{Value, value::Table};
 
+pub mod audit;
+pub mod bandwidth;
+pub mod events;
+pub mod filter;
+pub mod history;
+pub mod index;
+pub mod limits;
+pub mod links;
+pub mod placeholder;
+pub mod sessions;
+pub mod signing;
+pub mod sync_state;
+#[cfg(test)]
+mod test_dir;
+pub mod tls;
+pub mod tokens;
+pub mod totp;
+pub mod tree;
+
+use audit::{AuditConfig, AuditIdentity};
+use bandwidth::BandwidthConfig;
+use filter::{IgnoredPaths, MappingFilter};
+use index::FileIndex;
+use limits::{LimitsConfig, RateLimiter, RetryAfter, account_key, failure_keys, ip_key};
+use sessions::{SessionStore, SESSION_COOKIE};
+use signing::{ValidSignature, SIGNATURE_HEADER};
+use tls::{TlsConfig, certificate_username};
+use tokens::TokenStore;
+use totp::TotpStore;
+
 #[cfg(test)]
 mod tests {
-	use super::{FileInfo, modified_seconds_path, sha256_digest_path};
+	use super::{FileInfo, ServerConfig, RetryConfig, Access, modified_seconds_path, sha256_digest_path, virtual_path_from_str};
 	use std::path::Path;
+	use std::time::Duration;
 
 	const TEST_DIR_PATH_STR: &str = "test_dir";
 	const TEST_FILE_PATH_STR: &str = "test_dir/test.txt";
@@ -25,7 +63,7 @@ mod tests {
 	#[test]
 	fn get_test_file_info() {
 		let test_file_path = Path::new(TEST_FILE_PATH_STR).to_path_buf();
-		let test_file_info = FileInfo::from_file_path(test_file_path).expect(format!("Error creating test file info from path at '{}'", TEST_FILE_PATH_STR).as_str());
+		let test_file_info = FileInfo::from_file_path(test_file_path).unwrap_or_else(|_| panic!("Error creating test file info from path at '{}'", TEST_FILE_PATH_STR));
 		let assert_path = Path::new(TEST_FILE_PATH_STR);
 		assert_eq!(test_file_info.get_path(), assert_path);
 		assert_eq!(test_file_info.get_seconds(), modified_seconds_path(assert_path));
@@ -35,7 +73,7 @@ mod tests {
 	#[test]
 	fn get_inner_file_info() {
 		let inner_file_path = Path::new(INNER_FILE_PATH_STR).to_path_buf();
-		let inner_file_info = FileInfo::from_file_path(inner_file_path).expect(format!("Error creating inner file info from path at '{}'", INNER_FILE_PATH_STR).as_str());
+		let inner_file_info = FileInfo::from_file_path(inner_file_path).unwrap_or_else(|_| panic!("Error creating inner file info from path at '{}'", INNER_FILE_PATH_STR));
 		let assert_path = Path::new(INNER_FILE_PATH_STR);
 		assert_eq!(inner_file_info.get_path(), assert_path);
 		assert_eq!(inner_file_info.get_seconds(), modified_seconds_path(assert_path));
@@ -45,13 +83,13 @@ mod tests {
 	#[test]
 	fn get_test_dir_info() {
 		let test_file_path = Path::new(TEST_FILE_PATH_STR).to_path_buf();
-		let test_file_info = FileInfo::from_file_path(test_file_path).expect(format!("Error creating test file info from path at '{}'", TEST_FILE_PATH_STR).as_str());
+		let test_file_info = FileInfo::from_file_path(test_file_path).unwrap_or_else(|_| panic!("Error creating test file info from path at '{}'", TEST_FILE_PATH_STR));
 
 		let inner_file_path = Path::new(INNER_FILE_PATH_STR).to_path_buf();
-		let inner_file_info = FileInfo::from_file_path(inner_file_path).expect(format!("Error creating inner file info from path at '{}'", INNER_FILE_PATH_STR).as_str());
+		let inner_file_info = FileInfo::from_file_path(inner_file_path).unwrap_or_else(|_| panic!("Error creating inner file info from path at '{}'", INNER_FILE_PATH_STR));
 
 		let test_dir_path = Path::new(TEST_DIR_PATH_STR);
-		let test_dir_infos = FileInfo::from_dir_path(test_dir_path).expect(format!("Error creating test dir infos from path at '{}'", TEST_DIR_PATH_STR).as_str());
+		let test_dir_infos = FileInfo::from_dir_path(test_dir_path).unwrap_or_else(|_| panic!("Error creating test dir infos from path at '{}'", TEST_DIR_PATH_STR));
 		
 		assert_eq!(test_dir_infos.len(), 2);
 		assert_eq!(test_dir_infos[0], inner_file_info);
@@ -61,18 +99,76 @@ mod tests {
 	#[test]
 	fn get_inner_dir_info() {
 		let inner_file_path = Path::new(INNER_FILE_PATH_STR).to_path_buf();
-		let inner_file_info = FileInfo::from_file_path(inner_file_path).expect(format!("Error creating inner file info from path at '{}'", INNER_FILE_PATH_STR).as_str());
+		let inner_file_info = FileInfo::from_file_path(inner_file_path).unwrap_or_else(|_| panic!("Error creating inner file info from path at '{}'", INNER_FILE_PATH_STR));
 
 		let test_dir_path = Path::new(INNER_DIR_PATH_STR);
-		let test_dir_infos = FileInfo::from_dir_path(test_dir_path).expect(format!("Error creating inner dir infos from path at '{}'", INNER_DIR_PATH_STR).as_str());
+		let test_dir_infos = FileInfo::from_dir_path(test_dir_path).unwrap_or_else(|_| panic!("Error creating inner dir infos from path at '{}'", INNER_DIR_PATH_STR));
 		
 		assert_eq!(test_dir_infos.len(), 1);
 		assert_eq!(test_dir_infos[0], inner_file_info);
 	}
+
+	#[test]
+	fn retry_backoff() {
+		let retry_config: RetryConfig = toml::from_str(r#"
+			base_milliseconds = 100
+			max_milliseconds = 1000
+		"#).expect("Error parsing test retry config");
+
+		// Each retry waits between half and all of the doubled delay, which stops growing at the most any retry waits
+		for (retry, delay) in [(0, 100), (1, 200), (2, 400), (3, 800), (4, 1000), (40, 1000)] {
+			for _ in 0..20 {
+				let backoff = retry_config.backoff(retry);
+				assert!(backoff >= Duration::from_millis(delay / 2) && backoff <= Duration::from_millis(delay), "retry {} waited {:?}", retry, backoff);
+			}
+		}
+
+		// A wait the server asks for is honored unless it is longer than any retry waits
+		assert_eq!(retry_config.wait_for(Duration::from_secs(1), 0), Some(Duration::from_secs(1)));
+		assert!(retry_config.wait_for(Duration::ZERO, 0).unwrap() >= Duration::from_millis(50));
+		assert_eq!(retry_config.wait_for(Duration::from_secs(2), 0), None);
+	}
+
+	#[test]
+	fn share_access() {
+		let server_config: ServerConfig = toml::from_str(r#"
+			files_root = "server_files"
+			password = "testpass"
+			ignored_paths = []
+
+			[users.alice]
+			password = "alicepass"
+
+			[users.bob]
+			password = "bobpass"
+
+			[users.carol]
+			password = "carolpass"
+
+			[shares.team]
+			owner = "alice"
+			members = { bob = "read" }
+		"#).expect("Error parsing test server config");
+
+		assert_eq!(server_config.get_access(None, Path::new("/anything/at/all.txt")), Access::ReadWrite);
+		assert_eq!(server_config.get_access(Some("alice"), Path::new("/shares/team/notes.txt")), Access::ReadWrite);
+		assert_eq!(server_config.get_access(Some("bob"), Path::new("shares/team/notes.txt")), Access::Read);
+		assert_eq!(server_config.get_access(Some("carol"), Path::new("/shares/team/notes.txt")), Access::Denied);
+		assert_eq!(server_config.get_access(Some("alice"), Path::new("/shares/other/notes.txt")), Access::Denied);
+		assert_eq!(server_config.get_access(Some("alice"), Path::new("/notes.txt")), Access::Denied);
+	}
+
+	#[test]
+	fn parse_virtual_paths() {
+		assert_eq!(virtual_path_from_str("/docs/notes.txt"), Some(Path::new("docs/notes.txt").to_path_buf()));
+		assert_eq!(virtual_path_from_str("docs/notes.txt"), Some(Path::new("docs/notes.txt").to_path_buf()));
+		assert_eq!(virtual_path_from_str("/docs/../../etc/passwd"), None);
+		assert_eq!(virtual_path_from_str("/"), None);
+	}
 }
 
 // A structure for representing the config file
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct Config {
 	server: ServerConfig,
 	client: ClientConfig
@@ -83,8 +179,13 @@ impl Config {
 	// Constructor
 
 	pub fn from_file<P: AsRef<Path>>(path: P) -> Self {
-		let config_string = fs::read_to_string(path).unwrap();
-		toml::from_str(&config_string).unwrap()
+		Self::load(path).unwrap()
+	}
+
+	// Like from_file, but for callers that want to report a bad config instead of panicking
+	pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+		let config_string = fs::read_to_string(path).map_err(ConfigError::Read)?;
+		toml::from_str(&config_string).map_err(ConfigError::Parse)
 	}
 
 	// Getters
@@ -98,12 +199,45 @@ impl Config {
 	}
 }
 
+// Things that can go wrong when loading the config file
+#[derive(Debug)]
+pub enum ConfigError {
+	Read(io::Error),
+	Parse(toml::de::Error)
+}
+
+impl fmt::Display for ConfigError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			ConfigError::Read(e) => write!(f, "could not read config file: {}", e),
+			ConfigError::Parse(e) => write!(f, "could not parse config file: {}", e)
+		}
+	}
+}
+
+impl std::error::Error for ConfigError {}
+
+// The virtual directory that shared folders live under
+pub const SHARES_DIR: &str = "shares";
+
 // A structure for representing the server config
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct ServerConfig {
 	files_root: String,
 	password: String,
-	ignored_paths: Value
+	#[serde(default)]
+	ignored_paths: IgnoredPaths,
+	#[serde(default = "default_data_root")]
+	data_root: String,
+	#[serde(default)]
+	limits: LimitsConfig,
+	#[serde(default)]
+	audit: AuditConfig,
+	#[serde(default)]
+	users: HashMap<String, UserConfig>,
+	#[serde(default)]
+	shares: HashMap<String, ShareConfig>,
+	tls: Option<TlsConfig>
 }
 
 impl ServerConfig {
@@ -118,15 +252,169 @@ impl ServerConfig {
 		self.password.as_str()
 	}
 
-	pub fn get_ignored_paths(&self) -> Vec<&OsStr> {
-		self.ignored_paths.as_array().expect("Ignored paths is not an array").as_slice().iter().map(|p| OsStr::new(p.as_str().expect("Ignored path is not string"))).collect()
+	pub fn get_data_root(&self) -> &str {
+		self.data_root.as_str()
+	}
+
+	pub fn get_limits(&self) -> &LimitsConfig {
+		&self.limits
+	}
+
+	pub fn get_audit(&self) -> &AuditConfig {
+		&self.audit
+	}
+
+	pub fn get_tls(&self) -> Option<&TlsConfig> {
+		self.tls.as_ref()
+	}
+
+	pub fn get_ignored_paths(&self) -> &IgnoredPaths {
+		&self.ignored_paths
+	}
+
+	pub fn get_user(&self, username: &str) -> Option<&UserConfig> {
+		self.users.get(username)
+	}
+
+	pub fn get_share(&self, share_name: &str) -> Option<&ShareConfig> {
+		self.shares.get(share_name)
+	}
+
+	// Get a user's password, or the server password if there is no user
+	pub fn get_password_for(&self, username: Option<&str>) -> Result<&str, PasswordValidationError> {
+		match username {
+			Some(username) => match self.get_user(username) {
+				Some(user) => Ok(user.get_password()),
+				None => Err(PasswordValidationError::UnknownUser)
+			},
+			None => Ok(self.get_password())
+		}
+	}
+
+	// Check a password against a user's password, or against the server password if there is no user
+	pub fn check_password(&self, username: Option<&str>, password: &str) -> Result<(), PasswordValidationError> {
+		let actual_password = self.get_password_for(username)?;
+
+		// Check if the given password is equal to the actual password
+		if password == actual_password {
+			Ok(())
+		} else {
+			Err(PasswordValidationError::IncorrectPassword)
+		}
+	}
+
+	// Work out what a user may do with a virtual path, no user meaning the server password was used
+	pub fn get_access(&self, username: Option<&str>, virtual_path: &Path) -> Access {
+		// The server password has full access to everything
+		let username = match username {
+			Some(username) => username,
+			None => return Access::ReadWrite
+		};
+
+		// Users can only reach paths inside of a shared folder, so look for /shares/<name>/...
+		let mut parts = virtual_path.iter().filter(|part| *part != "/");
+		if parts.next() != Some(OsStr::new(SHARES_DIR)) {
+			return Access::Denied;
+		}
+
+		// Look up the share by name and ask it about the user
+		match parts.next().and_then(|name| name.to_str()).and_then(|name| self.get_share(name)) {
+			Some(share) => share.get_access(username),
+			None => Access::Denied
+		}
+	}
+}
+
+// Where the server keeps its own state if the config does not say otherwise
+fn default_data_root() -> String {
+	"server_data".to_string()
+}
+
+// A structure for representing a user account
+#[derive(Deserialize, Clone)]
+pub struct UserConfig {
+	password: String
+}
+
+impl UserConfig {
+
+	// Getters
+
+	pub fn get_password(&self) -> &str {
+		self.password.as_str()
+	}
+}
+
+// The rights a user can have on a path
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Access {
+	Denied,
+	Read,
+	ReadWrite
+}
+
+impl Access {
+	pub fn can_read(&self) -> bool {
+		*self >= Access::Read
+	}
+
+	pub fn can_write(&self) -> bool {
+		*self >= Access::ReadWrite
+	}
+}
+
+// A structure for representing a named shared folder
+#[derive(Deserialize, Clone)]
+pub struct ShareConfig {
+	owner: String,
+	#[serde(default)]
+	members: HashMap<String, Access>
+}
+
+impl ShareConfig {
+
+	// Getters
+
+	pub fn get_owner(&self) -> &str {
+		self.owner.as_str()
+	}
+
+	pub fn get_members(&self) -> &HashMap<String, Access> {
+		&self.members
+	}
+
+	// The owner can always read and write, members get what they were granted
+	pub fn get_access(&self, username: &str) -> Access {
+		if username == self.owner {
+			return Access::ReadWrite;
+		}
+		self.members.get(username).copied().unwrap_or(Access::Denied)
 	}
 }
 
 // A structure for representing the client config
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct ClientConfig {
 	server_url: String,
+	username: Option<String>,
+	password: Option<String>,
+	token: Option<String>,
+	#[serde(default)]
+	sign_requests: bool,
+	#[serde(default = "default_client_data_root")]
+	data_root: String, // Where the client keeps its own state
+	ca_certs: Option<String>, // Path to extra PEM certificates to trust, for servers with their own certificate authority
+	client_cert: Option<String>, // Path to the PEM certificate to identify this client with
+	client_key: Option<String>, // Path to the PEM private key for the client certificate
+	#[serde(default)]
+	watch: WatchConfig,
+	#[serde(default)]
+	transfers: TransferConfig,
+	#[serde(default)]
+	bandwidth: BandwidthConfig,
+	#[serde(default)]
+	retry: RetryConfig,
 	mappings: Mappings
 }
 
@@ -141,13 +429,175 @@ impl ClientConfig {
 	pub fn get_server_url(&self) -> &str {
 		&self.server_url
 	}
+
+	pub fn get_username(&self) -> Option<&str> {
+		self.username.as_deref()
+	}
+
+	pub fn get_password(&self) -> Option<&str> {
+		self.password.as_deref()
+	}
+
+	pub fn get_token(&self) -> Option<&str> {
+		self.token.as_deref()
+	}
+
+	pub fn get_sign_requests(&self) -> bool {
+		self.sign_requests
+	}
+
+	pub fn get_data_root(&self) -> &str {
+		&self.data_root
+	}
+
+	pub fn get_ca_certs(&self) -> Option<&str> {
+		self.ca_certs.as_deref()
+	}
+
+	pub fn get_client_cert(&self) -> Option<&str> {
+		self.client_cert.as_deref()
+	}
+
+	pub fn get_client_key(&self) -> Option<&str> {
+		self.client_key.as_deref()
+	}
+
+	pub fn get_watch(&self) -> &WatchConfig {
+		&self.watch
+	}
+
+	pub fn get_transfers(&self) -> &TransferConfig {
+		&self.transfers
+	}
+
+	pub fn get_bandwidth(&self) -> &BandwidthConfig {
+		&self.bandwidth
+	}
+
+	pub fn get_retry(&self) -> &RetryConfig {
+		&self.retry
+	}
+}
+
+// A structure for representing how the client's watch daemon waits for changes
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct WatchConfig {
+	debounce_milliseconds: u64, // How long a path has to stop changing before it is synced
+	poll_seconds: u64 // How often every mapping is synced anyway, to pick up changes on the server
+}
+
+impl Default for WatchConfig {
+	fn default() -> Self {
+		Self {
+			debounce_milliseconds: 500,
+			poll_seconds: 60
+		}
+	}
+}
+
+impl WatchConfig {
+
+	// Getters
+
+	pub fn get_debounce_milliseconds(&self) -> u64 {
+		self.debounce_milliseconds
+	}
+
+	pub fn get_poll_seconds(&self) -> u64 {
+		self.poll_seconds
+	}
+}
+
+// A structure for representing how much work the client does at once
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct TransferConfig {
+	concurrent: usize, // How many files are checked and transferred at the same time
+	hash_threads: usize // How many files are hashed at the same time, 0 for one per CPU
+}
+
+impl Default for TransferConfig {
+	fn default() -> Self {
+		Self {
+			concurrent: 4,
+			hash_threads: 0
+		}
+	}
+}
+
+impl TransferConfig {
+
+	// Getters
+
+	pub fn get_concurrent(&self) -> usize {
+		self.concurrent.max(1)
+	}
+
+	pub fn get_hash_threads(&self) -> usize {
+		match self.hash_threads {
+			0 => index::default_hash_threads(),
+			hash_threads => hash_threads
+		}
+	}
+}
+
+// A structure for representing how the client tries again after failures that might not happen a second time
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct RetryConfig {
+	attempts: u32, // How many times a request is tried again before giving up on it
+	base_milliseconds: u64, // About how long to wait before the first retry, doubling for each one after
+	max_milliseconds: u64 // The longest to wait before any retry
+}
+
+impl Default for RetryConfig {
+	fn default() -> Self {
+		Self {
+			attempts: 3,
+			base_milliseconds: 500,
+			max_milliseconds: 30_000
+		}
+	}
+}
+
+impl RetryConfig {
+
+	// Getters
+
+	pub fn get_attempts(&self) -> u32 {
+		self.attempts
+	}
+
+	// How long to wait before a retry, counting from 0, somewhere between half and all of the doubled delay so clients that failed together don't retry together
+	pub fn backoff(&self, retry: u32) -> std::time::Duration {
+		let delay = self.base_milliseconds.saturating_mul(1 << retry.min(32)).min(self.max_milliseconds);
+		let mut random = [0; 8];
+		SystemRandom::new().fill(&mut random).expect("Could not generate random bytes");
+		let jitter = u64::from_le_bytes(random) % (delay / 2 + 1);
+		std::time::Duration::from_millis(delay - jitter)
+	}
+
+	// How long to wait before a retry when the server asked for a wait, giving nothing if that is longer than any retry waits
+	pub fn wait_for(&self, requested: std::time::Duration, retry: u32) -> Option<std::time::Duration> {
+		(requested.as_millis() <= u128::from(self.max_milliseconds)).then(|| requested.max(self.backoff(retry)))
+	}
+}
+
+// Where the client keeps its own state if the config does not say otherwise
+fn default_client_data_root() -> String {
+	"client_data".to_string()
 }
 
 // A structure for representing the file and directory mappings
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct Mappings {
 	files: Value,
-	dirs: Value
+	dirs: Value, // A directory's entry can be a table with the server path under server and the rules for which files are synced
+	#[serde(default)]
+	placeholders: Vec<PathBuf>, // The client paths of the mapped directories that get placeholders instead of downloads
+	#[serde(default)]
+	delete_placeholders: bool // If deleting a placeholder deletes the file from the server, rather than the placeholder being put back
 }
 
 impl Mappings {
@@ -161,6 +611,32 @@ impl Mappings {
 	pub fn get_dir_mappings(&self) -> &Table {
 		self.dirs.as_table().expect("Dir mappings are not a table")
 	}
+
+	// Get the rules a mapped directory is filtered with, which it only has if its entry is a table
+	pub fn get_filter(&self, client_path: &Path) -> Result<Option<MappingFilter>, String> {
+		match self.get_dir_mappings().get(&*client_path.to_string_lossy()) {
+			Some(entry) if entry.is_table() => entry.clone().try_into().map(Some).map_err(|e| format!("bad rules for {:?}: {}", client_path, e)),
+			_ => Ok(None)
+		}
+	}
+
+	pub fn get_placeholders(&self) -> &[PathBuf] {
+		&self.placeholders
+	}
+
+	// If a mapped directory gets placeholders for the files on the server instead of downloading them
+	pub fn uses_placeholders(&self, client_path: &Path) -> bool {
+		self.placeholders.iter().any(|placeholder_path| placeholder_path == client_path)
+	}
+
+	pub fn get_delete_placeholders(&self) -> bool {
+		self.delete_placeholders
+	}
+}
+
+// Utility function to get the server path of a mapping's entry, which is either the path itself or a table with it under server
+pub fn mapping_server_str(entry: &Value) -> Option<&str> {
+	entry.as_str().or_else(|| entry.get("server")?.as_str())
 }
 
 // A structure for representing a mapping from a client path to a server path
@@ -177,8 +653,8 @@ impl Mapping {
 		let client_mapping_str = client_file_string.as_str();
 		let client_path_buf = Path::new(client_mapping_str).to_path_buf();
 
-		let server_mapping_str = server_file_value.as_str()
-			.expect(format!("Mapping value was not a string: {:?}", server_file_value).as_str());
+		let server_mapping_str = mapping_server_str(server_file_value)
+			.unwrap_or_else(|| panic!("Mapping value was not a string: {:?}", server_file_value));
 		let server_path_buf = Path::new(server_mapping_str).to_path_buf();
 
 		Self {
@@ -194,7 +670,7 @@ impl Mapping {
 	}
 
 	pub fn get_client_path_str(&self) -> &str {
-		&self.client_path_buf.to_str().expect("Client path could not be interpreted as &str")
+		self.client_path_buf.to_str().expect("Client path could not be interpreted as &str")
 	}
 
 	pub fn get_server_path(&self) -> &Path {
@@ -202,7 +678,7 @@ impl Mapping {
 	}
 
 	pub fn get_server_path_str(&self) -> &str {
-		&self.server_path_buf.to_str().expect("Server path could not be interpreted as &str")
+		self.server_path_buf.to_str().expect("Server path could not be interpreted as &str")
 	}
 }
 
@@ -212,7 +688,9 @@ pub struct FileInfo {
 	path: PathBuf, // The path of the file
 	seconds: u64, // When it was last modified
 	digest: String, // Its SHA-256 digest
-	exists: bool // If it exists
+	exists: bool, // If it exists
+	#[serde(default)]
+	size: u64 // How many bytes it is
 }
 
 // Things that can go wrong when making a FileInfo
@@ -226,17 +704,31 @@ pub enum FileInfoError {
 impl FileInfo {
 	// Associated function to make a single FileInfo struct based on a path
 	pub fn from_file_path(path: PathBuf) -> Result<Self, FileInfoError> {
+		Self::from_file_path_digested(path, &sha256_digest_path)
+	}
+
+	// Associated function to make a single FileInfo struct based on a path, only hashing the file if the index doesn't already know its digest
+	pub fn from_indexed_file_path(path: PathBuf, index: &FileIndex) -> Result<Self, FileInfoError> {
+		Self::from_file_path_digested(path, &|path| index.digest(path))
+	}
+
+	// Associated function to make a FileInfo struct for a path that doesn't exist
+	pub fn missing(path: PathBuf) -> Self {
+		Self {
+			path,
+			seconds: 0,
+			digest: "".to_string(),
+			exists: false,
+			size: 0
+		}
+	}
+
+	// Private utility function to make a single FileInfo struct, getting the digest of the file however it is told to
+	fn from_file_path_digested(path: PathBuf, digest_path: &dyn Fn(&Path) -> String) -> Result<Self, FileInfoError> {
 		if !path.is_file() {
 			if !path.exists() {
 				// If the path is not a file and does not exist, return a non-existent FileInfo struct
-				return Ok(
-					Self {
-						path,
-						seconds: 0,
-						digest: "".to_string(),
-						exists: false,
-					}
-				);
+				return Ok(Self::missing(path));
 			}
 			
 			// If the path is not a file but does exist (meaning it is an existing path), return an error
@@ -245,7 +737,8 @@ impl FileInfo {
 
 		// Get some info based on the path
 		let seconds = modified_seconds_path(&path);
-		let digest = sha256_digest_path(&path);
+		let digest = digest_path(&path);
+		let size = fs::metadata(&path).map(|metadata| metadata.len()).unwrap_or(0);
 
 		// Build and return the FileInfo structure
 		Ok(
@@ -253,13 +746,29 @@ impl FileInfo {
 				path,
 				seconds,
 				digest,
-				exists: true
+				exists: true,
+				size
 			}
 		)
 	}
 
 	// Associated function to make a vector of FileInfo structs based on a path
 	pub fn from_dir_path(path: &Path) -> Result<Vec<Self>, FileInfoError> {
+		Self::from_dir_path_digested(path, &sha256_digest_path, &|_, _| true)
+	}
+
+	// Associated function to make a vector of FileInfo structs based on a path, using the index for the digests
+	// Only files the filter lets in are included, and directories it refuses aren't looked inside
+	// Any files the index doesn't know yet are hashed at the same time first
+	pub fn from_indexed_dir_path(path: &Path, index: &FileIndex, include: &dyn Fn(&Path, bool) -> bool) -> Result<Vec<Self>, FileInfoError> {
+		if let Ok(paths) = Self::walk_dir(path, include) {
+			index.digest_all(&paths);
+		}
+		Self::from_dir_path_digested(path, &|path| index.digest(path), include)
+	}
+
+	// Private utility function to make a vector of FileInfo structs, getting the digest of each file however it is told to
+	fn from_dir_path_digested(path: &Path, digest_path: &dyn Fn(&Path) -> String, include: &dyn Fn(&Path, bool) -> bool) -> Result<Vec<Self>, FileInfoError> {
 		if !path.is_dir() {
 			if !path.exists() {
 				// If the path is not a directory and does not exist, return an empty vector
@@ -271,10 +780,10 @@ impl FileInfo {
 		}
 
 		// Get the vector of PathBuf structs under the given path
-		let paths = Self::walk_dir(path).unwrap();
+		let paths = Self::walk_dir(path, include).unwrap();
 
 		// Turn the PathBufs into FileInfos and return the new vector
-		Ok(paths.into_iter().map(|p| Self::from_file_path(p).unwrap()).collect())
+		Ok(paths.into_iter().map(|p| Self::from_file_path_digested(p, digest_path).unwrap()).collect())
 	}
 
 	// Getters
@@ -295,14 +804,18 @@ impl FileInfo {
 		self.exists
 	}
 
+	pub fn get_size(&self) -> u64 {
+		self.size
+	}
+
 	// Remove a prefix from the path
 	pub fn strip_prefix<P: AsRef<Path>>(&mut self, prefix: P) -> Result<(), StripPrefixError> {
 		self.path = self.path.strip_prefix(prefix)?.to_path_buf();
 		Ok(())
 	}
 
-	// Private utility function to recursively search a directory
-	fn walk_dir(path: &Path) -> Result<Vec<PathBuf>, FileInfoError> {
+	// Private utility function to recursively search a directory, skipping whatever the filter refuses
+	fn walk_dir(path: &Path, include: &dyn Fn(&Path, bool) -> bool) -> Result<Vec<PathBuf>, FileInfoError> {
 		// If the path doesn't exist, how are we going to walk it?
 		if !path.exists() {
 			return Err(FileInfoError::NotFound);
@@ -318,22 +831,22 @@ impl FileInfo {
 
 		// If reading the given path's directory goes ok
 		if let Ok(iter) = fs::read_dir(path) {
-			// Loop though each entry
-			for entry in iter {
-				// If each entry is read ok
-				if let Ok(entry) = entry {
-					// Get the path of the entry
-					let path = entry.path();
-
-					if path.is_dir() { // If it is a directory
-						// Walk that directory and append it's returned vector to our own
-						if let Ok(mut subpaths) = Self::walk_dir(path.as_path()) {
-							paths.append(&mut subpaths);
-						}
-					} else { // If it is a file
-						// Add it to our own vector of PathBufs
-						paths.push(path)
+			// Loop though each entry that is read ok
+			for entry in iter.flatten() {
+				// Get the path of the entry
+				let path = entry.path();
+
+				if path.is_dir() { // If it is a directory
+					// Walk that directory and append it's returned vector to our own
+					if !include(&path, true) {
+						continue;
 					}
+					if let Ok(mut subpaths) = Self::walk_dir(path.as_path(), include) {
+						paths.append(&mut subpaths);
+					}
+				} else if include(&path, false) { // If it is a file
+					// Add it to our own vector of PathBufs
+					paths.push(path)
 				}
 			}
 		}
@@ -363,7 +876,7 @@ fn sha256_digest<R: Read>(mut reader: R) -> Result<Digest, io::Error> {
 }
 
 // A wrapper around sha256_digest, given a path
-fn sha256_digest_path(path: &Path) -> String {
+pub(crate) fn sha256_digest_path(path: &Path) -> String {
 	// Create a string to hold the eventual digest
 	let mut digest_string = "".to_string();
 
@@ -382,6 +895,78 @@ fn sha256_digest_path(path: &Path) -> String {
 	digest_string
 }
 
+// A wrapper around sha256_digest, given a string
+pub fn sha256_digest_str(string: &str) -> String {
+	sha256_digest_bytes(string.as_bytes())
+}
+
+// A wrapper around sha256_digest, given some bytes
+pub fn sha256_digest_bytes(bytes: &[u8]) -> String {
+	// Digesting an in-memory slice can not fail
+	let digest = sha256_digest(bytes).expect("Could not digest bytes");
+	HEXUPPER.encode(digest.as_ref())
+}
+
+// Utility function to get a random token of the given number of bytes as upper-case hexadecimal
+pub fn random_token(bytes: usize) -> String {
+	let mut buffer = vec![0; bytes];
+	SystemRandom::new().fill(&mut buffer).expect("Could not generate random bytes");
+	HEXUPPER.encode(&buffer)
+}
+
+// How many rounds of PBKDF2 go into a saved password hash, so that guessing the password from it is slow
+const PASSWORD_HASH_ITERATIONS: u32 = 100_000;
+
+// Utility function to hash a password that has to be saved, with a new random salt
+// Returns the salt and the hash as upper-case hexadecimal, joined by a '$'
+pub fn hash_password(password: &str) -> String {
+	let mut salt = [0; 16];
+	SystemRandom::new().fill(&mut salt).expect("Could not generate random bytes");
+	let mut hash = [0; ring::digest::SHA256_OUTPUT_LEN];
+	let iterations = NonZeroU32::new(PASSWORD_HASH_ITERATIONS).expect("Password hash iterations can not be zero");
+	pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, password.as_bytes(), &mut hash);
+	format!("{}${}", HEXUPPER.encode(&salt), HEXUPPER.encode(&hash))
+}
+
+// Utility function to check a password against a hash made by hash_password in constant time
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+	let iterations = NonZeroU32::new(PASSWORD_HASH_ITERATIONS).expect("Password hash iterations can not be zero");
+	match password_hash.split_once('$').map(|(salt, hash)| (HEXUPPER.decode(salt.as_bytes()), HEXUPPER.decode(hash.as_bytes()))) {
+		Some((Ok(salt), Ok(hash))) => pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, password.as_bytes(), &hash).is_ok(),
+		_ => false
+	}
+}
+
+// Utility function for request guards to get a query parameter that might be left out, failing with a 422 if it is there but can't be parsed
+pub fn optional_query_value<'r, T: rocket::form::FromForm<'r>>(req: &'r Request<'_>, name: &str) -> Result<Option<T>, Status> {
+	req.query_value(name).transpose().map_err(|_| Status::UnprocessableEntity)
+}
+
+// Utility function to turn a string into a relative virtual path, refusing anything that could escape the files root
+pub fn virtual_path_from_str(path: &str) -> Option<PathBuf> {
+	let virtual_path = Path::new(path.trim_start_matches('/'));
+	let normal = virtual_path.components().all(|component| matches!(component, Component::Normal(_)));
+	if normal && virtual_path.components().next().is_some() {
+		Some(virtual_path.to_path_buf())
+	} else {
+		None
+	}
+}
+
+// Utility function to move a file, falling back to copying it since renaming can't cross filesystems
+pub fn move_path(from: &Path, to: &Path) -> io::Result<()> {
+	if fs::rename(from, to).is_err() {
+		fs::copy(from, to)?;
+		fs::remove_file(from)?;
+	}
+	Ok(())
+}
+
+// Utility function to get the current number of seconds since the unix epoch
+pub fn now_seconds() -> u64 {
+	SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
 // Utility function to get when a path was last modified
 fn modified_seconds_path(path: &Path) -> u64 {
 	// Create a unsigned 64-bit integer to hold the eventual number of seconds
@@ -403,14 +988,95 @@ fn modified_seconds_path(path: &Path) -> u64 {
 	modified_seconds
 }
 
+// The key the server signs things with, kept in the data root so that signatures survive restarts
+pub struct ServerSecret {
+	key: hmac::Key
+}
+
+impl ServerSecret {
+
+	// Constructor
+
+	pub fn load_or_create(data_root: &Path) -> Self {
+		let secret_path = data_root.join("secret");
+
+		// Read the existing secret, or generate and save a new one if there isn't one yet
+		let secret = match fs::read_to_string(&secret_path) {
+			Ok(secret) => secret.trim().to_string(),
+			Err(_) => {
+				let secret = random_token(32);
+				fs::create_dir_all(data_root).expect("Could not create data root");
+				fs::write(&secret_path, &secret).expect("Could not save server secret");
+				secret
+			}
+		};
+
+		Self {
+			key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes())
+		}
+	}
+
+	// Sign a message, returning the signature as upper-case hexadecimal
+	pub fn sign(&self, message: &str) -> String {
+		HEXUPPER.encode(hmac::sign(&self.key, message.as_bytes()).as_ref())
+	}
+
+	// Check a signature made by sign in constant time
+	pub fn verify(&self, message: &str, signature: &str) -> bool {
+		match HEXUPPER.decode(signature.to_uppercase().as_bytes()) {
+			Ok(signature) => hmac::verify(&self.key, message.as_bytes(), &signature).is_ok(),
+			Err(_) => false
+		}
+	}
+}
+
 // A request guard strucure for getting authenticaing a request
-pub struct ValidPassword;
+pub struct ValidPassword {
+	username: Option<String>, // The user that authenticated, None if it was the server password
+	body_digest: Option<String> // The SHA-256 digest of the body, if the request was signed
+}
+
+impl ValidPassword {
+
+	// Getters
+
+	pub fn get_username(&self) -> Option<&str> {
+		self.username.as_deref()
+	}
+
+	pub fn get_body_digest(&self) -> Option<&str> {
+		self.body_digest.as_deref()
+	}
+
+	// What the authenticated party may do with a virtual path
+	pub fn get_access(&self, server_config: &ServerConfig, virtual_path: &Path) -> Access {
+		server_config.get_access(self.get_username(), virtual_path)
+	}
+}
 
 // Things that could go wrong with a valid password
 #[derive(Debug)]
 pub enum PasswordValidationError {
 	IncorrectPassword,
-	PasswordHeaderMissing
+	PasswordHeaderMissing,
+	UnknownUser,
+	InvalidToken,
+	TotpRequired,
+	IncorrectTotpCode,
+	LockedOut,
+	RateLimited,
+	SignatureHeaderMissing,
+	SignatureExpired,
+	SignatureReplayed
+}
+
+// Check a password and, for users that have two-factor authentication turned on, a code
+pub fn check_credentials(server_config: &ServerConfig, totp_store: &TotpStore, username: Option<&str>, password: &str, code: Option<&str>) -> Result<(), PasswordValidationError> {
+	server_config.check_password(username, password)?;
+	match username {
+		Some(username) if !totp_store.verify(username, code) => Err(PasswordValidationError::IncorrectTotpCode),
+		_ => Ok(())
+	}
 }
 
 // Request guard logic
@@ -419,22 +1085,122 @@ impl<'r> FromRequest<'r> for ValidPassword {
 	type Error = PasswordValidationError;
 
 	async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-		// Make sure that the 'password' header is present, fail and report if not
-		match req.headers().get_one("password") {
-			Some(password) => {
-				// Get the actual password from the config file
-				let actual_password = req.rocket().state::<Config>().unwrap().get_server_config().get_password();
-
-				// Check if the given password is equal to the actual password
-				if password == actual_password {
-					return Outcome::Success(Self);
-				} else {
-					return Outcome::Failure((Status::Unauthorized, PasswordValidationError::IncorrectPassword));
+		// Work out who is making the request, Rocket having already verified any client certificate
+		let certificate_username = match req.guard::<Certificate<'_>>().await {
+			Outcome::Success(certificate) => certificate_username(&certificate),
+			_ => None
+		};
+		let (valid_password, rate_key) = match Self::authenticate(req, certificate_username) {
+			Ok(authenticated) => authenticated,
+			Err(e) => {
+				return Outcome::Failure(e);
+			}
+		};
+
+		// Then make sure they aren't making too many requests
+		if let Some(limiter) = req.rocket().state::<RateLimiter>() {
+			if let Err(retry_after) = limiter.check_rate(&rate_key) {
+				req.local_cache(|| RetryAfter(retry_after));
+				return Outcome::Failure((Status::TooManyRequests, PasswordValidationError::RateLimited));
+			}
+		}
+
+		// Let the audit log know who it was
+		req.local_cache(|| AuditIdentity { username: valid_password.username.clone(), token: None });
+
+		Outcome::Success(valid_password)
+	}
+}
+
+impl ValidPassword {
+	// Check the request's client certificate, session, token or password, returning who it is and the key to count their requests against
+	fn authenticate(req: &Request<'_>, certificate_username: Option<String>) -> Result<(Self, String), (Status, PasswordValidationError)> {
+		let server_config = req.rocket().state::<Config>().unwrap().get_server_config();
+		let limiter = req.rocket().state::<RateLimiter>();
+
+		// Refuse anyone that is locked out from failing too many times
+		let check_locked = |keys: &[String]| {
+			match limiter.and_then(|limiter| limiter.locked_for(keys)) {
+				Some(retry_after) => {
+					req.local_cache(|| RetryAfter(retry_after));
+					Err((Status::TooManyRequests, PasswordValidationError::LockedOut))
+				},
+				None => Ok(())
+			}
+		};
+		let totp_enabled = |username: Option<&str>| username.zip(req.rocket().state::<TotpStore>()).is_some_and(|(username, totp)| totp.is_enabled(username));
+
+		// A client certificate signed by the configured authority is as good as the user's password, so it gets the same checks
+		if let Some(username) = certificate_username.filter(|username| server_config.get_user(username).is_some()) {
+			check_locked(&failure_keys(req.client_ip(), Some(&username)))?;
+			if totp_enabled(Some(&username)) {
+				return Err((Status::Unauthorized, PasswordValidationError::TotpRequired));
+			}
+			let rate_key = account_key(Some(&username));
+			return Ok((Self { username: Some(username), body_digest: None }, rate_key));
+		}
+
+		// A browser that logged in already has a session cookie, which is as good as a password
+		if let (Some(cookie), Some(sessions)) = (req.cookies().get(SESSION_COOKIE), req.rocket().state::<SessionStore>()) {
+			if let Some(session) = sessions.get(cookie.value()) {
+				let rate_key = account_key(session.get_username());
+				return Ok((Self { username: session.get_username().map(str::to_string), body_digest: None }, rate_key));
+			}
+		}
+
+		// An API token is also as good as a password, but a wrong one is a failure that counts against the IP address
+		if let (Some(token), Some(tokens)) = (req.headers().get_one("token"), req.rocket().state::<TokenStore>()) {
+			let keys: Vec<String> = req.client_ip().map(ip_key).into_iter().collect();
+			check_locked(&keys)?;
+			return match tokens.get(token) {
+				Some(api_token) => {
+					let token_digest = sha256_digest_str(token);
+					req.local_cache(|| AuditIdentity { username: api_token.get_username().map(str::to_string), token: Some(token_digest.clone()) });
+					let rate_key = format!("token:{}", token_digest);
+					Ok((Self { username: api_token.get_username().map(str::to_string), body_digest: None }, rate_key))
+				},
+				None => {
+					limiter.inspect(|limiter| limiter.record_failure(&keys));
+					Err((Status::Unauthorized, PasswordValidationError::InvalidToken))
 				}
-			},
-			None => {
-				return Outcome::Failure((Status::Unauthorized, PasswordValidationError::PasswordHeaderMissing));
+			};
+		}
+
+		// If a 'username' header is present, check against that user's password, otherwise against the server password
+		let username = req.headers().get_one("username");
+		let keys = failure_keys(req.client_ip(), username);
+
+		// A signed request proves it knows the password without sending it, otherwise the 'password' header has to be present
+		let body_digest = if req.headers().get_one(SIGNATURE_HEADER).is_some() {
+			check_locked(&keys)?;
+			let checked = server_config.get_password_for(username).and_then(|password| ValidSignature::verify(req, password));
+			match checked {
+				Ok(signature) => Some(signature.get_body_digest().to_string()),
+				Err(e) => {
+					// Only wrong signatures count as failures, a stale or replayed one was made with the right password
+					if matches!(e, PasswordValidationError::IncorrectPassword | PasswordValidationError::UnknownUser) {
+						limiter.inspect(|limiter| limiter.record_failure(&keys));
+					}
+					return Err((Status::Unauthorized, e));
+				}
+			}
+		} else {
+			let password = req.headers().get_one("password")
+				.ok_or((Status::Unauthorized, PasswordValidationError::PasswordHeaderMissing))?;
+			check_locked(&keys)?;
+			if let Err(e) = server_config.check_password(username, password) {
+				limiter.inspect(|limiter| limiter.record_failure(&keys));
+				return Err((Status::Unauthorized, e));
 			}
+			None
+		};
+		limiter.inspect(|limiter| limiter.record_success(&keys));
+
+		// Users with two-factor authentication can't get by with just a password, they have to mint a token
+		if totp_enabled(username) {
+			return Err((Status::Unauthorized, PasswordValidationError::TotpRequired));
 		}
+
+		Ok((Self { username: username.map(str::to_string), body_digest }, account_key(username)))
 	}
 }
@@ -0,0 +1,217 @@
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::now_seconds;
+
+#[cfg(test)]
+mod tests {
+	use super::{LimitsConfig, RateLimiter};
+
+	fn test_limiter() -> RateLimiter {
+		RateLimiter::new(&LimitsConfig {
+			max_failures: 3,
+			lockout_seconds: 10,
+			max_lockout_seconds: 25,
+			requests_per_minute: 2
+		})
+	}
+
+	#[test]
+	fn lockout_grows_and_resets() {
+		let limiter = test_limiter();
+		let keys = vec!["ip:127.0.0.1".to_string(), "user:bob".to_string()];
+
+		// Failures below the limit don't lock anything
+		limiter.record_failure(&keys);
+		limiter.record_failure(&keys);
+		assert_eq!(limiter.locked_for(&keys), None);
+
+		// Then each failure doubles the lockout, up to the maximum
+		limiter.record_failure(&keys);
+		assert_eq!(limiter.locked_for(&keys), Some(10));
+		limiter.record_failure(&keys);
+		assert_eq!(limiter.locked_for(&keys), Some(20));
+		limiter.record_failure(&keys);
+		assert_eq!(limiter.locked_for(&keys), Some(25));
+
+		// Either key being locked is enough
+		assert_eq!(limiter.locked_for(&["user:bob".to_string()]), Some(25));
+		assert_eq!(limiter.locked_for(&["user:alice".to_string()]), None);
+
+		// A success clears everything
+		limiter.record_success(&keys);
+		assert_eq!(limiter.locked_for(&keys), None);
+	}
+
+	#[test]
+	fn request_rate() {
+		let limiter = test_limiter();
+		assert!(limiter.check_rate("user:bob").is_ok());
+		assert!(limiter.check_rate("user:bob").is_ok());
+		assert!(limiter.check_rate("user:bob").is_err());
+		assert!(limiter.check_rate("user:alice").is_ok());
+	}
+}
+
+// A structure for representing the limits on authentication and requests
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct LimitsConfig {
+	max_failures: u32, // How many failed attempts are allowed before locking out
+	lockout_seconds: u64, // How long the first lockout lasts, doubling with each failure after that
+	max_lockout_seconds: u64, // The longest a lockout can last
+	requests_per_minute: u32 // How many requests each token or account can make a minute, 0 for no limit
+}
+
+impl Default for LimitsConfig {
+	fn default() -> Self {
+		Self {
+			max_failures: 5,
+			lockout_seconds: 30,
+			max_lockout_seconds: 60 * 60,
+			requests_per_minute: 600
+		}
+	}
+}
+
+// A structure for representing the failed attempts of an IP address or account
+struct FailureRecord {
+	failures: u32,
+	last_failure: u64,
+	locked_until: u64
+}
+
+// A structure for representing the requests made in the current minute
+struct RequestWindow {
+	start: u64,
+	count: u32
+}
+
+// A structure for keeping track of failed authentication and request rates, only kept in memory
+// Clones share the same counts, so relaunching the server doesn't let anyone off a lockout
+#[derive(Clone)]
+pub struct RateLimiter {
+	limits: LimitsConfig,
+	failures: Arc<Mutex<HashMap<String, FailureRecord>>>,
+	requests: Arc<Mutex<HashMap<String, RequestWindow>>>
+}
+
+impl RateLimiter {
+
+	// Constructor
+
+	pub fn new(limits: &LimitsConfig) -> Self {
+		Self {
+			limits: limits.clone(),
+			failures: Arc::new(Mutex::new(HashMap::new())),
+			requests: Arc::new(Mutex::new(HashMap::new()))
+		}
+	}
+
+	// How many seconds are left before any of the keys are allowed to try again, if they are locked out
+	pub fn locked_for(&self, keys: &[String]) -> Option<u64> {
+		let failures = self.failures.lock().unwrap();
+		let now = now_seconds();
+		keys.iter()
+			.filter_map(|key| failures.get(key))
+			.map(|record| record.locked_until.saturating_sub(now))
+			.filter(|seconds| *seconds > 0)
+			.max()
+	}
+
+	// Count a failed attempt against every key, locking them out for longer and longer once there are too many
+	pub fn record_failure(&self, keys: &[String]) {
+		let mut failures = self.failures.lock().unwrap();
+		let now = now_seconds();
+
+		// Forget about failures that are old enough not to matter anymore
+		let max_lockout_seconds = self.limits.max_lockout_seconds;
+		failures.retain(|_, record| record.locked_until > now || now.saturating_sub(record.last_failure) < max_lockout_seconds);
+
+		for key in keys {
+			let record = failures.entry(key.clone()).or_insert(FailureRecord { failures: 0, last_failure: now, locked_until: 0 });
+			record.failures += 1;
+			record.last_failure = now;
+
+			// Double the lockout for every failure past the limit
+			if record.failures >= self.limits.max_failures {
+				let doublings = (record.failures - self.limits.max_failures).min(32);
+				let lockout_seconds = self.limits.lockout_seconds.saturating_mul(1 << doublings).min(max_lockout_seconds);
+				record.locked_until = now + lockout_seconds;
+			}
+		}
+	}
+
+	// Clear the failed attempts of every key
+	pub fn record_success(&self, keys: &[String]) {
+		let mut failures = self.failures.lock().unwrap();
+		for key in keys {
+			failures.remove(key);
+		}
+	}
+
+	// Count a request against a key, returning how many seconds to wait if it has made too many this minute
+	pub fn check_rate(&self, key: &str) -> Result<(), u64> {
+		if self.limits.requests_per_minute == 0 {
+			return Ok(());
+		}
+
+		let mut requests = self.requests.lock().unwrap();
+		let now = now_seconds();
+
+		// Forget about windows that are over
+		requests.retain(|_, window| now.saturating_sub(window.start) < 60);
+
+		let window = requests.entry(key.to_string()).or_insert(RequestWindow { start: now, count: 0 });
+		if window.count >= self.limits.requests_per_minute {
+			return Err(60 - now.saturating_sub(window.start));
+		}
+		window.count += 1;
+		Ok(())
+	}
+}
+
+// Utility function to get the keys that failed attempts are counted against
+pub fn failure_keys(ip: Option<IpAddr>, username: Option<&str>) -> Vec<String> {
+	let mut keys: Vec<String> = ip.map(ip_key).into_iter().collect();
+	keys.push(account_key(username));
+	keys
+}
+
+// Utility function to get the key for an IP address
+pub fn ip_key(ip: IpAddr) -> String {
+	format!("ip:{}", ip)
+}
+
+// Utility function to get the key for an account, the server password counting as its own account
+pub fn account_key(username: Option<&str>) -> String {
+	match username {
+		Some(username) => format!("user:{}", username),
+		None => "server".to_string()
+	}
+}
+
+// Utility function to get the key for a public link, which wrong link passwords are counted against
+pub fn link_key(id: &str) -> String {
+	format!("link:{}", id)
+}
+
+// Kept in the request's local cache so the 429 catcher knows how long to tell the requester to wait
+pub struct RetryAfter(pub u64);
+
+// Responder for telling a requester to slow down
+pub struct TooManyRequests(pub u64);
+
+impl<'r> Responder<'r, 'static> for TooManyRequests {
+	fn respond_to(self, _req: &'r Request<'_>) -> response::Result<'static> {
+		Response::build()
+			.status(Status::TooManyRequests)
+			.raw_header("Retry-After", self.0.to_string())
+			.ok()
+	}
+}
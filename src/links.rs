@@ -0,0 +1,341 @@
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::limits::{RateLimiter, link_key};
+use crate::{ServerConfig, ServerSecret, hash_password, now_seconds, optional_query_value, random_token, verify_password};
+
+#[cfg(test)]
+mod tests {
+	use super::{LinkParams, LinkRequest, LinkStore};
+	use crate::limits::{LimitsConfig, RateLimiter};
+	use crate::{ServerConfig, ServerSecret};
+	use crate::test_dir::TestDir;
+	use rocket::http::Status;
+	use std::fs;
+	use std::path::Path;
+
+	// Utility function to get the parameters a link's URL comes with, along with the password sent for it
+	fn link_params(url: &str, password: Option<&str>) -> LinkParams {
+		let query: Vec<(&str, &str)> = url.split_once('?').unwrap().1.split('&').map(|pair| pair.split_once('=').unwrap()).collect();
+		let param = |name: &str| query.iter().find(|(key, _)| *key == name).unwrap().1.to_string();
+		LinkParams { link: param("link"), expires: param("expires").parse().unwrap(), signature: param("signature"), password: password.map(str::to_string) }
+	}
+
+	#[test]
+	fn create_and_redeem() {
+		let data_root = TestDir::new("links");
+		let secret = ServerSecret::load_or_create(&data_root);
+		let links = LinkStore::load(&data_root, RateLimiter::new(&LimitsConfig::default()));
+		let server_config: ServerConfig = toml::from_str(r#"
+			files_root = "server_files"
+			password = "testpass"
+			ignored_paths = []
+		"#).expect("Error parsing test server config");
+
+		// An expiry that doesn't fit is refused instead of wrapping around to a link that never worked
+		let request: LinkRequest = serde_json::from_str(r#"{ "expires_in": 18446744073709551615 }"#).unwrap();
+		assert_eq!(links.create(Path::new("docs/notes.txt"), &request, None, &secret).err(), Some(Status::BadRequest));
+
+		let request: LinkRequest = serde_json::from_str(r#"{ "expires_in": 60, "password": "linkpass", "max_downloads": 1 }"#).unwrap();
+		let url = links.create(Path::new("docs/notes.txt"), &request, None, &secret).unwrap().get_url().to_string();
+
+		// The password is only saved salted and hashed
+		let saved = fs::read_to_string(data_root.join("links.json")).unwrap();
+		assert!(!saved.contains("linkpass"));
+
+		// Only the linked file can be read, and only with the password
+		assert_eq!(links.redeem(&link_params(&url, None), Path::new("docs/notes.txt"), &secret, &server_config), Err(Status::Unauthorized));
+		assert_eq!(links.redeem(&link_params(&url, Some("wrongpass")), Path::new("docs/notes.txt"), &secret, &server_config), Err(Status::Unauthorized));
+		let params = link_params(&url, Some("linkpass"));
+		assert_eq!(links.redeem(&params, Path::new("docs/other.txt"), &secret, &server_config), Err(Status::Forbidden));
+		assert_eq!(links.redeem(&params, Path::new("docs/notes.txt"), &secret, &server_config), Ok(()));
+
+		// Redeeming doesn't use up a download, sending the file does, until it runs out
+		assert_eq!(links.redeem(&params, Path::new("docs/notes.txt"), &secret, &server_config), Ok(()));
+		assert_eq!(links.count_download(&params), Ok(()));
+		assert_eq!(links.count_download(&params), Err(Status::Gone));
+		assert_eq!(links.redeem(&params, Path::new("docs/notes.txt"), &secret, &server_config), Err(Status::Gone));
+	}
+
+	#[test]
+	fn directory_links() {
+		let data_root = TestDir::new("links_dir");
+		let secret = ServerSecret::load_or_create(&data_root);
+		let links = LinkStore::load(&data_root, RateLimiter::new(&LimitsConfig::default()));
+		let server_config: ServerConfig = toml::from_str(r#"
+			files_root = "server_files"
+			password = "testpass"
+			ignored_paths = []
+		"#).expect("Error parsing test server config");
+
+		// Every file under a linked directory can be read, and each one sent is a download
+		let request: LinkRequest = serde_json::from_str(r#"{ "expires_in": 60, "max_downloads": 2 }"#).unwrap();
+		let params = link_params(links.create(Path::new("builds"), &request, None, &secret).unwrap().get_url(), None);
+		assert_eq!(links.redeem(&params, Path::new("builds/app.zip"), &secret, &server_config), Ok(()));
+		assert_eq!(links.redeem(&params, Path::new("builds/old/app.zip"), &secret, &server_config), Ok(()));
+		assert_eq!(links.redeem(&params, Path::new("builds2/app.zip"), &secret, &server_config), Err(Status::Forbidden));
+		assert_eq!(links.count_download(&params), Ok(()));
+		assert_eq!(links.count_download(&params), Ok(()));
+		assert_eq!(links.redeem(&params, Path::new("builds/app.zip"), &secret, &server_config), Err(Status::Gone));
+	}
+
+	#[test]
+	fn password_lockout() {
+		let data_root = TestDir::new("links_lockout");
+		let secret = ServerSecret::load_or_create(&data_root);
+		let links = LinkStore::load(&data_root, RateLimiter::new(&LimitsConfig::default()));
+		let server_config: ServerConfig = toml::from_str(r#"
+			files_root = "server_files"
+			password = "testpass"
+			ignored_paths = []
+		"#).expect("Error parsing test server config");
+
+		// Too many wrong passwords lock the link, even for the right one
+		let request: LinkRequest = serde_json::from_str(r#"{ "expires_in": 60, "password": "linkpass" }"#).unwrap();
+		let url = links.create(Path::new("docs/notes.txt"), &request, None, &secret).unwrap().get_url().to_string();
+		for _ in 0..5 {
+			assert_eq!(links.redeem(&link_params(&url, Some("guess")), Path::new("docs/notes.txt"), &secret, &server_config), Err(Status::Unauthorized));
+		}
+		assert_eq!(links.redeem(&link_params(&url, Some("linkpass")), Path::new("docs/notes.txt"), &secret, &server_config), Err(Status::TooManyRequests));
+	}
+}
+
+// The header the password for a link is sent in, which keeps it out of URLs that end up in logs and browser history
+pub const LINK_PASSWORD_HEADER: &str = "link-password";
+
+// A structure for representing a public link to a file
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ShareLink {
+	path: PathBuf, // The virtual path the link gives access to
+	expires: u64, // When the link stops working
+	#[serde(alias = "password_digest")]
+	password_hash: Option<String>, // The link's password as made by hash_password, if it has one, links saved with an unsalted digest never match
+	max_downloads: Option<u64>, // How many downloads are allowed, if limited
+	downloads: u64, // How many downloads have happened
+	created_by: Option<String> // The user that made the link, None if it was the server password
+}
+
+impl ShareLink {
+
+	// Getters
+
+	pub fn get_path(&self) -> &Path {
+		&self.path
+	}
+
+	pub fn get_expires(&self) -> u64 {
+		self.expires
+	}
+
+	pub fn get_downloads(&self) -> u64 {
+		self.downloads
+	}
+
+	pub fn get_created_by(&self) -> Option<&str> {
+		self.created_by.as_deref()
+	}
+
+	// The message that gets signed for a link, tying its id, path and expiry together
+	fn signing_message(id: &str, path: &Path, expires: u64) -> String {
+		format!("{}\n{}\n{}", id, path.to_string_lossy(), expires)
+	}
+}
+
+// A structure for representing what a requester wants out of a new link
+#[derive(Deserialize)]
+pub struct LinkRequest {
+	expires_in: u64, // How many seconds the link should work for
+	password: Option<String>,
+	max_downloads: Option<u64>
+}
+
+// A structure for representing a newly created link
+#[derive(Serialize, Deserialize)]
+pub struct LinkResponse {
+	url: String,
+	expires: u64
+}
+
+impl LinkResponse {
+
+	// Getters
+
+	pub fn get_url(&self) -> &str {
+		&self.url
+	}
+
+	pub fn get_expires(&self) -> u64 {
+		self.expires
+	}
+}
+
+// The query parameters that come along with a link, and the password sent in its header
+pub struct LinkParams {
+	link: String,
+	expires: u64,
+	signature: String,
+	password: Option<String>
+}
+
+impl LinkParams {
+	// Constructor
+
+	fn from_query(req: &Request<'_>) -> Result<Option<Self>, Status> {
+		let link = match optional_query_value(req, "link")? {
+			Some(link) => link,
+			None => {
+				return Ok(None);
+			}
+		};
+		Ok(Some(Self {
+			link,
+			expires: optional_query_value(req, "expires")?.ok_or(Status::UnprocessableEntity)?,
+			signature: optional_query_value(req, "signature")?.ok_or(Status::UnprocessableEntity)?,
+			password: req.headers().get_one(LINK_PASSWORD_HEADER).map(str::to_string)
+		}))
+	}
+}
+
+// Requests without a link are forwarded, so routes can take an Option to serve both
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LinkParams {
+	type Error = ();
+
+	async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+		match Self::from_query(req) {
+			Ok(Some(params)) => Outcome::Success(params),
+			Ok(None) => Outcome::Forward(()),
+			Err(status) => Outcome::Failure((status, ()))
+		}
+	}
+}
+
+// A structure for keeping track of every link, saved as JSON in the data root
+pub struct LinkStore {
+	path: PathBuf,
+	links: Mutex<HashMap<String, ShareLink>>,
+	limiter: RateLimiter // Shared with the rest of the server, wrong link passwords are counted against the link
+}
+
+impl LinkStore {
+
+	// Constructor
+
+	pub fn load(data_root: &Path, limiter: RateLimiter) -> Self {
+		let path = data_root.join("links.json");
+
+		// Start with no links if the file doesn't exist yet
+		let links = match fs::read_to_string(&path) {
+			Ok(links_string) => serde_json::from_str(&links_string).expect("Could not parse saved links"),
+			Err(_) => HashMap::new()
+		};
+
+		Self {
+			path,
+			links: Mutex::new(links),
+			limiter
+		}
+	}
+
+	// Make a new link to a virtual path and return the URL for it, or the status to fail with if it can't expire when asked
+	pub fn create(&self, virtual_path: &Path, request: &LinkRequest, created_by: Option<&str>, secret: &ServerSecret) -> Result<LinkResponse, Status> {
+		let id = random_token(16);
+		let expires = now_seconds().checked_add(request.expires_in).ok_or(Status::BadRequest)?;
+
+		let link = ShareLink {
+			path: virtual_path.to_path_buf(),
+			expires,
+			password_hash: request.password.as_deref().map(hash_password),
+			max_downloads: request.max_downloads,
+			downloads: 0,
+			created_by: created_by.map(str::to_string)
+		};
+
+		// Sign the link so that none of its parts can be changed
+		let signature = secret.sign(&ShareLink::signing_message(&id, virtual_path, expires));
+		let url = format!("/file/{}?link={}&expires={}&signature={}", virtual_path.to_string_lossy().trim_start_matches('/'), id, expires, signature);
+
+		let mut links = self.links.lock().unwrap();
+		links.insert(id, link);
+		self.save(&links);
+
+		Ok(LinkResponse {
+			url,
+			expires
+		})
+	}
+
+	// Check that a link allows reading a virtual path, returning the status to fail with otherwise
+	// Nothing on disk is looked at, so a link can't be used to find out what exists without being opened properly
+	pub fn redeem(&self, params: &LinkParams, virtual_path: &Path, secret: &ServerSecret, server_config: &ServerConfig) -> Result<(), Status> {
+		// Find the link, which has to exist and match its signature
+		// The link is copied out so the lock isn't held while checking the password, which is slow on purpose
+		let link = self.links.lock().unwrap().get(&params.link).cloned().ok_or(Status::NotFound)?;
+		if params.expires != link.expires || !secret.verify(&ShareLink::signing_message(&params.link, &link.path, link.expires), &params.signature) {
+			return Err(Status::Unauthorized);
+		}
+
+		// The requested path has to be the linked file, or be inside the linked directory
+		if !virtual_path.starts_with(&link.path) {
+			return Err(Status::Forbidden);
+		}
+
+		// Links stop working once they expire or run out of downloads
+		let exhausted = link.max_downloads.is_some_and(|max| link.downloads >= max);
+		if now_seconds() > link.expires || exhausted {
+			return Err(Status::Gone);
+		}
+
+		// If the link has a password, it has to be given, and wrong ones count towards locking the link out
+		if let Some(password_hash) = &link.password_hash {
+			let keys = [link_key(&params.link)];
+			if self.limiter.locked_for(&keys).is_some() {
+				return Err(Status::TooManyRequests);
+			}
+			if !params.password.as_deref().is_some_and(|password| verify_password(password, password_hash)) {
+				self.limiter.record_failure(&keys);
+				return Err(Status::Unauthorized);
+			}
+			self.limiter.record_success(&keys);
+		}
+
+		// Whoever made the link has to still be able to read the path
+		if !server_config.get_access(link.get_created_by(), virtual_path).can_read() {
+			return Err(Status::Forbidden);
+		}
+		Ok(())
+	}
+
+	// Count a download through a link once the file is open, refusing it if other downloads used up the link in the meantime
+	pub fn count_download(&self, params: &LinkParams) -> Result<(), Status> {
+		let mut links = self.links.lock().unwrap();
+		let link = links.get_mut(&params.link).ok_or(Status::NotFound)?;
+		if link.max_downloads.is_some_and(|max| link.downloads >= max) {
+			return Err(Status::Gone);
+		}
+		link.downloads += 1;
+		self.save(&links);
+		Ok(())
+	}
+
+	// Remove links that have expired
+	pub fn prune(&self) {
+		let mut links = self.links.lock().unwrap();
+		let now = now_seconds();
+		links.retain(|_, link| link.expires >= now);
+		self.save(&links);
+	}
+
+	// Write the links out to disk
+	fn save(&self, links: &HashMap<String, ShareLink>) {
+		let links_string = serde_json::to_string_pretty(links).expect("Could not serialize links");
+		if let Err(e) = fs::write(&self.path, links_string) {
+			println!("Could not save links to {:?}: {}", self.path, e);
+		}
+	}
+}
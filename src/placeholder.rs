@@ -0,0 +1,95 @@
+use serde::{Serialize, Deserialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::FileInfo;
+
+#[cfg(test)]
+#[allow(clippy::items_after_test_module)] // The tests lead the module here too, ahead of the functions they cover
+mod tests {
+	use super::{placeholder_path, placeholder_target, read_placeholder, write_placeholder};
+	use crate::FileInfo;
+	use crate::test_dir::TestDir;
+	use std::fs;
+	use std::path::Path;
+
+	#[test]
+	fn stand_in_for_server_files() {
+		let root = TestDir::new("placeholder");
+		let client_path = root.join("video.mp4");
+		assert_eq!(placeholder_path(&client_path), root.join("video.mp4.skywriter"));
+		assert_eq!(placeholder_target(&root.join("video.mp4.skywriter")), None);
+		assert_eq!(placeholder_target(&root.join(".skywriterignore")), None);
+		assert_eq!(placeholder_target(&client_path), None);
+
+		// The placeholder keeps what the server had, under the client path
+		let server_file_info: FileInfo = serde_json::from_str(r#"{"path":"/archive/video.mp4","seconds":1700000000,"digest":"ABC","exists":true,"size":40000000000}"#).unwrap();
+		assert!(read_placeholder(&client_path).is_none());
+		write_placeholder(&client_path, &server_file_info).unwrap();
+		assert_eq!(placeholder_target(&root.join("video.mp4.skywriter")), Some(client_path.clone()));
+		let file_info = read_placeholder(&client_path).unwrap();
+		assert_eq!(file_info.get_path(), Path::new(&client_path));
+		assert_eq!(file_info.get_digest(), "ABC");
+		assert_eq!(file_info.get_size(), 40_000_000_000);
+		assert!(fs::metadata(placeholder_path(&client_path)).unwrap().len() < 1000);
+
+		// A file of the user's that only has the same extension isn't taken for a placeholder
+		let notes_path = root.join("notes");
+		fs::write(placeholder_path(&notes_path), r#"{"path":"/notes","seconds":1,"digest":"DEF","exists":true,"size":3}"#).unwrap();
+		assert_eq!(placeholder_target(&placeholder_path(&notes_path)), None);
+		assert!(read_placeholder(&notes_path).is_none());
+	}
+}
+
+// The extension added to the name of a file to get the name of its placeholder
+pub const PLACEHOLDER_EXTENSION: &str = "skywriter";
+
+// What a placeholder holds, marked so a file that only happens to have the extension isn't taken for one
+#[derive(Serialize, Deserialize)]
+struct Placeholder<T> {
+	skywriter_placeholder: u32, // The version of the placeholder format
+	#[serde(flatten)]
+	file_info: T
+}
+
+// The version of the placeholder format written now
+const PLACEHOLDER_VERSION: u32 = 1;
+
+// Utility function to get the path of the placeholder for a client file
+pub fn placeholder_path(client_path: &Path) -> PathBuf {
+	let mut path = client_path.as_os_str().to_owned();
+	path.push(".");
+	path.push(PLACEHOLDER_EXTENSION);
+	PathBuf::from(path)
+}
+
+// Utility function to get the client file a path is the placeholder for, going by the name alone
+// This is for paths that might be gone already, everything else should use placeholder_target
+pub fn placeholder_name_target(path: &Path) -> Option<PathBuf> {
+	(path.extension()? == PLACEHOLDER_EXTENSION).then(|| path.with_extension(""))
+}
+
+// Utility function to get the client file a path is the placeholder for, if it is one
+pub fn placeholder_target(path: &Path) -> Option<PathBuf> {
+	let target_path = placeholder_name_target(path)?;
+	read_placeholder(&target_path).map(|_| target_path)
+}
+
+// Utility function to write a placeholder for a client file, holding the server's information about it instead of its contents
+pub fn write_placeholder(client_path: &Path, server_file_info: &FileInfo) -> io::Result<()> {
+	let placeholder = Placeholder { skywriter_placeholder: PLACEHOLDER_VERSION, file_info: server_file_info };
+	let contents = serde_json::to_string_pretty(&placeholder).map_err(io::Error::from)?;
+	if let Some(parent_path) = client_path.parent() {
+		fs::create_dir_all(parent_path)?;
+	}
+	fs::write(placeholder_path(client_path), contents)
+}
+
+// Utility function to read the placeholder for a client file, giving what the server had when it was written as if it were the client's
+pub fn read_placeholder(client_path: &Path) -> Option<FileInfo> {
+	let contents = fs::read_to_string(placeholder_path(client_path)).ok()?;
+	let mut placeholder: Placeholder<FileInfo> = serde_json::from_str(&contents).ok()?;
+	placeholder.file_info.path = client_path.to_path_buf();
+	Some(placeholder.file_info)
+}
@@ -1,15 +1,93 @@
 #[macro_use] extern crate rocket;
 use rocket::fs::{NamedFile, TempFile};
 use rocket::http::uri::Segments;
-use rocket::http::Status;
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
+use rocket::response::content::RawHtml;
+use rocket::response::Redirect;
+use rocket::response::stream::{Event, EventStream};
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::error::RecvError;
 use rocket::form::Form;
-use rocket::State;
+use rocket::{Build, Rocket, Shutdown, State};
+use rocket::request::{FromRequest, Outcome, Request};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::vec;
 use rocket::serde::json::Json;
 use std::fs;
 
-use skywriter::{FileInfo, Config, ValidPassword};
+use skywriter::{FileInfo, Config, ServerSecret, ValidPassword, PasswordValidationError, check_credentials, move_path, random_token, virtual_path_from_str};
+use skywriter::audit::{AuditLog, AuditQuery, AuditRecord, Auditor};
+use skywriter::events::{ChangeEvent, ChangeFeed, ChangeNotifier, ChangeOperation, ClientId, DEFAULT_FEED_LIMIT};
+use skywriter::history;
+use skywriter::index::{FileIndex, default_hash_threads};
+use skywriter::limits::{RateLimiter, RetryAfter, TooManyRequests, failure_keys};
+use skywriter::links::{LinkStore, LinkParams, LinkRequest, LinkResponse};
+use skywriter::sessions::{SessionStore, SESSION_COOKIE};
+use skywriter::signing::{NonceStore, SignedJson};
+use skywriter::tokens::{TokenStore, TokenResponse};
+use skywriter::tls::{TlsConfig, TlsFiles};
+use skywriter::tree::TreeInfo;
+use skywriter::totp::{TotpStore, TotpProvisioning, TotpCode};
+
+// The binary is built against the library without its test modules, so it takes the same temporary directory guard from its file
+#[cfg(test)]
+#[path = "test_dir.rs"]
+mod test_dir;
+
+#[cfg(test)]
+mod tests {
+	use super::{rocket, LaunchConfig, MemoryState};
+	use rocket::http::{Header, Status};
+	use rocket::local::blocking::Client;
+	use skywriter::Config;
+	use std::fs;
+	use crate::test_dir::TestDir;
+
+	#[test]
+	fn move_onto_itself_or_a_directory() {
+		let root = TestDir::new("server_move");
+		let config: Config = toml::from_str(&format!(r#"
+			[server]
+			files_root = "{root}/files"
+			data_root = "{root}/data"
+			password = "testpass"
+			ignored_paths = []
+			[client]
+			server_url = "http://127.0.0.1:8000"
+			password = "testpass"
+			[client.mappings]
+			files = {{}}
+			dirs = {{}}
+		"#, root = root.display())).expect("Error parsing test config");
+		fs::create_dir_all(root.join("files/docs")).unwrap();
+		fs::write(root.join("files/notes.txt"), "notes").unwrap();
+		let memory = MemoryState::new(&config);
+		let client = Client::tracked(rocket(LaunchConfig { config, tls_files: None }, &memory)).unwrap();
+
+		// Both are refused before anything is archived, so the file stays where it was
+		for to in ["/notes.txt", "/docs"] {
+			let response = client.post(format!("/move/notes.txt?to={}", to)).header(Header::new("password", "testpass")).dispatch();
+			assert_eq!(response.status(), Status::UnprocessableEntity);
+			assert_eq!(fs::read_to_string(root.join("files/notes.txt")).unwrap(), "notes");
+		}
+
+		let response = client.post("/move/notes.txt?to=/docs/notes.txt").header(Header::new("password", "testpass")).dispatch();
+		assert_eq!(response.status(), Status::Created);
+		assert!(root.join("files/docs/notes.txt").is_file());
+	}
+}
+
+// The pages for the web interface
+const LOGIN_TEMPLATE: &str = include_str!("templates/login.html");
+const FILES_TEMPLATE: &str = include_str!("templates/files.html");
+
+// The directory in the data root that uploads are kept in until they are checked
+const UPLOADS_DIR: &str = "uploads";
 
 // Health check route
 #[get("/")]
@@ -17,22 +95,44 @@ async fn index() -> &'static str {
     "Skywriter Operational"
 }
 
-// Route for getting a file
+// Route for getting a file, either with a password or by following a public link
 #[get("/file/<virtual_path_segments..>")]
-async fn get_file(virtual_path_segments: Segments<'_, rocket::http::uri::fmt::Path>, config: &State<Config>, _password: ValidPassword) -> Result<NamedFile, Status> {
+async fn get_file(virtual_path_segments: Segments<'_, rocket::http::uri::fmt::Path>, link: Option<LinkParams>, config: &State<Config>, links: &State<LinkStore>, secret: &State<ServerSecret>, index: &State<FileIndex>, password: Result<ValidPassword, PasswordValidationError>) -> Result<NamedFile, Status> {
     // Turn the segments into PathBuf
     let virtual_path = virtual_path_segments.to_path_buf(true).unwrap();
 
+	// Anyone locked out or making too many requests is told to wait, even when following a link, return 429
+	let password = unless_limited(password)?;
+
+	// Make sure the link or the requester allows reading the path before anything on disk is looked at, return 401, 403, 404, 410 or 429 otherwise
+	match (&link, &password) {
+		(Some(link), _) => links.redeem(link, &virtual_path, secret, config.get_server_config())?,
+		(None, Some(password)) => {
+			if !password.get_access(config.get_server_config(), &virtual_path).can_read() {
+				return Err(Status::Forbidden);
+			}
+		},
+		(None, None) => {
+			return Err(Status::Unauthorized);
+		}
+	}
+
 	// Get the full path for the file based on the configured file root
-	let full_path = Path::new(config.get_server_config().get_files_root()).join(virtual_path);
+	let full_path = Path::new(config.get_server_config().get_files_root()).join(&virtual_path);
 
 	// Check to see if the given path could create a FileInfo struct, return 422 otherwise
-	match FileInfo::from_file_path(full_path) {
+	match FileInfo::from_indexed_file_path(full_path, index) {
 		Ok(file_info) => {
 			// If the file exists, return it, otherwise return 404
-			let ignored = config.get_server_config().get_ignored_paths().contains(&file_info.get_path().as_os_str());
+			let ignored = config.get_server_config().get_ignored_paths().is_ignored(&virtual_path, false);
 			if file_info.exists() && !ignored {
-				Ok(NamedFile::open(file_info.get_path()).await.unwrap())
+				let file = NamedFile::open(file_info.get_path()).await.map_err(|_| Status::NotFound)?;
+
+				// Following a link counts as a download for each file sent, so it is only counted once the file is open
+				if let Some(link) = &link {
+					links.count_download(link)?;
+				}
+				Ok(file)
 			} else {
 				Err(Status::NotFound)
 			}
@@ -43,30 +143,22 @@ async fn get_file(virtual_path_segments: Segments<'_, rocket::http::uri::fmt::Pa
 	}
 }
 
-// Structure for getting the uploaded file
-#[derive(FromForm)]
-pub struct FileUpload<'r> {
-	file: TempFile<'r>
-}
-
-impl<'r> FileUpload<'r> {
-	// Move the file out of the struct
-	pub fn take_file(self) -> TempFile<'r> {
-		self.file
-	}
-}
-
-// Route for uploading a file
+// Route for uploading a file, sent as the only part of a multipart form
 #[put("/file/<virtual_path_segments..>", data="<form>")]
-async fn put_file(virtual_path_segments: Segments<'_, rocket::http::uri::fmt::Path>, form: Form<FileUpload<'_>>, config: &State<Config>, _password: ValidPassword) -> Status {
+async fn put_file(virtual_path_segments: Segments<'_, rocket::http::uri::fmt::Path>, form: Form<TempFile<'_>>, config: &State<Config>, changes: &State<ChangeNotifier>, index: &State<FileIndex>, client_id: ClientId, password: ValidPassword) -> Status {
     // Turn the segments into PathBuf
     let virtual_path = virtual_path_segments.to_path_buf(true).unwrap();
 
+	// Make sure the requester is allowed to write the path, return 403 otherwise
+	if !password.get_access(config.get_server_config(), &virtual_path).can_write() {
+		return Status::Forbidden;
+	}
+
 	// Get the full path for the file based on the configured file root
-	let full_path = Path::new(config.get_server_config().get_files_root()).join(virtual_path);
+	let full_path = Path::new(config.get_server_config().get_files_root()).join(&virtual_path);
 
 	// Check to see if we should ignore it
-	let ignored = config.get_server_config().get_ignored_paths().contains(&full_path.as_path().as_os_str());
+	let ignored = config.get_server_config().get_ignored_paths().is_ignored(&virtual_path, false);
 	if ignored {
 		return Status::NoContent;
 	}
@@ -77,12 +169,39 @@ async fn put_file(virtual_path_segments: Segments<'_, rocket::http::uri::fmt::Pa
 			// Try to create that parent path if it doesn't exist, return 403 otherwise
 			match fs::create_dir_all(parent_path) {
 				Ok(()) => {
-					// Try to get the uploaded file and save it to full_path, return 500 otherwise
-					match form.into_inner().take_file().persist_to(full_path).await {
+					// Try to get the uploaded file and save it somewhere out of the way first, return 500 otherwise
+					let uploads_path = Path::new(config.get_server_config().get_data_root()).join(UPLOADS_DIR);
+					let staging_path = uploads_path.join(random_token(16));
+					let mut file = form.into_inner();
+					if fs::create_dir_all(&uploads_path).is_err() {
+						return Status::InternalServerError;
+					}
+
+					// Persisting renames the file, which can't cross filesystems, so fall back to copying it and removing the original
+					if file.persist_to(&staging_path).await.is_err() && file.move_copy_to(&staging_path).await.is_err() {
+						return Status::InternalServerError;
+					}
+
+					// If the request was signed, the upload has to be the file that was signed, return 400 otherwise
+					if let Some(body_digest) = password.get_body_digest() {
+						let matches = FileInfo::from_file_path(staging_path.clone()).is_ok_and(|fi| fi.get_digest() == body_digest);
+						if !matches {
+							let _ = fs::remove_file(&staging_path);
+							return Status::BadRequest;
+						}
+					}
+
+					// Keep the version that is about to be overwritten and move the upload into place, return 500 if unable
+					let saved = history::archive(config.get_server_config(), &virtual_path)
+						.and_then(|()| move_path(&staging_path, &full_path));
+					match saved {
 						Ok(()) => {
+							let digest = FileInfo::from_indexed_file_path(full_path, index).ok().map(|file_info| file_info.get_digest().to_string());
+							changes.publish(ChangeEvent::new(ChangeOperation::Put, &virtual_path, None, digest, client_id.0));
 							Status::Created
 						},
 						Err(_) => {
+							let _ = fs::remove_file(&staging_path);
 							Status::InternalServerError
 						}
 					}
@@ -98,17 +217,170 @@ async fn put_file(virtual_path_segments: Segments<'_, rocket::http::uri::fmt::Pa
 	}
 }
 
+// Route for deleting a file, which keeps it in the file's history
+#[delete("/file/<virtual_path_segments..>")]
+async fn delete_file(virtual_path_segments: Segments<'_, rocket::http::uri::fmt::Path>, config: &State<Config>, changes: &State<ChangeNotifier>, index: &State<FileIndex>, client_id: ClientId, password: ValidPassword) -> Status {
+    // Turn the segments into PathBuf
+    let virtual_path = virtual_path_segments.to_path_buf(true).unwrap();
+
+	// Make sure the requester is allowed to write the path, return 403 otherwise
+	if !password.get_access(config.get_server_config(), &virtual_path).can_write() {
+		return Status::Forbidden;
+	}
+
+	// Get the full path for the file based on the configured file root
+	let full_path = Path::new(config.get_server_config().get_files_root()).join(&virtual_path);
+
+	// Only delete files that exist and aren't ignored, return 404 otherwise
+	let ignored = config.get_server_config().get_ignored_paths().is_ignored(&virtual_path, false);
+	if !full_path.is_file() || ignored {
+		return Status::NotFound;
+	}
+
+	// Move the file into its history, return 500 if unable
+	match history::archive(config.get_server_config(), &virtual_path) {
+		Ok(()) => {
+			index.forget(&full_path);
+			changes.publish(ChangeEvent::new(ChangeOperation::Delete, &virtual_path, None, None, client_id.0));
+			Status::NoContent
+		},
+		Err(_) => {
+			Status::InternalServerError
+		}
+	}
+}
+
+// Route for moving a file to another virtual path
+#[post("/move/<virtual_path_segments..>?<to>")]
+async fn move_file(virtual_path_segments: Segments<'_, rocket::http::uri::fmt::Path>, to: &str, config: &State<Config>, changes: &State<ChangeNotifier>, index: &State<FileIndex>, client_id: ClientId, password: ValidPassword) -> Status {
+    // Turn the segments into PathBuf
+    let virtual_path = virtual_path_segments.to_path_buf(true).unwrap();
+
+	// Make sure the destination is a sensible virtual path, return 422 otherwise
+	let destination_path = match virtual_path_from_str(to) {
+		Some(destination_path) => destination_path,
+		None => {
+			return Status::UnprocessableEntity;
+		}
+	};
+
+	// Make sure the requester is allowed to write both paths, return 403 otherwise
+	let server_config = config.get_server_config();
+	if !password.get_access(server_config, &virtual_path).can_write() || !password.get_access(server_config, &destination_path).can_write() {
+		return Status::Forbidden;
+	}
+
+	// Get the full paths for both files based on the configured file root
+	let full_path = Path::new(server_config.get_files_root()).join(&virtual_path);
+	let full_destination_path = Path::new(server_config.get_files_root()).join(&destination_path);
+
+	// Only move files that exist and aren't ignored to places that aren't ignored, return 404 otherwise
+	let ignored_paths = server_config.get_ignored_paths();
+	if !full_path.is_file() || ignored_paths.is_ignored(&virtual_path, false) || ignored_paths.is_ignored(&destination_path, false) {
+		return Status::NotFound;
+	}
+
+	// Moving a file onto itself or over a directory would archive it and then fail to move it, return 422
+	if destination_path == virtual_path || full_destination_path.is_dir() {
+		return Status::UnprocessableEntity;
+	}
+
+	// Keep whatever is about to be overwritten, create the destination's parent and move the file, return 500 if unable
+	let moved = history::archive(server_config, &destination_path)
+		.and_then(|()| fs::create_dir_all(full_destination_path.parent().unwrap_or(Path::new(""))))
+		.and_then(|()| fs::rename(&full_path, &full_destination_path));
+	match moved {
+		Ok(()) => {
+			index.forget(&full_path);
+			let digest = FileInfo::from_indexed_file_path(full_destination_path, index).ok().map(|file_info| file_info.get_digest().to_string());
+			changes.publish(ChangeEvent::new(ChangeOperation::Move, &destination_path, Some(&virtual_path), digest, client_id.0));
+			Status::Created
+		},
+		Err(_) => {
+			Status::InternalServerError
+		}
+	}
+}
+
+// Route for getting the information of every old version of a file
+#[get("/info/history/<virtual_path_segments..>")]
+async fn get_history_info(virtual_path_segments: Segments<'_, rocket::http::uri::fmt::Path>, config: &State<Config>, index: &State<FileIndex>, password: ValidPassword) -> Result<Json<Vec<FileInfo>>, Status> {
+    // Turn the segments into PathBuf
+    let virtual_path = virtual_path_segments.to_path_buf(true).unwrap();
+
+	// Make sure the requester is allowed to read the path, return 403 otherwise
+	if !password.get_access(config.get_server_config(), &virtual_path).can_read() {
+		return Err(Status::Forbidden);
+	}
+
+	// Ignored files have no history as far as anyone asking is concerned, return 404
+	if config.get_server_config().get_ignored_paths().is_ignored(&virtual_path, false) {
+		return Err(Status::NotFound);
+	}
+
+	// Only files have a history, the history of a directory would be the histories of everything in it, return 422
+	if Path::new(config.get_server_config().get_files_root()).join(&virtual_path).is_dir() {
+		return Err(Status::UnprocessableEntity);
+	}
+
+	// Check to see if the history could create a vector of FileInfo structs, return 422 otherwise
+	// The histories of files that were in a directory that had the same path are kept inside it, so they aren't looked at
+	let history_path = history::history_path(config.get_server_config(), &virtual_path);
+	match FileInfo::from_indexed_dir_path(history_path.as_path(), index, &|_, is_dir| !is_dir) {
+		Ok(mut file_infos) => {
+			// Strip the history prefix so each path is just the version, and put the newest first
+			file_infos.iter_mut().for_each(|fi| fi.strip_prefix(&history_path).unwrap());
+			file_infos.retain(|fi| history::parse_version(&fi.get_path().to_string_lossy()).is_some());
+			file_infos.sort_by_key(|fi| std::cmp::Reverse(history::parse_version(&fi.get_path().to_string_lossy())));
+			Ok(Json(file_infos))
+		},
+		Err(_) => {
+			Err(Status::UnprocessableEntity)
+		}
+	}
+}
+
+// Route for getting an old version of a file
+#[get("/history/<version>/<virtual_path_segments..>")]
+async fn get_history_file(version: &str, virtual_path_segments: Segments<'_, rocket::http::uri::fmt::Path>, config: &State<Config>, password: ValidPassword) -> Result<NamedFile, Status> {
+    // Turn the segments into PathBuf
+    let virtual_path = virtual_path_segments.to_path_buf(true).unwrap();
+
+	// Make sure the requester is allowed to read the path, return 403 otherwise
+	if !password.get_access(config.get_server_config(), &virtual_path).can_read() {
+		return Err(Status::Forbidden);
+	}
+
+	// Return the version if there is one and the file isn't ignored, otherwise return 404
+	if config.get_server_config().get_ignored_paths().is_ignored(&virtual_path, false) {
+		return Err(Status::NotFound);
+	}
+	let version_path = history::version_path(config.get_server_config(), &virtual_path, version).ok_or(Status::NotFound)?;
+	NamedFile::open(version_path).await
+		.map_err(|_| Status::NotFound)
+}
+
 // Route for getting a file's information
 #[get("/info/file/<virtual_path_segments..>")]
-async fn get_file_info(virtual_path_segments: Segments<'_, rocket::http::uri::fmt::Path>, config: &State<Config>, _password: ValidPassword) -> Result<Json<FileInfo>, Status> {
+async fn get_file_info(virtual_path_segments: Segments<'_, rocket::http::uri::fmt::Path>, config: &State<Config>, index: &State<FileIndex>, password: ValidPassword) -> Result<Json<FileInfo>, Status> {
     // Turn the segments into PathBuf
     let virtual_path = virtual_path_segments.to_path_buf(true).unwrap();
 
+	// Make sure the requester is allowed to read the path, return 403 otherwise
+	if !password.get_access(config.get_server_config(), &virtual_path).can_read() {
+		return Err(Status::Forbidden);
+	}
+
+	// Ignored files aren't there as far as anyone asking is concerned, return 404
+	if config.get_server_config().get_ignored_paths().is_ignored(&virtual_path, false) {
+		return Err(Status::NotFound);
+	}
+
 	// Get the full path for the file based on the configured file root
 	let full_path = Path::new(config.get_server_config().get_files_root()).join(virtual_path);
 
 	// Check to see if the given path could create a FileInfo struct, return 422 otherwise
-	match FileInfo::from_file_path(full_path) {
+	match FileInfo::from_indexed_file_path(full_path, index) {
 		Ok(mut file_info) => {
 			// Strip the server's file root prefix from file_info and return it as JSON
 			file_info.strip_prefix(config.get_server_config().get_files_root()).unwrap();
@@ -121,18 +393,36 @@ async fn get_file_info(virtual_path_segments: Segments<'_, rocket::http::uri::fm
 }
 
 #[get("/info/dir/<virtual_path_segments..>")]
-async fn get_dir_info(virtual_path_segments: Segments<'_, rocket::http::uri::fmt::Path>, config: &State<Config>, _password: ValidPassword) -> Result<Json<Vec<FileInfo>>, Status> {
+async fn get_dir_info(virtual_path_segments: Segments<'_, rocket::http::uri::fmt::Path>, config: &State<Config>, index: &State<FileIndex>, password: ValidPassword) -> Result<Json<Vec<FileInfo>>, Status> {
     // Turn the segments into PathBuf
     let virtual_path = virtual_path_segments.to_path_buf(true).unwrap();
 
+	// Ignored directories aren't there as far as anyone asking is concerned, return 404
+	let server_config = config.get_server_config();
+	if server_config.get_ignored_paths().is_ignored(&virtual_path, true) {
+		return Err(Status::NotFound);
+	}
+
 	// Get the full path for the file based on the configured file root
-	let full_path = Path::new(config.get_server_config().get_files_root()).join(virtual_path);
+	let full_path = Path::new(server_config.get_files_root()).join(&virtual_path);
+
+	// Leave out ignored files, and don't look inside ignored directories at all
+	let not_ignored = |path: &Path, is_dir: bool| path.strip_prefix(&full_path)
+		.map_or(true, |relative_path| !server_config.get_ignored_paths().is_ignored(&virtual_path.join(relative_path), is_dir));
 
 	// Check to see if the given path could create a vector of FileInfo structs, return 422 otherwise
-	match FileInfo::from_dir_path(full_path.as_path()) {
+	match FileInfo::from_indexed_dir_path(full_path.as_path(), index, &not_ignored) {
 		Ok(mut file_infos) => {
 			// Strip the server's file root prefix from file_infos and return it as JSON
 			file_infos.iter_mut().for_each(|fi| fi.strip_prefix(&full_path).unwrap());
+
+			// Only keep the files the requester is allowed to read, return 403 if the directory itself is off limits and nothing is left
+			let dir_access = password.get_access(server_config, &virtual_path);
+			file_infos.retain(|fi| password.get_access(server_config, &virtual_path.join(fi.get_path())).can_read());
+			if !dir_access.can_read() && file_infos.is_empty() {
+				return Err(Status::Forbidden);
+			}
+
 			Ok(Json(file_infos))
 		},
 		Err(_) => {
@@ -141,16 +431,421 @@ async fn get_dir_info(virtual_path_segments: Segments<'_, rocket::http::uri::fmt
 	}
 }
 
-#[launch]
-fn rocket() -> _ {
-	let config = Config::from_file("Config.toml");
-	if config.get_server_config().get_password() == "testpass" {
+// Route for getting the tree of digests under a directory, down to a depth that defaults to 1
+#[get("/info/tree/<virtual_path_segments..>?<depth>")]
+async fn get_tree_info(virtual_path_segments: Segments<'_, rocket::http::uri::fmt::Path>, depth: Option<u32>, config: &State<Config>, index: &State<FileIndex>, password: ValidPassword) -> Result<Json<TreeInfo>, Status> {
+    // Turn the segments into PathBuf
+    let virtual_path = virtual_path_segments.to_path_buf(true).unwrap();
+
+	// Ignored directories aren't there as far as anyone asking is concerned, return 404
+	let server_config = config.get_server_config();
+	if server_config.get_ignored_paths().is_ignored(&virtual_path, true) {
+		return Err(Status::NotFound);
+	}
+
+	// Get the full path for the directory based on the configured file root
+	let full_path = Path::new(server_config.get_files_root()).join(&virtual_path);
+
+	// Only the files the requester is allowed to read go into the tree, though any directory could have a readable share in it, and nothing ignored does
+	let readable = |relative_path: &Path, is_dir: bool| {
+		let path = virtual_path.join(relative_path);
+		!server_config.get_ignored_paths().is_ignored(&path, is_dir) && (is_dir || password.get_access(server_config, &path).can_read())
+	};
+
+	// Check to see if the given path could create a tree, return 422 otherwise
+	match TreeInfo::from_dir_path(&full_path, depth.unwrap_or(1), &readable, Some(index), false) {
+		Ok(tree_info) => {
+			// Return 403 if the directory itself is off limits and nothing is left
+			if !password.get_access(server_config, &virtual_path).can_read() && tree_info.get_files() == 0 {
+				return Err(Status::Forbidden);
+			}
+			Ok(Json(tree_info))
+		},
+		Err(_) => {
+			Err(Status::UnprocessableEntity)
+		}
+	}
+}
+
+// Route for creating a public link to a file or directory
+#[post("/link/<virtual_path_segments..>", data="<request>")]
+async fn create_link(virtual_path_segments: Segments<'_, rocket::http::uri::fmt::Path>, request: SignedJson<LinkRequest>, config: &State<Config>, links: &State<LinkStore>, secret: &State<ServerSecret>, password: ValidPassword) -> Result<Json<LinkResponse>, Status> {
+    // Turn the segments into PathBuf
+    let virtual_path = virtual_path_segments.to_path_buf(true).unwrap();
+
+	// Make sure the requester is allowed to read the path, return 403 otherwise
+	if !password.get_access(config.get_server_config(), &virtual_path).can_read() {
+		return Err(Status::Forbidden);
+	}
+
+	// Only make links to things that exist and aren't ignored, return 404 otherwise
+	let full_path = Path::new(config.get_server_config().get_files_root()).join(&virtual_path);
+	if !full_path.exists() || config.get_server_config().get_ignored_paths().is_ignored(&virtual_path, full_path.is_dir()) {
+		return Err(Status::NotFound);
+	}
+
+	// An expiry too far away to keep track of is refused, return 400
+	links.create(&virtual_path, &request, password.get_username(), secret).map(Json)
+}
+
+// Route for the login page
+#[get("/login")]
+async fn login_page() -> RawHtml<&'static str> {
+	RawHtml(LOGIN_TEMPLATE)
+}
+
+// Route for logging in from the browser, which starts a session and sends its cookie
+#[post("/login", data="<form>")]
+async fn login(form: Form<HashMap<&str, &str>>, ip: Option<IpAddr>, config: &State<Config>, sessions: &State<SessionStore>, totp: &State<TotpStore>, limiter: &State<RateLimiter>, cookies: &CookieJar<'_>) -> Result<Redirect, AuthFailure> {
+	// The login page sends a username, password and code, and the password can't be left out, return 422 otherwise
+	let password = form.get("password").copied().ok_or(AuthFailure::Status(Status::UnprocessableEntity))?;
+
+	// An empty username means the server password is being used, and an empty code means none was given
+	let username = form.get("username").copied().filter(|username| !username.is_empty());
+	let code = form.get("code").copied().filter(|code| !code.is_empty());
+
+	// Check the credentials, showing the login page again if they are wrong
+	match check_limited_credentials(limiter, config, totp, ip, username, password, code) {
+		Ok(()) => {
+			let session_id = sessions.create(username);
+			cookies.add(Cookie::build(SESSION_COOKIE, session_id).path("/").http_only(true).same_site(SameSite::Strict).finish());
+			Ok(Redirect::to(uri!(files_page)))
+		},
+		Err(AuthFailure::Status(status)) => {
+			Err(AuthFailure::Page((status, RawHtml(LOGIN_TEMPLATE))))
+		},
+		Err(e) => {
+			Err(e)
+		}
+	}
+}
+
+// Route for logging out from the browser
+#[post("/logout")]
+async fn logout(sessions: &State<SessionStore>, cookies: &CookieJar<'_>) -> Redirect {
+	if let Some(cookie) = cookies.get(SESSION_COOKIE) {
+		sessions.remove(cookie.value());
+	}
+	cookies.remove(Cookie::named(SESSION_COOKIE));
+	Redirect::to(uri!(login_page))
+}
+
+// Route for the file manager, which sends the browser to the login page if it isn't logged in
+#[get("/files")]
+async fn files_page(password: Result<ValidPassword, PasswordValidationError>) -> Result<Result<RawHtml<&'static str>, Redirect>, Status> {
+	Ok(match unless_limited(password)? {
+		Some(_) => Ok(RawHtml(FILES_TEMPLATE)),
+		None => Err(Redirect::to(uri!(login_page)))
+	})
+}
+
+// Utility function for routes that work without credentials, which still refuse anyone locked out or making too many requests
+// The 429 catcher adds how long they have to wait
+fn unless_limited(password: Result<ValidPassword, PasswordValidationError>) -> Result<Option<ValidPassword>, Status> {
+	match password {
+		Ok(password) => Ok(Some(password)),
+		Err(PasswordValidationError::LockedOut | PasswordValidationError::RateLimited) => Err(Status::TooManyRequests),
+		Err(_) => Ok(None)
+	}
+}
+
+// Responder for when checking credentials fails
+#[derive(Responder)]
+pub enum AuthFailure {
+	Status(Status),
+	Page((Status, RawHtml<&'static str>)),
+	LockedOut(TooManyRequests)
+}
+
+// Check credentials from the login page or token minting, counting failures and refusing anyone that is locked out
+fn check_limited_credentials(limiter: &RateLimiter, config: &Config, totp: &TotpStore, ip: Option<IpAddr>, username: Option<&str>, password: &str, code: Option<&str>) -> Result<(), AuthFailure> {
+	let keys = failure_keys(ip, username);
+	if let Some(retry_after) = limiter.locked_for(&keys) {
+		return Err(AuthFailure::LockedOut(TooManyRequests(retry_after)));
+	}
+
+	match check_credentials(config.get_server_config(), totp, username, password, code) {
+		Ok(()) => {
+			limiter.record_success(&keys);
+			Ok(())
+		},
+		Err(_) => {
+			limiter.record_failure(&keys);
+			Err(AuthFailure::Status(Status::Unauthorized))
+		}
+	}
+}
+
+// Catcher for requests that were refused for being too many, telling the requester how long to wait
+#[catch(429)]
+fn too_many_requests(req: &Request) -> TooManyRequests {
+	TooManyRequests(req.local_cache(|| RetryAfter(1)).0)
+}
+
+// Request guard for the credentials sent in headers when minting or revoking a token
+pub struct CredentialHeaders<'r> {
+	username: Option<&'r str>,
+	password: Option<&'r str>,
+	totp: Option<&'r str>,
+	token: Option<&'r str>
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CredentialHeaders<'r> {
+	type Error = Infallible;
+
+	async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+		let headers = req.headers();
+		Outcome::Success(Self {
+			username: headers.get_one("username"),
+			password: headers.get_one("password"),
+			totp: headers.get_one("totp"),
+			token: headers.get_one("token")
+		})
+	}
+}
+
+// Route for minting an API token, which takes a two-factor code in the 'totp' header for users that have it turned on
+#[post("/token")]
+async fn mint_token(credentials: CredentialHeaders<'_>, ip: Option<IpAddr>, config: &State<Config>, tokens: &State<TokenStore>, totp: &State<TotpStore>, limiter: &State<RateLimiter>) -> Result<Json<TokenResponse>, AuthFailure> {
+	// Make sure a password was given, return 401 otherwise
+	let password = credentials.password.ok_or(AuthFailure::Status(Status::Unauthorized))?;
+
+	// Check the credentials, return 401 if they are wrong or 429 if locked out
+	check_limited_credentials(limiter, config, totp, ip, credentials.username, password, credentials.totp)?;
+
+	Ok(Json(tokens.mint(credentials.username)))
+}
+
+// Route for revoking the API token in the 'token' header
+#[delete("/token")]
+async fn revoke_token(credentials: CredentialHeaders<'_>, tokens: &State<TokenStore>) -> Status {
+	match credentials.token {
+		Some(token) => {
+			tokens.revoke(token);
+			Status::NoContent
+		},
+		None => {
+			Status::UnprocessableEntity
+		}
+	}
+}
+
+// Route for starting two-factor enrollment, which only user accounts without it already on can do
+#[post("/totp/enroll")]
+async fn enroll_totp(totp: &State<TotpStore>, password: ValidPassword) -> Result<Json<TotpProvisioning>, Status> {
+	let username = password.get_username().ok_or(Status::UnprocessableEntity)?;
+	totp.enroll(username).map(Json).ok_or(Status::Conflict)
+}
+
+// Route for confirming two-factor enrollment with a code, after which it is enforced
+#[post("/totp/confirm", data="<code>")]
+async fn confirm_totp(code: SignedJson<TotpCode>, totp: &State<TotpStore>, password: ValidPassword) -> Status {
+	match password.get_username() {
+		Some(username) if totp.confirm(username, code.get_code()) => Status::NoContent,
+		Some(_) => Status::Unauthorized,
+		None => Status::UnprocessableEntity
+	}
+}
+
+// Route for turning two-factor authentication off with a code or recovery code
+#[post("/totp/disable", data="<code>")]
+async fn disable_totp(code: SignedJson<TotpCode>, totp: &State<TotpStore>, password: ValidPassword) -> Status {
+	match password.get_username() {
+		Some(username) if totp.disable(username, code.get_code()) => Status::NoContent,
+		Some(_) => Status::Unauthorized,
+		None => Status::UnprocessableEntity
+	}
+}
+
+// Route for searching the audit log, which only the server password can do
+#[get("/admin/audit")]
+async fn get_audit(query: AuditQuery, audit_log: &State<AuditLog>, password: ValidPassword) -> Result<Json<Vec<AuditRecord>>, Status> {
+	match password.get_username() {
+		Some(_) => Err(Status::Forbidden),
+		None => Ok(Json(audit_log.query(&query)))
+	}
+}
+
+// Route for following changes to files as server-sent events, only those under the given prefixes if any are given
+// Subscribers that fall too far behind get a resync event, since they can no longer trust what they've seen
+#[get("/events?<prefix>")]
+async fn get_events<'r>(prefix: Vec<String>, config: &'r State<Config>, changes: &State<ChangeNotifier>, password: ValidPassword, mut shutdown: Shutdown) -> EventStream![Event + 'r] {
+	let prefixes: Vec<PathBuf> = prefix.iter().filter_map(|prefix| virtual_path_from_str(prefix)).collect();
+	let mut receiver = changes.subscribe();
+
+	EventStream! {
+		loop {
+			let change = select! {
+				change = receiver.recv() => change,
+				_ = &mut shutdown => break
+			};
+
+			match change {
+				Ok(change) => {
+					if is_wanted_change(&change, &prefixes, config, &password) {
+						yield Event::json(&change).event("change");
+					}
+				},
+				Err(RecvError::Lagged(_)) => {
+					yield Event::empty().event("resync");
+				},
+				Err(RecvError::Closed) => break
+			}
+		}
+	}
+}
+
+// Route for getting the changes to files after a cursor, only those under the given prefixes if any are given
+#[get("/changes?<since>&<prefix>&<limit>")]
+async fn get_changes(since: Option<u64>, prefix: Vec<String>, limit: Option<usize>, config: &State<Config>, changes: &State<ChangeNotifier>, password: ValidPassword) -> Json<ChangeFeed> {
+	let prefixes: Vec<PathBuf> = prefix.iter().filter_map(|prefix| virtual_path_from_str(prefix)).collect();
+
+	Json(changes.changes_since(since, limit.unwrap_or(DEFAULT_FEED_LIMIT), |change| is_wanted_change(change, &prefixes, config, &password)))
+}
+
+// Check if a change is under the prefixes someone asked for, all of them if there are none, and that they are allowed to see it and it isn't ignored
+fn is_wanted_change(change: &ChangeEvent, prefixes: &[PathBuf], config: &Config, password: &ValidPassword) -> bool {
+	let server_config = config.get_server_config();
+	change.get_paths().any(|path| prefixes.is_empty() || prefixes.iter().any(|prefix| path.starts_with(prefix)))
+		&& change.get_paths().all(|path| password.get_access(server_config, path).can_read() && !server_config.get_ignored_paths().is_ignored(path, false))
+}
+
+// The state that is only kept in memory, made once so relaunching with new certificates doesn't log everyone out or let anyone off a lockout
+struct MemoryState {
+	sessions: SessionStore,
+	nonces: NonceStore,
+	limiter: RateLimiter
+}
+
+impl MemoryState {
+
+	// Constructor
+
+	fn new(config: &Config) -> Self {
+		Self {
+			sessions: SessionStore::default(),
+			nonces: NonceStore::default(),
+			limiter: RateLimiter::new(config.get_server_config().get_limits())
+		}
+	}
+}
+
+// What the server is launched with, kept so it can go back to the last one that worked
+#[derive(Clone)]
+struct LaunchConfig {
+	config: Config,
+	tls_files: Option<TlsFiles>
+}
+
+impl LaunchConfig {
+
+	// Constructor
+
+	fn read(config_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+		let config = Config::load(config_path)?;
+		let tls_files = config.get_server_config().get_tls().map(TlsConfig::read_files).transpose()?;
+		Ok(Self {
+			config,
+			tls_files
+		})
+	}
+}
+
+// Build the server out of its config and the state saved in the data root
+fn rocket(launch_config: LaunchConfig, memory: &MemoryState) -> Rocket<Build> {
+	let LaunchConfig { config, tls_files } = launch_config;
+	let data_root = Path::new(config.get_server_config().get_data_root()).to_path_buf();
+	fs::create_dir_all(&data_root).expect("Could not create data root");
+	let secret = ServerSecret::load_or_create(&data_root);
+	let links = LinkStore::load(&data_root, memory.limiter.clone());
+	links.prune();
+	let tokens = TokenStore::load(&data_root);
+	let totp = TotpStore::load(&data_root);
+	let audit_log = AuditLog::new(&data_root, config.get_server_config().get_audit());
+	let changes = ChangeNotifier::load(&data_root);
+	let index = FileIndex::load(&data_root, default_hash_threads());
+
+	// Serve over TLS if it is configured
+	let figment = match (config.get_server_config().get_tls(), &tls_files) {
+		(Some(tls_config), Some(tls_files)) => tls_config.figment(rocket::Config::figment(), tls_files),
+		_ => rocket::Config::figment()
+	};
+
+	rocket::custom(figment)
+		.manage(config)
+		.manage(secret)
+		.manage(links)
+		.manage(memory.sessions.clone())
+		.manage(tokens)
+		.manage(totp)
+		.manage(memory.limiter.clone())
+		.manage(memory.nonces.clone())
+		.manage(audit_log)
+		.manage(changes)
+		.manage(index)
+		.attach(Auditor)
+		.mount("/", routes![index, get_file, put_file, delete_file, move_file, get_file_info, get_dir_info, get_tree_info, get_history_info, get_history_file, create_link])
+		.mount("/", routes![login_page, login, logout, files_page])
+		.mount("/", routes![mint_token, revoke_token, enroll_totp, confirm_totp, disable_totp])
+		.mount("/", routes![get_audit, get_events, get_changes])
+		.register("/", catchers![too_many_requests])
+}
+
+// Launch the server and wait for it to stop, giving whether it stopped because the certificates changed
+async fn serve(launch_config: LaunchConfig, memory: &MemoryState) -> Result<bool, rocket::Error> {
+	let tls_config = launch_config.config.get_server_config().get_tls().cloned();
+	let rocket = rocket(launch_config, memory).ignite().await?;
+
+	let reloading = Arc::new(AtomicBool::new(false));
+	if let Some(tls_config) = tls_config {
+		rocket::tokio::spawn(tls_config.watch(rocket.shutdown(), reloading.clone()));
+	}
+
+	let _ = rocket.launch().await?;
+	Ok(reloading.load(Ordering::SeqCst))
+}
+
+#[rocket::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+	let mut launch_config = LaunchConfig::read("Config.toml")?;
+	if launch_config.config.get_server_config().get_password() == "testpass" {
 		println!();
 		println!("DEFAULT PASSWORD DETECTED!");
 		println!("Make sure you change the password from the default.");
 		println!();
 	}
-	rocket::build()
-		.manage(config)
-		.mount("/", routes![index, get_file, put_file, get_file_info, get_dir_info])
+	let memory = MemoryState::new(&launch_config.config);
+
+	// Rocket can't swap certificates while it is running, so it gets launched again whenever they change
+	// If it can't be launched with the changed ones, like when they are only half written, it goes back to the ones that worked
+	let mut last_working: Option<LaunchConfig> = None;
+	loop {
+		match serve(launch_config.clone(), &memory).await {
+			// Anything other than the certificates changing means the server was asked to stop
+			Ok(false) => {
+				return Ok(());
+			},
+			Ok(true) => {
+				println!("TLS certificates changed, relaunching");
+				match LaunchConfig::read("Config.toml") {
+					Ok(changed) => {
+						last_working = Some(std::mem::replace(&mut launch_config, changed));
+					},
+					Err(e) => {
+						println!("Could not read the changed config or certificates, keeping the old ones: {}", e);
+					}
+				}
+			},
+			Err(e) => match last_working.take() {
+				Some(working) => {
+					println!("Could not launch with the changed config or certificates, going back to the old ones: {}", e);
+					launch_config = working;
+				},
+				None => {
+					return Err(e.into());
+				}
+			}
+		}
+	}
 }
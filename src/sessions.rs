@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::{now_seconds, random_token};
+
+// The name of the cookie that holds a browser's session id
+pub const SESSION_COOKIE: &str = "session";
+
+// How long a browser stays logged in
+pub const SESSION_SECONDS: u64 = 60 * 60 * 24 * 7;
+
+// A structure for representing a logged in browser
+#[derive(Clone)]
+pub struct Session {
+	username: Option<String>, // The user that logged in, None if it was the server password
+	expires: u64 // When the session stops working
+}
+
+impl Session {
+
+	// Getters
+
+	pub fn get_username(&self) -> Option<&str> {
+		self.username.as_deref()
+	}
+
+	pub fn get_expires(&self) -> u64 {
+		self.expires
+	}
+}
+
+// A structure for keeping track of every logged in browser, only kept in memory so a restart logs everyone out
+// Clones share the same sessions, so they last through the server being launched again with new certificates
+#[derive(Default, Clone)]
+pub struct SessionStore {
+	sessions: Arc<Mutex<HashMap<String, Session>>>
+}
+
+impl SessionStore {
+
+	// Start a new session for a user and return its id
+	pub fn create(&self, username: Option<&str>) -> String {
+		let id = random_token(32);
+		let session = Session {
+			username: username.map(str::to_string),
+			expires: now_seconds() + SESSION_SECONDS
+		};
+
+		let mut sessions = self.sessions.lock().unwrap();
+
+		// Take the chance to forget about sessions that have expired
+		let now = now_seconds();
+		sessions.retain(|_, session| session.expires >= now);
+
+		sessions.insert(id.clone(), session);
+		id
+	}
+
+	// Get a session by its id if it hasn't expired
+	pub fn get(&self, id: &str) -> Option<Session> {
+		let sessions = self.sessions.lock().unwrap();
+		sessions.get(id).filter(|session| session.expires >= now_seconds()).cloned()
+	}
+
+	// End a session
+	pub fn remove(&self, id: &str) {
+		self.sessions.lock().unwrap().remove(id);
+	}
+}
@@ -0,0 +1,180 @@
+use data_encoding::HEXUPPER;
+use ring::hmac;
+use rocket::data::{self, Data, FromData, Limits};
+use rocket::http::Status;
+use rocket::request::Request;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+use crate::{now_seconds, sha256_digest_bytes, PasswordValidationError};
+
+#[cfg(test)]
+mod tests {
+	use super::{signing_message, sign, verify};
+	use crate::sha256_digest_str;
+
+	#[test]
+	fn sign_and_verify() {
+		let message = signing_message("GET", "/info/file/docs/notes.txt", 1700000000, "ABCDEF", &sha256_digest_str(""));
+		let signature = sign("testpass", &message);
+
+		assert!(verify("testpass", &message, &signature));
+		assert!(verify("testpass", &message, &signature.to_lowercase()));
+		assert!(!verify("wrongpass", &message, &signature));
+
+		// Changing any part of the request breaks the signature
+		let other_message = signing_message("PUT", "/info/file/docs/notes.txt", 1700000000, "ABCDEF", &sha256_digest_str(""));
+		assert!(!verify("testpass", &other_message, &signature));
+	}
+}
+
+// How far off a signed request's timestamp can be from the server's clock
+pub const SIGNATURE_WINDOW_SECONDS: u64 = 5 * 60;
+
+// The headers that make up a signed request
+pub const SIGNATURE_HEADER: &str = "signature";
+pub const TIMESTAMP_HEADER: &str = "timestamp";
+pub const NONCE_HEADER: &str = "nonce";
+pub const CONTENT_SHA256_HEADER: &str = "content-sha256";
+
+// Build the message that gets signed for a request
+pub fn signing_message(method: &str, path_and_query: &str, timestamp: u64, nonce: &str, body_digest: &str) -> String {
+	format!("{}\n{}\n{}\n{}\n{}", method.to_uppercase(), path_and_query, timestamp, nonce, body_digest.to_uppercase())
+}
+
+// Sign a message with a password, returning the signature as upper-case hexadecimal
+pub fn sign(password: &str, message: &str) -> String {
+	let key = hmac::Key::new(hmac::HMAC_SHA256, password.as_bytes());
+	HEXUPPER.encode(hmac::sign(&key, message.as_bytes()).as_ref())
+}
+
+// Check a signature made by sign in constant time
+pub fn verify(password: &str, message: &str, signature: &str) -> bool {
+	let key = hmac::Key::new(hmac::HMAC_SHA256, password.as_bytes());
+	match HEXUPPER.decode(signature.to_uppercase().as_bytes()) {
+		Ok(signature) => hmac::verify(&key, message.as_bytes(), &signature).is_ok(),
+		Err(_) => false
+	}
+}
+
+// A structure for remembering the nonces of recent signed requests so they can't be replayed, only kept in memory
+// Clones share the same nonces, like sessions
+#[derive(Default, Clone)]
+pub struct NonceStore {
+	nonces: Arc<Mutex<HashMap<String, u64>>> // When each nonce can be forgotten
+}
+
+impl NonceStore {
+	// Remember a nonce, returning false if it was already used
+	pub fn use_nonce(&self, nonce: &str) -> bool {
+		let mut nonces = self.nonces.lock().unwrap();
+		let now = now_seconds();
+
+		// Nonces only need to be kept for as long as their timestamps are in the window
+		nonces.retain(|_, expires| *expires >= now);
+
+		nonces.insert(nonce.to_string(), now + 2 * SIGNATURE_WINDOW_SECONDS).is_none()
+	}
+}
+
+// Kept in the request's local cache so the body of a signed request can be checked against the digest that was signed
+pub struct SignedBodyDigest(pub Option<String>);
+
+// A structure for a request that was authenticated by its signature instead of by sending the password
+pub struct ValidSignature {
+	username: Option<String>, // The user that signed the request, None if it was signed with the server password
+	body_digest: String // The SHA-256 digest of the body that was signed
+}
+
+impl ValidSignature {
+
+	// Getters
+
+	pub fn get_username(&self) -> Option<&str> {
+		self.username.as_deref()
+	}
+
+	pub fn get_body_digest(&self) -> &str {
+		&self.body_digest
+	}
+
+	// Check the signature headers of a request against the password of whoever claims to have signed it
+	pub fn verify(req: &Request<'_>, password: &str) -> Result<Self, PasswordValidationError> {
+		let headers = req.headers();
+		let signature = headers.get_one(SIGNATURE_HEADER).ok_or(PasswordValidationError::SignatureHeaderMissing)?;
+		let timestamp = headers.get_one(TIMESTAMP_HEADER).and_then(|timestamp| timestamp.parse::<u64>().ok())
+			.ok_or(PasswordValidationError::SignatureHeaderMissing)?;
+		let nonce = headers.get_one(NONCE_HEADER).ok_or(PasswordValidationError::SignatureHeaderMissing)?;
+		let body_digest = headers.get_one(CONTENT_SHA256_HEADER).ok_or(PasswordValidationError::SignatureHeaderMissing)?;
+
+		// The request has to have been signed recently
+		if now_seconds().abs_diff(timestamp) > SIGNATURE_WINDOW_SECONDS {
+			return Err(PasswordValidationError::SignatureExpired);
+		}
+
+		// The signature has to match the request
+		let message = signing_message(req.method().as_str(), &req.uri().to_string(), timestamp, nonce, body_digest);
+		if !verify(password, &message, signature) {
+			return Err(PasswordValidationError::IncorrectPassword);
+		}
+
+		// And it can't have been seen before
+		if let Some(nonces) = req.rocket().state::<NonceStore>() {
+			if !nonces.use_nonce(nonce) {
+				return Err(PasswordValidationError::SignatureReplayed);
+			}
+		}
+
+		// Remember the digest so the body can be checked once it is read
+		req.local_cache(|| SignedBodyDigest(Some(body_digest.to_uppercase())));
+
+		Ok(Self {
+			username: headers.get_one("username").map(str::to_string),
+			body_digest: body_digest.to_uppercase()
+		})
+	}
+}
+
+// A JSON data guard that also checks the body against the digest of a signed request
+pub struct SignedJson<T>(pub T);
+
+impl<T> Deref for SignedJson<T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		&self.0
+	}
+}
+
+#[rocket::async_trait]
+impl<'r, T: DeserializeOwned> FromData<'r> for SignedJson<T> {
+	type Error = String;
+
+	async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+		let limit = req.limits().get("json").unwrap_or(Limits::JSON);
+		let bytes = match data.open(limit).into_bytes().await {
+			Ok(bytes) if bytes.is_complete() => bytes.into_inner(),
+			Ok(_) => {
+				return data::Outcome::Failure((Status::PayloadTooLarge, "Body is too large".to_string()));
+			},
+			Err(e) => {
+				return data::Outcome::Failure((Status::BadRequest, e.to_string()));
+			}
+		};
+
+		// If the request was signed, the body has to be the one that was signed
+		if let SignedBodyDigest(Some(body_digest)) = req.local_cache(|| SignedBodyDigest(None)) {
+			let digest = sha256_digest_bytes(&bytes);
+			if digest != *body_digest {
+				return data::Outcome::Failure((Status::BadRequest, "Body does not match signed digest".to_string()));
+			}
+		}
+
+		match serde_json::from_slice(&bytes) {
+			Ok(value) => data::Outcome::Success(SignedJson(value)),
+			Err(e) => data::Outcome::Failure((Status::UnprocessableEntity, e.to_string()))
+		}
+	}
+}
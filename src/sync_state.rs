@@ -0,0 +1,305 @@
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::now_seconds;
+
+#[cfg(test)]
+mod tests {
+	use super::{Direction, PendingOperation, SyncState};
+	use crate::test_dir::TestDir;
+	use std::fs;
+	use std::path::{Path, PathBuf};
+
+	#[test]
+	fn pending_queue() {
+		let data_root = TestDir::new("pending");
+		let state = SyncState::load(&data_root);
+		state.queue(PendingOperation::new(Path::new("local/a.txt"), Path::new("/a.txt"), Direction::Both, "first".to_string()));
+		state.queue(PendingOperation::new(Path::new("local/b.txt"), Path::new("/b.txt"), Direction::Push, "first".to_string()));
+
+		// Failing again keeps the file's place and when it first failed, with the latest direction and error
+		state.queue(PendingOperation::new(Path::new("local/a.txt"), Path::new("/a.txt"), Direction::Pull, "second".to_string()));
+		assert_eq!(state.pending_len(), 2);
+
+		// The queue survives being saved and loaded again, and taking it empties it
+		state.save();
+		let state = SyncState::load(&data_root);
+		let pending = state.take_pending();
+		assert_eq!(state.pending_len(), 0);
+		assert_eq!(pending.len(), 2);
+		assert_eq!(pending[0].get_client_path(), Path::new("local/a.txt"));
+		assert_eq!(pending[0].get_direction(), Direction::Pull);
+		assert_eq!(pending[0].get_error(), "second");
+		assert_eq!(pending[1].get_server_path(), Path::new("/b.txt"));
+	}
+
+	#[test]
+	fn kept_for_both_paths() {
+		let data_root = TestDir::new("state");
+		let state = SyncState::load(&data_root);
+		state.set(Path::new("local/dir/a.txt"), Path::new("/team/dir/a.txt"), "ABC");
+		state.set(Path::new("local/dir/b.txt"), Path::new("/other/dir/b.txt"), "DEF");
+		state.set_cursor(Path::new("local/dir"), Path::new("/team/dir"), 7);
+		state.save();
+
+		// Only what was saved is left next to the state, and it all comes back
+		let mut names: Vec<String> = fs::read_dir(&data_root).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+		names.sort();
+		assert_eq!(names, ["change_cursors.json", "filter_digests.json", "pending_operations.json", "sync_state.json"]);
+		let state = SyncState::load(&data_root);
+		assert_eq!(state.get(Path::new("local/dir/a.txt"), Path::new("/team/dir/a.txt")).as_deref(), Some("ABC"));
+		assert_eq!(state.get_cursor(Path::new("local/dir"), Path::new("/team/dir")), Some(7));
+
+		// Pointing the same client path somewhere else on the server starts it over
+		assert_eq!(state.get(Path::new("local/dir/a.txt"), Path::new("/elsewhere/dir/a.txt")), None);
+		assert_eq!(state.get_cursor(Path::new("local/dir"), Path::new("/elsewhere/dir")), None);
+		assert_eq!(state.paths_under(Path::new("local/dir"), Path::new("/team/dir")), [(PathBuf::from("local/dir/a.txt"), PathBuf::from("/team/dir/a.txt"))]);
+	}
+
+	#[test]
+	fn unreadable_state() {
+		let data_root = TestDir::new("broken_state");
+		for name in ["sync_state.json", "change_cursors.json", "pending_operations.json", "filter_digests.json"] {
+			fs::write(data_root.join(name), "{\"cut off").unwrap();
+		}
+
+		// Files that can't be parsed are started over instead of stopping the client
+		let state = SyncState::load(&data_root);
+		assert_eq!(state.get(Path::new("local/a.txt"), Path::new("/a.txt")), None);
+		assert_eq!(state.pending_len(), 0);
+	}
+}
+
+// Which ways a sync is allowed to move files
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Direction {
+	Both,
+	Push,
+	Pull
+}
+
+// A file that couldn't be synced because the server couldn't be reached or had trouble, to be tried again once it can be
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PendingOperation {
+	client_path: PathBuf,
+	server_path: PathBuf,
+	direction: Direction, // Which way the sync that failed was going
+	error: String, // What went wrong the last time
+	queued: u64 // When it first failed
+}
+
+impl PendingOperation {
+
+	// Constructor
+
+	pub fn new(client_path: &Path, server_path: &Path, direction: Direction, error: String) -> Self {
+		Self {
+			client_path: client_path.to_path_buf(),
+			server_path: server_path.to_path_buf(),
+			direction,
+			error,
+			queued: now_seconds()
+		}
+	}
+
+	// Getters
+
+	pub fn get_client_path(&self) -> &Path {
+		&self.client_path
+	}
+
+	pub fn get_server_path(&self) -> &Path {
+		&self.server_path
+	}
+
+	pub fn get_direction(&self) -> Direction {
+		self.direction
+	}
+
+	pub fn get_error(&self) -> &str {
+		&self.error
+	}
+
+	pub fn get_queued(&self) -> u64 {
+		self.queued
+	}
+}
+
+// A client path and the server path it syncs with, which is what everything in the state is kept for
+// Changing where a mapping points on the server then starts it over instead of taking the new place to be in sync
+type PathPair = (PathBuf, PathBuf);
+
+// How a value kept for a pair of paths is saved, since JSON objects can only have strings for keys
+#[derive(Serialize, Deserialize)]
+struct SavedEntry<T> {
+	client_path: PathBuf,
+	server_path: PathBuf,
+	value: T
+}
+
+// A structure for remembering the digest each client file had when it was last in sync with the server, saved as JSON in the client's data root
+// Knowing what both sides started from is what tells a deletion from a new file, and an edit on one side from edits on both
+// The server's change cursor for each mapped directory is kept alongside, so only what changed since can be looked at
+// So are the files that are waiting for the server to be reachable again, and the filter rules each directory was last synced with
+pub struct SyncState {
+	path: PathBuf,
+	cursors_path: PathBuf,
+	pending_path: PathBuf,
+	filters_path: PathBuf,
+	digests: Mutex<HashMap<PathPair, String>>,
+	cursors: Mutex<HashMap<PathPair, u64>>, // Keyed by the paths of the directory
+	filters: Mutex<HashMap<PathPair, String>>, // The digest of the rules, keyed by the paths of the directory
+	pending: Mutex<Vec<PendingOperation>> // Oldest first
+}
+
+impl SyncState {
+
+	// Constructor
+
+	pub fn load(data_root: &Path) -> Self {
+		let path = data_root.join("sync_state.json");
+		let cursors_path = data_root.join("change_cursors.json");
+		let pending_path = data_root.join("pending_operations.json");
+		let filters_path = data_root.join("filter_digests.json");
+
+		Self {
+			digests: Mutex::new(load_entries(&path)),
+			cursors: Mutex::new(load_entries(&cursors_path)),
+			filters: Mutex::new(load_entries(&filters_path)),
+			pending: Mutex::new(load_json(&pending_path).unwrap_or_default()),
+			path,
+			cursors_path,
+			pending_path,
+			filters_path
+		}
+	}
+
+	// Get the digest a file had when it was last in sync
+	pub fn get(&self, client_path: &Path, server_path: &Path) -> Option<String> {
+		self.digests.lock().unwrap().get(&pair(client_path, server_path)).cloned()
+	}
+
+	// Remember that a file is in sync with the given digest
+	pub fn set(&self, client_path: &Path, server_path: &Path, digest: &str) {
+		self.digests.lock().unwrap().insert(pair(client_path, server_path), digest.to_string());
+	}
+
+	// Forget about a file that is gone from both sides
+	pub fn forget(&self, client_path: &Path, server_path: &Path) {
+		self.digests.lock().unwrap().remove(&pair(client_path, server_path));
+	}
+
+	// Get every file under a directory that was in sync with the same place under the server directory
+	pub fn paths_under(&self, client_dir_path: &Path, server_dir_path: &Path) -> Vec<PathPair> {
+		self.digests.lock().unwrap().keys()
+			.filter(|(client_path, server_path)| client_path.strip_prefix(client_dir_path).is_ok_and(|relative_path| *server_path == server_dir_path.join(relative_path)))
+			.cloned()
+			.collect()
+	}
+
+	// Get the server's change cursor from when a directory was last in sync
+	pub fn get_cursor(&self, client_dir_path: &Path, server_dir_path: &Path) -> Option<u64> {
+		self.cursors.lock().unwrap().get(&pair(client_dir_path, server_dir_path)).copied()
+	}
+
+	// Remember the server's change cursor for a directory that is in sync
+	pub fn set_cursor(&self, client_dir_path: &Path, server_dir_path: &Path, cursor: u64) {
+		self.cursors.lock().unwrap().insert(pair(client_dir_path, server_dir_path), cursor);
+	}
+
+	// Get the digest of the filter rules a directory was last in sync with
+	pub fn get_filter_digest(&self, client_dir_path: &Path, server_dir_path: &Path) -> Option<String> {
+		self.filters.lock().unwrap().get(&pair(client_dir_path, server_dir_path)).cloned()
+	}
+
+	// Remember the filter rules a directory is in sync with
+	pub fn set_filter_digest(&self, client_dir_path: &Path, server_dir_path: &Path, digest: &str) {
+		self.filters.lock().unwrap().insert(pair(client_dir_path, server_dir_path), digest.to_string());
+	}
+
+	// Remember a file that has to be synced again once the server can be reached, keeping when it first failed if it already was waiting
+	pub fn queue(&self, operation: PendingOperation) {
+		let mut pending = self.pending.lock().unwrap();
+		match pending.iter_mut().find(|pending_operation| pending_operation.client_path == operation.client_path && pending_operation.server_path == operation.server_path) {
+			Some(pending_operation) => {
+				pending_operation.direction = operation.direction;
+				pending_operation.error = operation.error;
+			},
+			None => pending.push(operation)
+		}
+	}
+
+	// Take every file that is waiting to be synced again
+	pub fn take_pending(&self) -> Vec<PendingOperation> {
+		std::mem::take(&mut *self.pending.lock().unwrap())
+	}
+
+	// Get how many files are waiting to be synced again
+	pub fn pending_len(&self) -> usize {
+		self.pending.lock().unwrap().len()
+	}
+
+	// Write the state out to disk, creating the data root if needed
+	pub fn save(&self) {
+		let digests_string = entries_string(&self.digests.lock().unwrap());
+		let cursors_string = entries_string(&self.cursors.lock().unwrap());
+		let pending_string = serde_json::to_string_pretty(&*self.pending.lock().unwrap()).expect("Could not serialize pending operations");
+		let filters_string = entries_string(&self.filters.lock().unwrap());
+		let saved = self.path.parent().map_or(Ok(()), fs::create_dir_all)
+			.and_then(|()| write_replacing(&self.path, digests_string))
+			.and_then(|()| write_replacing(&self.cursors_path, cursors_string))
+			.and_then(|()| write_replacing(&self.pending_path, pending_string))
+			.and_then(|()| write_replacing(&self.filters_path, filters_string));
+		if let Err(e) = saved {
+			eprintln!("Could not save sync state to {:?}: {}", self.path, e);
+		}
+	}
+}
+
+// Utility function to make the key for a pair of paths
+fn pair(client_path: &Path, server_path: &Path) -> PathPair {
+	(client_path.to_path_buf(), server_path.to_path_buf())
+}
+
+// Utility function to read a saved file, starting over with nothing if it doesn't exist yet or can't be read
+// A file that can't be parsed only means some files are looked at again as if they had never been in sync, so it isn't worth stopping for
+fn load_json<T: DeserializeOwned>(path: &Path) -> Option<T> {
+	let saved_string = fs::read_to_string(path).ok()?;
+	match serde_json::from_str(&saved_string) {
+		Ok(saved) => Some(saved),
+		Err(e) => {
+			eprintln!("Could not parse {:?}, starting it over: {}", path, e);
+			None
+		}
+	}
+}
+
+// Utility function to read a saved file of values kept for pairs of paths
+fn load_entries<T: DeserializeOwned>(path: &Path) -> HashMap<PathPair, T> {
+	load_json::<Vec<SavedEntry<T>>>(path).unwrap_or_default().into_iter()
+		.map(|entry| ((entry.client_path, entry.server_path), entry.value))
+		.collect()
+}
+
+// Utility function to turn values kept for pairs of paths into what gets saved, in order so the files are easy to compare
+fn entries_string<T: Serialize + Clone>(values: &HashMap<PathPair, T>) -> String {
+	let mut entries: Vec<SavedEntry<T>> = values.iter()
+		.map(|((client_path, server_path), value)| SavedEntry { client_path: client_path.clone(), server_path: server_path.clone(), value: value.clone() })
+		.collect();
+	entries.sort_by(|a, b| (&a.client_path, &a.server_path).cmp(&(&b.client_path, &b.server_path)));
+	serde_json::to_string_pretty(&entries).expect("Could not serialize sync state")
+}
+
+// Utility function to write a file by writing a new one next to it and moving it into place, so a crash part way leaves the old one
+fn write_replacing(path: &Path, contents: String) -> io::Result<()> {
+	let mut temp_path = path.as_os_str().to_owned();
+	temp_path.push(".tmp");
+	fs::write(&temp_path, contents)?;
+	fs::rename(&temp_path, path)
+}
@@ -0,0 +1,43 @@
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+// A directory for a test to work in under the system's temporary one, removed when the test is done with it even if it fails
+pub struct TestDir {
+	path: PathBuf
+}
+
+impl TestDir {
+
+	// Constructor
+
+	// Start from an empty directory, named after the test and the process so tests running at the same time don't share one
+	pub fn new(name: &str) -> Self {
+		let path = std::env::temp_dir().join(format!("skywriter_{}_test_{}", name, std::process::id()));
+		let _ = fs::remove_dir_all(&path);
+		fs::create_dir_all(&path).expect("Could not create test directory");
+		Self {
+			path
+		}
+	}
+}
+
+impl Deref for TestDir {
+	type Target = Path;
+
+	fn deref(&self) -> &Path {
+		&self.path
+	}
+}
+
+impl AsRef<Path> for TestDir {
+	fn as_ref(&self) -> &Path {
+		&self.path
+	}
+}
+
+impl Drop for TestDir {
+	fn drop(&mut self) {
+		let _ = fs::remove_dir_all(&self.path);
+	}
+}
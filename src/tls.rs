@@ -0,0 +1,100 @@
+use rocket::figment::Figment;
+use rocket::mtls::Certificate;
+use rocket::Shutdown;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
+
+// How often the certificate files are checked for changes
+pub const TLS_RELOAD_SECONDS: u64 = 10;
+
+// A structure for representing the server's TLS settings
+#[derive(Deserialize, Clone)]
+pub struct TlsConfig {
+	certs: String, // Path to the PEM certificate chain
+	key: String, // Path to the PEM private key
+	client_ca_certs: Option<String>, // Path to the PEM certificates that client certificates have to be signed by, turning on mutual TLS
+	#[serde(default)]
+	require_client_certs: bool // If clients without a certificate are refused
+}
+
+impl TlsConfig {
+
+	// Getters
+
+	pub fn get_certs(&self) -> &str {
+		&self.certs
+	}
+
+	pub fn get_key(&self) -> &str {
+		&self.key
+	}
+
+	pub fn get_client_ca_certs(&self) -> Option<&str> {
+		self.client_ca_certs.as_deref()
+	}
+
+	pub fn get_require_client_certs(&self) -> bool {
+		self.require_client_certs
+	}
+
+	// Read what is in the files, so the server can be launched with them even after they change
+	pub fn read_files(&self) -> io::Result<TlsFiles> {
+		Ok(TlsFiles {
+			certs: fs::read(&self.certs)?,
+			key: fs::read(&self.key)?,
+			client_ca_certs: self.client_ca_certs.as_ref().map(fs::read).transpose()?
+		})
+	}
+
+	// Add the TLS settings on top of Rocket's own configuration, with what was read from the files
+	pub fn figment(&self, figment: Figment, files: &TlsFiles) -> Figment {
+		let figment = figment
+			.merge(("tls.certs", &files.certs))
+			.merge(("tls.key", &files.key));
+
+		match &files.client_ca_certs {
+			Some(client_ca_certs) => figment
+				.merge(("tls.mutual.ca_certs", client_ca_certs))
+				.merge(("tls.mutual.mandatory", self.require_client_certs)),
+			None => figment
+		}
+	}
+
+	// When each of the files was last changed, to notice when they get replaced
+	fn modified_times(&self) -> Vec<Option<SystemTime>> {
+		[Some(&self.certs), Some(&self.key), self.client_ca_certs.as_ref()].iter().flatten()
+			.map(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+			.collect()
+	}
+
+	// Shut the server down once any of the files change so it can be launched again with them, setting the flag to say why
+	pub async fn watch(self, shutdown: Shutdown, reloading: Arc<AtomicBool>) {
+		let modified_times = self.modified_times();
+		loop {
+			rocket::tokio::time::sleep(Duration::from_secs(TLS_RELOAD_SECONDS)).await;
+			if self.modified_times() != modified_times {
+				reloading.store(true, Ordering::SeqCst);
+				shutdown.notify();
+				return;
+			}
+		}
+	}
+}
+
+// A structure for holding what was in the certificate files when they were read
+// Rocket is given these instead of the paths, so it can go back to ones that worked if the files are changed to something it can't use
+#[derive(Clone)]
+pub struct TlsFiles {
+	certs: Vec<u8>,
+	key: Vec<u8>,
+	client_ca_certs: Option<Vec<u8>>
+}
+
+// Utility function to get the user a verified client certificate was issued to, which is its subject's common name
+pub fn certificate_username(certificate: &Certificate<'_>) -> Option<String> {
+	certificate.subject().common_name().map(str::to_string)
+}
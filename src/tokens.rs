@@ -0,0 +1,126 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::{now_seconds, random_token, sha256_digest_str};
+
+// How long a minted API token works for
+pub const TOKEN_SECONDS: u64 = 60 * 60 * 24 * 90;
+
+// A structure for representing an API token, which is only ever saved as its digest
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ApiToken {
+	username: Option<String>, // The user the token belongs to, None if it was minted with the server password
+	created: u64, // When the token was minted
+	expires: u64 // When the token stops working
+}
+
+impl ApiToken {
+
+	// Getters
+
+	pub fn get_username(&self) -> Option<&str> {
+		self.username.as_deref()
+	}
+
+	pub fn get_created(&self) -> u64 {
+		self.created
+	}
+
+	pub fn get_expires(&self) -> u64 {
+		self.expires
+	}
+}
+
+// A structure for representing a newly minted token
+#[derive(Serialize, Deserialize)]
+pub struct TokenResponse {
+	token: String,
+	expires: u64
+}
+
+impl TokenResponse {
+
+	// Getters
+
+	pub fn get_token(&self) -> &str {
+		&self.token
+	}
+
+	pub fn get_expires(&self) -> u64 {
+		self.expires
+	}
+}
+
+// A structure for keeping track of every API token, saved as JSON in the data root
+pub struct TokenStore {
+	path: PathBuf,
+	tokens: Mutex<HashMap<String, ApiToken>> // Keyed by the SHA-256 digest of the token
+}
+
+impl TokenStore {
+
+	// Constructor
+
+	pub fn load(data_root: &Path) -> Self {
+		let path = data_root.join("tokens.json");
+
+		// Start with no tokens if the file doesn't exist yet
+		let tokens = match fs::read_to_string(&path) {
+			Ok(tokens_string) => serde_json::from_str(&tokens_string).expect("Could not parse saved tokens"),
+			Err(_) => HashMap::new()
+		};
+
+		Self {
+			path,
+			tokens: Mutex::new(tokens)
+		}
+	}
+
+	// Mint a new token for a user
+	pub fn mint(&self, username: Option<&str>) -> TokenResponse {
+		let token = random_token(32);
+		let now = now_seconds();
+		let api_token = ApiToken {
+			username: username.map(str::to_string),
+			created: now,
+			expires: now + TOKEN_SECONDS
+		};
+
+		let mut tokens = self.tokens.lock().unwrap();
+
+		// Take the chance to forget about tokens that have expired
+		tokens.retain(|_, api_token| api_token.expires >= now);
+
+		tokens.insert(sha256_digest_str(&token), api_token);
+		self.save(&tokens);
+
+		TokenResponse {
+			token,
+			expires: now + TOKEN_SECONDS
+		}
+	}
+
+	// Get a token if it exists and hasn't expired
+	pub fn get(&self, token: &str) -> Option<ApiToken> {
+		let tokens = self.tokens.lock().unwrap();
+		tokens.get(&sha256_digest_str(token)).filter(|api_token| api_token.expires >= now_seconds()).cloned()
+	}
+
+	// Revoke a token
+	pub fn revoke(&self, token: &str) {
+		let mut tokens = self.tokens.lock().unwrap();
+		tokens.remove(&sha256_digest_str(token));
+		self.save(&tokens);
+	}
+
+	// Write the tokens out to disk
+	fn save(&self, tokens: &HashMap<String, ApiToken>) {
+		let tokens_string = serde_json::to_string_pretty(tokens).expect("Could not serialize tokens");
+		if let Err(e) = fs::write(&self.path, tokens_string) {
+			println!("Could not save tokens to {:?}: {}", self.path, e);
+		}
+	}
+}
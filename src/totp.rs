@@ -0,0 +1,261 @@
+use data_encoding::BASE32_NOPAD;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::{now_seconds, random_token, sha256_digest_str};
+
+#[cfg(test)]
+mod tests {
+	use super::{totp_code, TotpStore, TOTP_STEP};
+	use crate::now_seconds;
+	use crate::test_dir::TestDir;
+	use data_encoding::BASE32_NOPAD;
+
+	// The SHA-1 secret used by the test vectors in RFC 6238
+	const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+	#[test]
+	fn rfc_6238_codes() {
+		// The RFC gives 8 digit codes, the last 6 digits of which are what authenticator apps show
+		assert_eq!(totp_code(RFC_SECRET, 59 / 30), "287082");
+		assert_eq!(totp_code(RFC_SECRET, 1111111109 / 30), "081804");
+		assert_eq!(totp_code(RFC_SECRET, 1234567890 / 30), "005924");
+		assert_eq!(totp_code(RFC_SECRET, 2000000000 / 30), "279037");
+	}
+
+	#[test]
+	fn confirmed_enrollment_is_kept() {
+		let data_root = TestDir::new("totp");
+		let store = TotpStore::load(&data_root);
+
+		// Enrolling again before confirming just starts over
+		store.enroll("alice").unwrap();
+		let provisioning = store.enroll("alice").unwrap();
+		let secret = BASE32_NOPAD.decode(provisioning.get_secret().as_bytes()).unwrap();
+		assert!(store.confirm("alice", &totp_code(&secret, now_seconds() / TOTP_STEP)));
+
+		// Once it's on, enrolling can't be used to swap the secret out
+		assert!(store.enroll("alice").is_none());
+		assert!(store.is_enabled("alice"));
+		assert!(!store.verify("alice", None));
+	}
+}
+
+// How many seconds each code is good for
+pub const TOTP_STEP: u64 = 30;
+
+// How many digits are in a code
+pub const TOTP_DIGITS: u32 = 6;
+
+// How many recovery codes a user gets when enrolling
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+// The name that shows up in authenticator apps
+const ISSUER: &str = "SkyWriter";
+
+// Calculate the code for a secret at a given step, as described in RFC 4226 and RFC 6238
+fn totp_code(secret: &[u8], counter: u64) -> String {
+	let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+	let tag = hmac::sign(&key, &counter.to_be_bytes());
+	let tag = tag.as_ref();
+
+	// Dynamic truncation, the last nibble picks which four bytes become the code
+	let offset = (tag[tag.len() - 1] & 0x0f) as usize;
+	let binary = u32::from_be_bytes([tag[offset] & 0x7f, tag[offset + 1], tag[offset + 2], tag[offset + 3]]);
+
+	format!("{:0width$}", binary % 10u32.pow(TOTP_DIGITS), width = TOTP_DIGITS as usize)
+}
+
+// A structure for representing a user's two-factor enrollment
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TotpEnrollment {
+	secret: String, // The shared secret, base32 encoded
+	recovery_code_digests: Vec<String>, // The SHA-256 digests of the recovery codes that haven't been used
+	confirmed: bool, // If the user proved they can make codes, which is when it starts being enforced
+	last_counter: u64 // The step of the last code used, so codes can't be replayed
+}
+
+// A structure for representing a fresh enrollment for the user to put in their authenticator app
+#[derive(Serialize, Deserialize)]
+pub struct TotpProvisioning {
+	secret: String,
+	provisioning_uri: String,
+	recovery_codes: Vec<String>
+}
+
+impl TotpProvisioning {
+
+	// Getters
+
+	pub fn get_secret(&self) -> &str {
+		&self.secret
+	}
+
+	pub fn get_provisioning_uri(&self) -> &str {
+		&self.provisioning_uri
+	}
+
+	pub fn get_recovery_codes(&self) -> &[String] {
+		&self.recovery_codes
+	}
+}
+
+// A structure for getting a code from a requester
+#[derive(Deserialize)]
+pub struct TotpCode {
+	code: String
+}
+
+impl TotpCode {
+
+	// Getters
+
+	pub fn get_code(&self) -> &str {
+		&self.code
+	}
+}
+
+// A structure for keeping track of every user's two-factor enrollment, saved as JSON in the data root
+pub struct TotpStore {
+	path: PathBuf,
+	enrollments: Mutex<HashMap<String, TotpEnrollment>>
+}
+
+impl TotpStore {
+
+	// Constructor
+
+	pub fn load(data_root: &Path) -> Self {
+		let path = data_root.join("totp.json");
+
+		// Start with nobody enrolled if the file doesn't exist yet
+		let enrollments = match fs::read_to_string(&path) {
+			Ok(enrollments_string) => serde_json::from_str(&enrollments_string).expect("Could not parse saved two-factor enrollments"),
+			Err(_) => HashMap::new()
+		};
+
+		Self {
+			path,
+			enrollments: Mutex::new(enrollments)
+		}
+	}
+
+	// If two-factor authentication is being enforced for a user
+	pub fn is_enabled(&self, username: &str) -> bool {
+		self.enrollments.lock().unwrap().get(username).is_some_and(|enrollment| enrollment.confirmed)
+	}
+
+	// Start enrolling a user, replacing any unconfirmed enrollment they had, which only gets enforced once it is confirmed
+	// Gives nothing if they already have two-factor authentication on, since it has to be disabled with a code first
+	pub fn enroll(&self, username: &str) -> Option<TotpProvisioning> {
+		let mut enrollments = self.enrollments.lock().unwrap();
+		if enrollments.get(username).is_some_and(|enrollment| enrollment.confirmed) {
+			return None;
+		}
+
+		let mut secret = vec![0; 20];
+		SystemRandom::new().fill(&mut secret).expect("Could not generate random bytes");
+		let secret = BASE32_NOPAD.encode(&secret);
+
+		let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT).map(|_| random_token(5)).collect();
+		let provisioning_uri = format!("otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+			issuer = ISSUER, username = username, secret = secret, digits = TOTP_DIGITS, period = TOTP_STEP);
+
+		let enrollment = TotpEnrollment {
+			secret: secret.clone(),
+			recovery_code_digests: recovery_codes.iter().map(String::as_str).map(sha256_digest_str).collect(),
+			confirmed: false,
+			last_counter: 0
+		};
+
+		enrollments.insert(username.to_string(), enrollment);
+		self.save(&enrollments);
+
+		Some(TotpProvisioning {
+			secret,
+			provisioning_uri,
+			recovery_codes
+		})
+	}
+
+	// Confirm an enrollment with a code from the authenticator app, turning enforcement on
+	pub fn confirm(&self, username: &str, code: &str) -> bool {
+		let mut enrollments = self.enrollments.lock().unwrap();
+		let confirmed = match enrollments.get_mut(username) {
+			Some(enrollment) => {
+				let valid = Self::check_code(enrollment, code);
+				enrollment.confirmed |= valid;
+				valid
+			},
+			None => false
+		};
+		self.save(&enrollments);
+		confirmed
+	}
+
+	// Turn two-factor authentication off for a user, which takes a valid code or recovery code
+	pub fn disable(&self, username: &str, code: &str) -> bool {
+		let mut enrollments = self.enrollments.lock().unwrap();
+		let valid = enrollments.get_mut(username).is_some_and(|enrollment| Self::check_code(enrollment, code) || Self::use_recovery_code(enrollment, code));
+		if valid {
+			enrollments.remove(username);
+		}
+		self.save(&enrollments);
+		valid
+	}
+
+	// Check a code or recovery code for a user, which always passes if they aren't enrolled
+	pub fn verify(&self, username: &str, code: Option<&str>) -> bool {
+		let mut enrollments = self.enrollments.lock().unwrap();
+		match enrollments.get_mut(username) {
+			Some(enrollment) if enrollment.confirmed => {
+				// Only a code that gets used up changes anything that needs saving
+				let valid = code.is_some_and(|code| Self::check_code(enrollment, code) || Self::use_recovery_code(enrollment, code));
+				if valid {
+					self.save(&enrollments);
+				}
+				valid
+			},
+			_ => true
+		}
+	}
+
+	// Check a code against the current step and the ones on either side of it to allow for clock drift
+	fn check_code(enrollment: &mut TotpEnrollment, code: &str) -> bool {
+		let secret = match BASE32_NOPAD.decode(enrollment.secret.as_bytes()) {
+			Ok(secret) => secret,
+			Err(_) => return false
+		};
+
+		let current = now_seconds() / TOTP_STEP;
+		for counter in current.saturating_sub(1)..=current + 1 {
+			// A code can only be used once
+			if counter > enrollment.last_counter && totp_code(&secret, counter) == code.trim() {
+				enrollment.last_counter = counter;
+				return true;
+			}
+		}
+		false
+	}
+
+	// Use up one of the recovery codes
+	fn use_recovery_code(enrollment: &mut TotpEnrollment, code: &str) -> bool {
+		let digest = sha256_digest_str(&code.trim().to_uppercase());
+		let before = enrollment.recovery_code_digests.len();
+		enrollment.recovery_code_digests.retain(|recovery_code_digest| *recovery_code_digest != digest);
+		enrollment.recovery_code_digests.len() < before
+	}
+
+	// Write the enrollments out to disk
+	fn save(&self, enrollments: &HashMap<String, TotpEnrollment>) {
+		let enrollments_string = serde_json::to_string_pretty(enrollments).expect("Could not serialize two-factor enrollments");
+		if let Err(e) = fs::write(&self.path, enrollments_string) {
+			println!("Could not save two-factor enrollments to {:?}: {}", self.path, e);
+		}
+	}
+}
@@ -0,0 +1,224 @@
+use serde::{Serialize, Deserialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{FileInfo, FileInfoError, sha256_digest_str};
+use crate::index::FileIndex;
+use crate::placeholder::{placeholder_target, read_placeholder};
+
+#[cfg(test)]
+mod tests {
+	use super::TreeInfo;
+	use crate::test_dir::TestDir;
+	use std::fs;
+	use std::path::Path;
+
+	#[test]
+	fn compare_trees() {
+		let root = TestDir::new("tree");
+		for side in ["one", "two"] {
+			fs::create_dir_all(root.join(side).join("docs")).unwrap();
+			fs::create_dir_all(root.join(side).join("photos")).unwrap();
+			fs::write(root.join(side).join("docs/notes.txt"), "notes").unwrap();
+			fs::write(root.join(side).join("photos/cat.jpg"), "cat").unwrap();
+		}
+		// Empty directories don't count
+		fs::create_dir_all(root.join("two/empty")).unwrap();
+
+		let one = TreeInfo::from_dir_path(&root.join("one"), 1, &|_, _| true, None, false).unwrap();
+		let two = TreeInfo::from_dir_path(&root.join("two"), 1, &|_, _| true, None, false).unwrap();
+		assert_eq!(one.get_digest(), two.get_digest());
+		assert_eq!(one.get_files(), 2);
+
+		// Only the directories on the way to a change differ
+		fs::write(root.join("two/docs/notes.txt"), "changed notes").unwrap();
+		let two = TreeInfo::from_dir_path(&root.join("two"), 1, &|_, _| true, None, false).unwrap();
+		assert_ne!(one.get_digest(), two.get_digest());
+		assert_ne!(one.get_child("docs").unwrap().get_digest(), two.get_child("docs").unwrap().get_digest());
+		assert_eq!(one.get_child("photos").unwrap().get_digest(), two.get_child("photos").unwrap().get_digest());
+
+		// Children are only kept down to the depth asked for, and filtered files are left out
+		assert!(two.get_child("docs").unwrap().get_children().is_none());
+		let filtered = TreeInfo::from_dir_path(&root.join("two"), u32::MAX, &|path, is_dir| is_dir || path.starts_with("photos"), None, false).unwrap();
+		assert_eq!(filtered.file_paths().iter().map(|(path, _)| path.as_path()).collect::<Vec<&Path>>(), vec![Path::new("photos/cat.jpg")]);
+	}
+}
+
+// A structure for representing a file or directory along with a digest of everything under it
+// Two directories with the same digest have the same files in them, so comparing trees only has to look inside directories that differ
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TreeInfo {
+	name: String, // The name of the file or directory
+	is_dir: bool,
+	digest: String, // The SHA-256 digest of the file, or of the names and digests of everything in the directory
+	size: u64, // How many bytes are under it
+	files: u64, // How many files are under it
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	children: Option<Vec<TreeInfo>> // What is in the directory sorted by name, None for files and for directories deeper than was asked for
+}
+
+impl TreeInfo {
+	// Associated function to make the tree under a directory, keeping what is in each directory down to the given depth
+	// The filter is given paths relative to the directory and if they are directories, what it refuses is left out and so are directories with no files left in them
+	// Files are only hashed if they aren't in the index, or have changed since they were hashed
+	// With placeholders, a placeholder is taken to be the file it stands in for, as the server had it
+	pub fn from_dir_path(path: &Path, depth: u32, include: &dyn Fn(&Path, bool) -> bool, index: Option<&FileIndex>, placeholders: bool) -> Result<Self, FileInfoError> {
+		let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+		if !path.is_dir() {
+			if !path.exists() {
+				// If the path is not a directory and does not exist, it is as good as empty
+				return Ok(Self::from_children(name, Vec::new(), depth));
+			}
+
+			// If the path is not a directory and does exist (meaning it is an existing file), return an error
+			return Err(FileInfoError::NotDir);
+		}
+
+		// Hash any files the index doesn't know yet at the same time, before going through them in order
+		if let Some(index) = index {
+			let mut file_paths = Vec::new();
+			Self::included_files(path, Path::new(""), include, &mut file_paths);
+			if placeholders {
+				file_paths.retain(|file_path| placeholder_target(file_path).is_none());
+			}
+			index.digest_all(&file_paths);
+		}
+
+		Ok(Self::walk_dir(path, name, Path::new(""), depth, include, index, placeholders))
+	}
+
+	// Associated function to find every file under a directory that the filter lets in
+	fn included_files(path: &Path, relative_path: &Path, include: &dyn Fn(&Path, bool) -> bool, file_paths: &mut Vec<PathBuf>) {
+		if let Ok(iter) = fs::read_dir(path) {
+			for entry in iter.flatten() {
+				let child_path = entry.path();
+				let child_relative_path = relative_path.join(entry.file_name());
+				if child_path.is_dir() {
+					if include(&child_relative_path, true) {
+						Self::included_files(&child_path, &child_relative_path, include, file_paths);
+					}
+				} else if include(&child_relative_path, false) {
+					file_paths.push(child_path);
+				}
+			}
+		}
+	}
+
+	// Associated function to make the tree under a directory that exists, which has to be walked all the way down for its digest
+	fn walk_dir(path: &Path, name: String, relative_path: &Path, depth: u32, include: &dyn Fn(&Path, bool) -> bool, index: Option<&FileIndex>, placeholders: bool) -> Self {
+		let mut children = Vec::new();
+		if let Ok(iter) = fs::read_dir(path) {
+			for entry in iter.flatten() {
+				let child_name = entry.file_name().to_string_lossy().into_owned();
+				let child_path = entry.path();
+				let child_relative_path = relative_path.join(&child_name);
+
+				if child_path.is_dir() {
+					if !include(&child_relative_path, true) {
+						continue;
+					}
+					let child = Self::walk_dir(&child_path, child_name, &child_relative_path, depth.saturating_sub(1), include, index, placeholders);
+					if child.files > 0 {
+						children.push(child);
+					}
+				} else if let Some(target_path) = placeholders.then(|| placeholder_target(&child_path)).flatten() {
+					// A placeholder only counts if the file it stands in for isn't there as well
+					let target_name = target_path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+					if target_path.exists() || !include(&relative_path.join(&target_name), false) {
+						continue;
+					}
+					if let Some(file_info) = read_placeholder(&target_path) {
+						children.push(Self {
+							name: target_name,
+							is_dir: false,
+							digest: file_info.get_digest().to_string(),
+							size: file_info.get_size(),
+							files: 1,
+							children: None
+						});
+					}
+				} else if include(&child_relative_path, false) {
+					let file_info = match index {
+						Some(index) => FileInfo::from_indexed_file_path(child_path, index),
+						None => FileInfo::from_file_path(child_path)
+					};
+					if let Ok(file_info) = file_info {
+						children.push(Self {
+							name: child_name,
+							is_dir: false,
+							digest: file_info.get_digest().to_string(),
+							size: file_info.get_size(),
+							files: 1,
+							children: None
+						});
+					}
+				}
+			}
+		}
+
+		children.sort_by(|a, b| a.name.cmp(&b.name));
+		Self::from_children(name, children, depth)
+	}
+
+	// Associated function to make a directory out of what is in it, sorted by name
+	fn from_children(name: String, children: Vec<Self>, depth: u32) -> Self {
+		// Names can't have nul characters in them, so nothing else can give the same listing
+		let listing: String = children.iter()
+			.map(|child| format!("{}\0{}\0{}\0", child.name, if child.is_dir { "d" } else { "f" }, child.digest))
+			.collect();
+
+		Self {
+			name,
+			is_dir: true,
+			digest: sha256_digest_str(&listing),
+			size: children.iter().map(|child| child.size).sum(),
+			files: children.iter().map(|child| child.files).sum(),
+			children: (depth > 0).then_some(children)
+		}
+	}
+
+	// Getters
+
+	pub fn get_name(&self) -> &str {
+		&self.name
+	}
+
+	pub fn get_is_dir(&self) -> bool {
+		self.is_dir
+	}
+
+	pub fn get_digest(&self) -> &str {
+		&self.digest
+	}
+
+	pub fn get_size(&self) -> u64 {
+		self.size
+	}
+
+	pub fn get_files(&self) -> u64 {
+		self.files
+	}
+
+	pub fn get_children(&self) -> Option<&[TreeInfo]> {
+		self.children.as_deref()
+	}
+
+	// Get something in the directory by name
+	pub fn get_child(&self, name: &str) -> Option<&TreeInfo> {
+		let children = self.children.as_ref()?;
+		children.binary_search_by(|child| child.name.as_str().cmp(name)).ok().map(|index| &children[index])
+	}
+
+	// Get the path relative to the directory and the digest of every file in it, as far down as the tree goes
+	pub fn file_paths(&self) -> Vec<(PathBuf, &str)> {
+		let mut file_paths = Vec::new();
+		for child in self.children.iter().flatten() {
+			if child.is_dir {
+				file_paths.extend(child.file_paths().into_iter().map(|(path, digest)| (Path::new(&child.name).join(path), digest)));
+			} else {
+				file_paths.push((PathBuf::from(&child.name), child.digest.as_str()));
+			}
+		}
+		file_paths
+	}
+}